@@ -0,0 +1,235 @@
+//! Algorithm-based fault tolerance for `mat_mul`.
+//!
+//! Each operand register gets checksum lanes appended after its data lanes, so after
+//! accumulation the block has column checksum rows and row checksum columns, computed
+//! by `OPAC` itself. A single faulty cell shows up as one mismatching row and one
+//! mismatching column with the same difference, and is corrected. A fault in a checksum
+//! cell mismatches only its row or column, and leaves data intact.
+//!
+//! Sum of operand lanes doesn't fit into `ChipT`, so it's split into `CHECKSUM_LANES`
+//! signed base-256 digits, and checksum cells of a row or column are weighted back on host.
+//! Checks are exact, because the accumulator is integer.
+
+use std::iter::zip;
+use ndarray::{s, Array2, ArrayView2, ArrayViewMut2};
+use crate::intrinsics::config::DIMENSION;
+use crate::intrinsics::counters::{count_vector, VectorKind};
+use crate::intrinsics::fault;
+use crate::intrinsics::intrinsics::{opac, Array1D, ChipT, Matrix};
+use crate::intrinsics::reductions::v_sum;
+use crate::intrinsics::trace::{record_vector, TraceOp};
+use crate::intrinsics::wrappers::tiled;
+
+/// Digits of a checksum, one lane each, enough for sums of `DIMENSION` lanes
+const CHECKSUM_LANES: usize = 3;
+
+/// Result of checksum verification of a block
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AbftOutcome {
+    /// Single cell was off by `delta` and has been fixed
+    Corrected { row: usize, col: usize, delta: i64 },
+    /// Only a checksum mismatches, data is intact
+    ChecksumFault,
+    /// Several rows or columns mismatch, block stays corrupted
+    Uncorrectable { rows: usize, cols: usize },
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AbftEvent {
+    /// First row and column of the block in the result
+    pub block: (usize, usize),
+    pub outcome: AbftOutcome,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AbftReport {
+    pub blocks: u64,
+    pub events: Vec<AbftEvent>,
+}
+
+impl AbftReport {
+    pub fn detected(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn corrected(&self) -> usize {
+        self.events.iter().filter(|e| matches!(e.outcome, AbftOutcome::Corrected { .. })).count()
+    }
+
+    pub fn uncorrectable(&self) -> usize {
+        self.events.iter().filter(|e| matches!(e.outcome, AbftOutcome::Uncorrectable { .. })).count()
+    }
+}
+
+/// `x` followed by digits of the sum of its lanes, lowest first.
+/// Digits are written by a select into the lanes past `x`, so the result takes faults of vector lanes
+fn with_checksum(x: &Array1D) -> Array1D {
+    let mut sum = v_sum(x) as i64;
+    count_vector(VectorKind::Select);
+    let mut res = Array1D::zeros(x.len() + CHECKSUM_LANES);
+    for (i, v) in x.as_slice().iter().enumerate() {
+        res[i] = *v;
+    }
+    for lane in x.len()..res.len() {
+        res[lane] = sum as ChipT;
+        sum = (sum - res[lane] as i64) >> 8;
+    }
+    debug_assert_eq!(sum, 0, "Checksum doesn't fit into its lanes");
+    record_vector(TraceOp::Vector, &[x], &mut res);
+    fault::vector_result(&mut res);
+    res
+}
+
+/// Compares data cells of the first `rows` by `cols` of `res` with its checksum cells
+fn verify(res: &mut Matrix, rows: usize, cols: usize) -> Option<AbftOutcome> {
+    let cell = |res: &Matrix, i, j| res[(i, j)] as i64;
+    let checksum = |cells: &mut dyn Iterator<Item = i64>| cells.fold((0, 1), |(s, w), x| (s + w * x, w << 8)).0;
+    let bad_rows: Vec<(usize, i64)> = (0..rows)
+        .map(|i| {
+            let sum: i64 = (0..cols).map(|j| cell(res, i, j)).sum();
+            (i, sum - checksum(&mut (cols..cols + CHECKSUM_LANES).map(|j| cell(res, i, j))))
+        })
+        .filter(|(_, d)| *d != 0)
+        .collect();
+    let bad_cols: Vec<(usize, i64)> = (0..cols)
+        .map(|j| {
+            let sum: i64 = (0..rows).map(|i| cell(res, i, j)).sum();
+            (j, sum - checksum(&mut (rows..rows + CHECKSUM_LANES).map(|i| cell(res, i, j))))
+        })
+        .filter(|(_, d)| *d != 0)
+        .collect();
+    match (bad_rows.as_slice(), bad_cols.as_slice()) {
+        ([], []) => None,
+        ([(row, delta)], [(col, delta_col)]) if delta == delta_col => {
+            res[(*row, *col)] = (cell(res, *row, *col) - delta) as _;
+            Some(AbftOutcome::Corrected { row: *row, col: *col, delta: *delta })
+        }
+        ([], _) | (_, []) if bad_rows.len() + bad_cols.len() == 1 => Some(AbftOutcome::ChecksumFault),
+        _ => Some(AbftOutcome::Uncorrectable { rows: bad_rows.len(), cols: bad_cols.len() }),
+    }
+}
+
+/// Same as `block_mul`, with checksums verified after accumulation
+fn block_mul_abft(mut res: ArrayViewMut2<f32>, a: ArrayView2<f32>, b: ArrayView2<f32>, offset: (usize, usize), report: &mut AbftReport) {
+    let (rows, cols) = (a.nrows(), b.nrows());
+    let mut res_mat = Matrix::zeros(rows + CHECKSUM_LANES, cols + CHECKSUM_LANES);
+    for (r1, r2) in zip(a.columns(), b.columns()) {
+        let (x, y): (Array1D, Array1D) = (r1.try_into().unwrap(), r2.try_into().unwrap());
+        opac(&mut res_mat, with_checksum(&x), with_checksum(&y));
+    }
+    report.blocks += 1;
+    if let Some(outcome) = verify(&mut res_mat, rows, cols) {
+        report.events.push(AbftEvent { block: offset, outcome });
+    }
+    // Checksum cells are read back along with data, for verification
+    let mut full = Array2::zeros((rows + CHECKSUM_LANES, cols + CHECKSUM_LANES));
+    res_mat.add_to(&mut full.view_mut());
+    res += &full.slice(s![..rows, ..cols]);
+}
+
+/// `mat_mul` with ABFT checksums. Blocks are `CHECKSUM_LANES` rows smaller than `DIMENSION`,
+/// so that checksum lanes fit into the hardware tile
+pub fn mat_mul_abft<'a>(a: ArrayView2<'a, f32>, b: ArrayView2<'a, f32>) -> (Array2<f32>, AbftReport) {
+    let mut report = AbftReport::default();
+    let rows = DIMENSION - CHECKSUM_LANES;
+    let res = tiled(a, b, rows, |res_block, block_a, block_b, offset| {
+        block_mul_abft(res_block, block_a, block_b, offset, &mut report)
+    });
+    (res, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sample;
+    use crate::intrinsics::counters::counted;
+    use crate::intrinsics::fault::{with_faults, BitFlip, Fault, FaultPlan, Location, Site, Trigger};
+    use crate::intrinsics::wrappers::mat_mul;
+
+    fn flip(call: u64, location: Location, mask: u32) -> Fault {
+        Fault { site: Site::Accumulator, trigger: Trigger::AtCall(call), location, flip: BitFlip::Mask(mask) }
+    }
+
+    #[test]
+    fn clean_run() {
+        let (a, b) = (sample(5, 9, 1, 1. / 16.), sample(6, 9, 2, 1. / 16.));
+        let (res, report) = mat_mul_abft(a.view(), b.view());
+        assert_eq!(res, mat_mul(a.view(), b.view()));
+        assert_eq!(report, AbftReport { blocks: 1, events: vec![] });
+    }
+
+    #[test]
+    fn corrects_single_cell() {
+        let (a, b) = (sample(5, 9, 1, 1. / 16.), sample(6, 9, 2, 1. / 16.));
+        let plan = FaultPlan { faults: vec![flip(3, Location::Cell(2, 4), 1 << 25)], seed: 0 };
+        let ((res, report), events) = with_faults(&plan, || mat_mul_abft(a.view(), b.view()));
+        assert_eq!(events.len(), 1);
+        assert_eq!(res, mat_mul(a.view(), b.view()));
+        assert_eq!(report.corrected(), 1);
+        assert!(matches!(report.events[0].outcome, AbftOutcome::Corrected { row: 2, col: 4, .. }));
+    }
+
+    #[test]
+    fn detects_multiple_faults() {
+        let (a, b) = (sample(5, 9, 1, 1. / 16.), sample(6, 9, 2, 1. / 16.));
+        let faults = vec![flip(2, Location::Cell(0, 1), 1 << 12), flip(5, Location::Cell(3, 2), 1 << 9)];
+        let ((_, report), _) = with_faults(&FaultPlan { faults, seed: 0 }, || mat_mul_abft(a.view(), b.view()));
+        assert_eq!(report.detected(), 1);
+        assert_eq!(report.uncorrectable(), 1);
+
+        // Operand fault spoils the whole row of the block
+        let operand = Fault { location: Location::Lane(1), ..Fault::single(Site::OperandA, 0, 3) };
+        let ((_, report), _) = with_faults(&FaultPlan { faults: vec![operand], seed: 0 }, || mat_mul_abft(a.view(), b.view()));
+        assert_eq!(report.uncorrectable(), 1);
+    }
+
+    #[test]
+    fn blocks_leave_room_for_checksums() {
+        let (a, b) = (sample(DIMENSION, 3, 1, 1. / 16.), sample(2, 3, 2, 1. / 16.));
+        let plan = FaultPlan { faults: vec![flip(4, Location::Cell(0, 1), 1 << 16)], seed: 0 };
+        let ((res, report), _) = with_faults(&plan, || mat_mul_abft(a.view(), b.view()));
+        assert_eq!(report.blocks, 2);
+        assert_eq!(report.detected(), 1);
+        // Flip clears the bit of a negative cell
+        assert_eq!(report.events[0], AbftEvent {
+            block: (DIMENSION - CHECKSUM_LANES, 0),
+            outcome: AbftOutcome::Corrected { row: 0, col: 1, delta: -(1 << 16) },
+        });
+        assert_eq!(res, mat_mul(a.view(), b.view()));
+    }
+
+    #[test]
+    fn checksum_fault() {
+        let (a, b) = (sample(5, 9, 1, 1. / 16.), sample(6, 9, 2, 1. / 16.));
+        let clean = mat_mul(a.view(), b.view());
+        // Cells past the data are checksums: second digit of a column, first digit of a row
+        for cell in [Location::Cell(6, 3), Location::Cell(1, 6)] {
+            let plan = FaultPlan { faults: vec![flip(4, cell, 1 << 20)], seed: 0 };
+            let ((res, report), _) = with_faults(&plan, || mat_mul_abft(a.view(), b.view()));
+            assert_eq!(report.events, vec![AbftEvent { block: (0, 0), outcome: AbftOutcome::ChecksumFault }]);
+            assert_eq!(res, clean);
+        }
+    }
+
+    #[test]
+    fn checksum_lane_fault() {
+        let (a, b) = (sample(5, 9, 1, 1. / 16.), sample(6, 9, 2, 1. / 16.));
+        // Second vector call is the select of the first operand, lane 5 is its first digit
+        let fault = Fault { location: Location::Lane(5), ..Fault::single(Site::VectorLane, 1, 0) };
+        let ((res, report), events) = with_faults(&FaultPlan { faults: vec![fault], seed: 0 }, || mat_mul_abft(a.view(), b.view()));
+        assert_eq!(events.len(), 1);
+        assert_eq!(report.detected(), 1);
+        assert_eq!(res, mat_mul(a.view(), b.view()));
+    }
+
+    #[test]
+    fn checksum_digits() {
+        let x = Array1D::from_slice(&[ChipT::MAX; DIMENSION - CHECKSUM_LANES]);
+        let (lanes, counters) = counted(|| with_checksum(&x));
+        assert_eq!(counters.vectors.reduction, 1);
+        assert_eq!(counters.vectors.select, 1);
+        let digits = &lanes.as_slice()[x.len()..];
+        let sum = digits.iter().rev().fold(0i64, |acc, d| acc * 256 + *d as i64);
+        assert_eq!(sum, ChipT::MAX as i64 * x.len() as i64);
+    }
+}
@@ -0,0 +1,156 @@
+//! Static worst-case error bound of `mat_mul`.
+//!
+//! Bound is derived from operand magnitudes only, so it holds for every input within them:
+//! - operands are rounded to the quantizer grid, or saturated at the ends of element range
+//! - products are accumulated exactly in the accumulator, unless it can overflow
+//! - each `DIMENSION` tile of the common dimension is read back to `f32` and added on host,
+//!   both steps round with relative error `f32::EPSILON / 2`
+//!
+//! Possible accumulator overflow makes any bound meaningless, so it is reported as infinite.
+
+use ndarray::ArrayView2;
+use super::config::DIMENSION;
+use super::intrinsics::AccT;
+use super::quant::{Element, Quantizer};
+
+/// Accumulator LSB is `2^-ACC_FRACTION_BITS`
+const ACC_FRACTION_BITS: i32 = 14;
+
+/// Arithmetic of the product, which is bounded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundConfig {
+    /// Quantizer of both operands
+    pub quantizer: Quantizer,
+    /// Width of accumulator cells, including sign
+    pub acc_bits: u32,
+    /// Tile of the common dimension, which is accumulated on chip
+    pub tile: usize,
+}
+
+impl Default for BoundConfig {
+    /// `mat_mul` with int8 operands
+    fn default() -> Self {
+        Self::for_element(Element::Int8)
+    }
+}
+
+impl BoundConfig {
+    /// `mat_mul_with` of given element
+    pub fn for_element(element: Element) -> Self {
+        BoundConfig { quantizer: element.quantizer(), acc_bits: AccT::BITS, tile: DIMENSION }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ErrorBound {
+    /// Bound of `|mat_mul(a, b) - a * b^T|` for every element
+    pub max_error: f64,
+    /// Part caused by rounding and saturation of operands
+    pub quantization: f64,
+    /// Part caused by `f32` readout and summation of tiles
+    pub rounding: f64,
+    /// Some operand value can be clamped to element range
+    pub saturation: bool,
+    /// Largest accumulator magnitude, in LSB
+    pub max_accumulator: f64,
+    pub overflow: bool,
+}
+
+/// Bound of `mat_mul` error for operands with `|a| <= max_a`, `|b| <= max_b`
+/// and common dimension `k`
+pub fn error_bound(max_a: f32, max_b: f32, k: usize, config: &BoundConfig) -> ErrorBound {
+    assert!(max_a >= 0. && max_b >= 0., "Magnitude bounds must be non-negative");
+    assert!(config.tile > 0, "Tile must be non-empty");
+    let q = config.quantizer;
+    let scale = q.scale() as f64;
+    let (q_min, q_max) = (q.element().min() as f64, q.element().max() as f64);
+    let (max_a, max_b, k) = (max_a as f64, max_b as f64, k as f64);
+
+    // Largest magnitude of quantized value, and error of a single operand
+    let levels = |m: f64| (m / scale).round().min(-q_min);
+    let error = |m: f64| (scale / 2.).max(m - scale * q_max);
+    let (levels_a, levels_b) = (levels(max_a), levels(max_b));
+    let (quantized_a, quantized_b) = (scale * levels_a, scale * levels_b);
+    let saturation = max_a.max(max_b) > scale * (q_max + 0.5);
+
+    // `|qa * qb - a * b| <= |qa| |eb| + |b| |ea|`
+    let quantization = k * (quantized_a * error(max_b) + max_b * error(max_a));
+
+    let lsb_per_product = scale * scale * 2f64.powi(ACC_FRACTION_BITS);
+    let max_accumulator = k.min(config.tile as f64) * levels_a * levels_b * lsb_per_product;
+    let overflow = max_accumulator > 2f64.powi(config.acc_bits as i32 - 1) - 1.;
+
+    // Every tile rounds once at readout, and once when added to a sum which is
+    // at most `k * |qa| * |qb|` grown by previous roundings
+    let unit = f32::EPSILON as f64 / 2.;
+    let tiles = (k / config.tile as f64).ceil();
+    let sum = k * quantized_a * quantized_b;
+    let rounding = tiles * unit * sum * (1. + (1. + unit).powf(tiles));
+
+    let max_error = if overflow { f64::INFINITY } else { quantization + rounding };
+    ErrorBound { max_error, quantization, rounding, saturation, max_accumulator, overflow }
+}
+
+/// Same as `error_bound`, with magnitudes taken from operands in `mat_mul` layout
+pub fn error_bound_for(a: ArrayView2<f32>, b: ArrayView2<f32>, config: &BoundConfig) -> ErrorBound {
+    assert_eq!(a.ncols(), b.ncols());
+    let max_abs = |x: ArrayView2<f32>| x.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+    error_bound(max_abs(a), max_abs(b), a.ncols(), config)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+    use super::*;
+    use crate::intrinsics::wrappers::{mat_mul, mat_mul_with};
+
+    fn max_error(res: &Array2<f32>, a: &Array2<f32>, b: &Array2<f32>) -> f64 {
+        let expected = a.mapv(f64::from).dot(&b.mapv(f64::from).t());
+        res.iter().zip(&expected).map(|(x, y)| (*x as f64 - y).abs()).fold(0., f64::max)
+    }
+
+    #[test]
+    fn bound_holds() {
+        let (m, n, k) = (4, 5, 2 * DIMENSION + 17);
+        let a = Array2::from_shape_fn((m, k), |(i, j)| ((i * 31 + j * 7) % 17) as f32 / 20. - 0.4);
+        let b = Array2::from_shape_fn((n, k), |(i, j)| ((i * 13 + j * 3) % 11) as f32 / 14. - 0.35);
+        for element in [Element::Int8, Element::Int4] {
+            let bound = error_bound_for(a.view(), b.view(), &BoundConfig::for_element(element));
+            assert!(!bound.overflow && !bound.saturation);
+            let error = max_error(&mat_mul_with(a.view(), b.view(), element), &a, &b);
+            assert!(error <= bound.max_error, "{:?} {} {:?}", element, error, bound);
+        }
+
+        // Worst case of rounding: every operand is half a step off the grid, with the same sign
+        let a = Array2::from_elem((2, k), 0.5 + 0.5 / 128.);
+        let bound = error_bound_for(a.view(), a.view(), &BoundConfig::default());
+        let error = max_error(&mat_mul(a.view(), a.view()), &a, &a);
+        assert!(error <= bound.max_error && error > 0.5 * bound.max_error, "{} {:?}", error, bound);
+    }
+
+    #[test]
+    fn saturation() {
+        let k = 10;
+        let a = Array2::from_elem((1, k), 1.5f32);
+        let b = Array2::from_elem((1, k), -0.75f32);
+        let bound = error_bound_for(a.view(), b.view(), &BoundConfig::default());
+        assert!(bound.saturation);
+        let error = max_error(&mat_mul(a.view(), b.view()), &a, &b);
+        assert!(error <= bound.max_error, "{} {:?}", error, bound);
+    }
+
+    #[test]
+    fn overflow() {
+        let config = BoundConfig::default();
+        let bound = error_bound(1., 1., DIMENSION, &config);
+        assert!(!bound.overflow);
+        assert_eq!(bound.max_accumulator, (DIMENSION * 128 * 128) as f64);
+        // Tiles of the common dimension are read back, so `k` doesn't matter
+        assert_eq!(error_bound(1., 1., 100 * DIMENSION, &config).max_accumulator, bound.max_accumulator);
+
+        let narrow = BoundConfig { acc_bits: 20, ..config };
+        assert!(error_bound(1., 1., DIMENSION, &narrow).overflow);
+        assert_eq!(error_bound(1., 1., DIMENSION, &narrow).max_error, f64::INFINITY);
+        assert!(!error_bound(1. / 16., 1. / 16., DIMENSION, &narrow).overflow);
+    }
+}
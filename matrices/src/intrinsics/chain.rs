@@ -0,0 +1,270 @@
+//! Matrix chain product `M1 * M2 * ... * Mn` with the order chosen by dynamic programming.
+//!
+//! Products run through `mat_mul_operands`, and cost of each is modelled from its tiling:
+//! `OPAC` passes, operand and accumulator conversions, and tile count. Operands are rescaled by powers of two into
+//! operand range before each product, so chains of any magnitude don't saturate.
+
+use std::fmt;
+use ndarray::{Array2, ArrayView2};
+use crate::intrinsics::config::DIMENSION;
+use crate::intrinsics::counters::count_conversions;
+use crate::intrinsics::intrinsics::ChipT;
+use crate::intrinsics::quant::{max_abs, Quantizer, OPERAND_MAX};
+use crate::intrinsics::wrappers::{mat_mul_operands, Operand};
+
+/// Weights of cost components
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CostModel {
+    pub opac: f64,
+    /// One value converted between `f32` and chip formats
+    pub conversion: f64,
+    /// Setup of one accumulator tile
+    pub tile: f64,
+}
+
+impl Default for CostModel {
+    /// Conversion is one lane of a vector instruction, so `DIMENSION` of them cost one `OPAC` pass
+    fn default() -> Self {
+        CostModel { opac: 1., conversion: 1. / DIMENSION as f64, tile: 1. }
+    }
+}
+
+/// How intermediate products are kept between `mat_mul` calls
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Intermediates {
+    F32,
+    /// Quantized once with per-matrix scale, so they are fed to `OPAC` without conversion
+    Quantized,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ChainCost {
+    pub opac: u64,
+    pub conversions: u64,
+    pub tiles: u64,
+}
+
+impl ChainCost {
+    pub fn weighted(&self, model: &CostModel) -> f64 {
+        self.opac as f64 * model.opac + self.conversions as f64 * model.conversion + self.tiles as f64 * model.tile
+    }
+}
+
+impl std::ops::Add for ChainCost {
+    type Output = ChainCost;
+
+    fn add(self, rhs: ChainCost) -> ChainCost {
+        ChainCost {
+            opac: self.opac + rhs.opac,
+            conversions: self.conversions + rhs.conversions,
+            tiles: self.tiles + rhs.tiles,
+        }
+    }
+}
+
+/// Parenthesization of the chain, leaves are indices of matrices
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChainOrder {
+    Leaf(usize),
+    Product(Box<ChainOrder>, Box<ChainOrder>),
+}
+
+impl fmt::Display for ChainOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainOrder::Leaf(i) => write!(f, "M{}", i),
+            ChainOrder::Product(l, r) => write!(f, "({} {})", l, r),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainPlan {
+    pub order: ChainOrder,
+    pub cost: ChainCost,
+}
+
+/// Cost of `[p, q] * [q, r]` product with `mat_mul`.
+/// Operands are converted once per tile of the other operand, unless they are kept quantized,
+/// but such intermediate result is quantized once more. So it saves when intermediate spans several tiles
+fn product_cost(
+    (p, q, r): (usize, usize, usize),
+    (left_intermediate, right_intermediate, result_intermediate): (bool, bool, bool),
+    intermediates: Intermediates,
+) -> ChainCost {
+    let tiles = |x: usize| x.div_ceil(DIMENSION) as u64;
+    let (p, q, r) = (p as u64, q as u64, r as u64);
+    let (ti, tj, tk) = (tiles(p as usize), tiles(r as usize), tiles(q as usize));
+    let quantized = intermediates == Intermediates::Quantized;
+    let mut conversions = p * r * tk;
+    if !(quantized && left_intermediate) {
+        conversions += p * q * tj;
+    }
+    if !(quantized && right_intermediate) {
+        conversions += r * q * ti;
+    }
+    if quantized && result_intermediate {
+        conversions += p * r;
+    }
+    ChainCost { opac: ti * tj * q, conversions, tiles: ti * tj * tk }
+}
+
+/// Optimal order of the chain with given `[rows, cols]` shapes
+pub fn plan_chain(shapes: &[(usize, usize)], model: &CostModel, intermediates: Intermediates) -> ChainPlan {
+    assert!(!shapes.is_empty());
+    for pair in shapes.windows(2) {
+        assert_eq!(pair[0].1, pair[1].0, "Chain shapes don't match");
+    }
+    let n = shapes.len();
+    // best[i][j]: cost and split of the sub-chain i..=j
+    let mut best = vec![vec![(ChainCost::default(), 0); n]; n];
+    for len in 2..=n {
+        for i in 0..=n - len {
+            let j = i + len - 1;
+            let mut candidates = (i..j).map(|k| {
+                let flags = (k > i, k + 1 < j, len < n);
+                let step = product_cost((shapes[i].0, shapes[k].1, shapes[j].1), flags, intermediates);
+                (best[i][k].0 + best[k + 1][j].0 + step, k)
+            });
+            let first = candidates.next().unwrap();
+            best[i][j] = candidates.fold(first, |acc, c| {
+                if c.0.weighted(model) < acc.0.weighted(model) { c } else { acc }
+            });
+        }
+    }
+
+    fn order(best: &[Vec<(ChainCost, usize)>], i: usize, j: usize) -> ChainOrder {
+        if i == j {
+            ChainOrder::Leaf(i)
+        } else {
+            let k = best[i][j].1;
+            ChainOrder::Product(Box::new(order(best, i, k)), Box::new(order(best, k + 1, j)))
+        }
+    }
+    ChainPlan { order: order(&best, 0, n - 1), cost: best[0][n - 1].0 }
+}
+
+/// Chain operand on host, as it is loaded to vector registers
+enum Values {
+    /// Converted on every load
+    F32(Array2<f32>),
+    /// Loaded as is
+    Quantized(Array2<ChipT>),
+}
+
+/// Operand of a product: `values / 128 * scale`, with values in operand range
+struct Scaled {
+    values: Values,
+    scale: f32,
+}
+
+impl Scaled {
+    /// Power of two scale keeps values exact
+    fn normalize(x: Array2<f32>) -> Self {
+        let max_abs = max_abs(&x);
+        let scale = if max_abs > 0. { 2f32.powi((max_abs / OPERAND_MAX).log2().ceil() as i32) } else { 1. };
+        Scaled { values: Values::F32(x / scale), scale }
+    }
+
+    /// Quantizes intermediate result once, so its loads don't convert it again
+    fn quantize(x: Array2<f32>) -> Self {
+        count_conversions(x.len() as u64);
+        let q = Quantizer::for_values(&x);
+        Scaled { values: Values::Quantized(x.mapv(|v| q.quantize(v))), scale: q.scale() * 128. }
+    }
+
+    /// Values as `mat_mul` operand, with common dimension along columns
+    fn operand(&self, transposed: bool) -> Operand<'_> {
+        match &self.values {
+            Values::F32(v) if transposed => Operand::F32(v.t()),
+            Values::F32(v) => Operand::F32(v.view()),
+            Values::Quantized(v) if transposed => Operand::Chip(v.t()),
+            Values::Quantized(v) => Operand::Chip(v.view()),
+        }
+    }
+}
+
+fn product(l: &Scaled, r: &Scaled) -> Array2<f32> {
+    mat_mul_operands(l.operand(false), r.operand(true)) * (l.scale * r.scale)
+}
+
+fn evaluate(order: &ChainOrder, mats: &[ArrayView2<f32>], intermediates: Intermediates) -> Array2<f32> {
+    match order {
+        ChainOrder::Leaf(i) => mats[*i].to_owned(),
+        ChainOrder::Product(l, r) => {
+            let operand = |o: &ChainOrder| {
+                let x = evaluate(o, mats, intermediates);
+                match (o, intermediates) {
+                    (ChainOrder::Product(..), Intermediates::Quantized) => Scaled::quantize(x),
+                    _ => Scaled::normalize(x),
+                }
+            };
+            product(&operand(l), &operand(r))
+        }
+    }
+}
+
+/// Chain product with default cost model and `f32` intermediates
+pub fn mat_mul_chain(mats: &[ArrayView2<f32>]) -> Array2<f32> {
+    mat_mul_chain_with(mats, &CostModel::default(), Intermediates::F32).0
+}
+
+/// Chain product in the order chosen by `plan_chain`
+pub fn mat_mul_chain_with(
+    mats: &[ArrayView2<f32>],
+    model: &CostModel,
+    intermediates: Intermediates,
+) -> (Array2<f32>, ChainPlan) {
+    let shapes: Vec<(usize, usize)> = mats.iter().map(|m| m.dim()).collect();
+    let plan = plan_chain(&shapes, model, intermediates);
+    (evaluate(&plan.order, mats, intermediates), plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sample;
+    use crate::intrinsics::counters::counted;
+
+    #[test]
+    fn classic_order() {
+        let shapes = [(10, 30), (30, 5), (5, 60)];
+        let plan = plan_chain(&shapes, &CostModel::default(), Intermediates::F32);
+        assert_eq!(plan.order.to_string(), "((M0 M1) M2)");
+        assert_eq!(plan.cost.opac, 35);
+        assert_eq!(plan.cost.conversions, 1450);
+
+        // Tall inner dimension makes right to left order cheaper
+        let shapes = [(2000, 10), (10, 2000), (2000, 3)];
+        let plan = plan_chain(&shapes, &CostModel::default(), Intermediates::F32);
+        assert_eq!(plan.order.to_string(), "(M0 (M1 M2))");
+    }
+
+    #[test]
+    fn chain_matches_reference() {
+        let mats = [sample(6, 40, 1, 2.75), sample(40, 9, 2, 2.75), sample(9, 50, 3, 2.75), sample(50, 4, 4, 2.75)];
+        let views: Vec<_> = mats.iter().map(|m| m.view()).collect();
+        let expected = mats[0].dot(&mats[1]).dot(&mats[2]).dot(&mats[3]);
+        let max_abs = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+        for intermediates in [Intermediates::F32, Intermediates::Quantized] {
+            let ((res, plan), counters) = counted(|| mat_mul_chain_with(&views, &CostModel::default(), intermediates));
+            assert_eq!(counters.opac, plan.cost.opac);
+            assert_eq!(counters.conversions, plan.cost.conversions);
+            for (x, y) in res.iter().zip(&expected) {
+                assert!((x - y).abs() < max_abs * 0.05, "{:?} {} {}", intermediates, x, y);
+            }
+        }
+        assert_eq!(mat_mul_chain(&views[..1]), mats[0]);
+    }
+
+    #[test]
+    fn quantized_intermediates_save_conversions() {
+        // Intermediate is loaded once per tile of the other operand, so it saves on reuse
+        let shapes = [(300, 200), (200, 1500), (1500, 2500)];
+        let model = CostModel::default();
+        let f32_plan = plan_chain(&shapes, &model, Intermediates::F32);
+        let quantized_plan = plan_chain(&shapes, &model, Intermediates::Quantized);
+        assert!(quantized_plan.cost.conversions < f32_plan.cost.conversions);
+        assert!(quantized_plan.cost.weighted(&model) < f32_plan.cost.weighted(&model));
+    }
+}
@@ -0,0 +1,163 @@
+//! Complex matrix multiplication built from real `OPAC` block products.
+//!
+//! Standard mode takes four real products. Gauss mode takes three, at the price of
+//! operand sums: they are halved before quantization to stay in operand range,
+//! so these products lose one bit of precision.
+
+use std::ops::{Add, Mul, Neg, Sub};
+use ndarray::{Array2, ArrayView2, Zip};
+use crate::intrinsics::wrappers::mat_mul;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub fn new(re: f32, im: f32) -> Self {
+        Complex { re, im }
+    }
+
+    pub fn abs(&self) -> f32 {
+        self.re.hypot(self.im)
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+
+    fn add(self, rhs: Complex) -> Complex {
+        Complex::new(self.re + rhs.re, self.im + rhs.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+
+    fn sub(self, rhs: Complex) -> Complex {
+        Complex::new(self.re - rhs.re, self.im - rhs.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, rhs: Complex) -> Complex {
+        Complex::new(self.re * rhs.re - self.im * rhs.im, self.re * rhs.im + self.im * rhs.re)
+    }
+}
+
+impl Neg for Complex {
+    type Output = Complex;
+
+    fn neg(self) -> Complex {
+        Complex::new(-self.re, -self.im)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ComplexMode {
+    /// `re = ar*br - ai*bi`, `im = ar*bi + ai*br`
+    Standard,
+    /// `k1 = (ar + ai)*br`, `k2 = ar*(bi - br)`, `k3 = ai*(br + bi)`,
+    /// `re = k1 - k3`, `im = k1 + k2`
+    Gauss,
+}
+
+impl ComplexMode {
+    pub fn real_products(&self) -> usize {
+        match self {
+            ComplexMode::Standard => 4,
+            ComplexMode::Gauss => 3,
+        }
+    }
+}
+
+/// `(x + y) / 2 * (z) * 2`: halved sum keeps operand in range
+fn mat_mul_halved<'a>(x: ArrayView2<'a, f32>, y: ArrayView2<'a, f32>, sign: f32, z: ArrayView2<'a, f32>) -> Array2<f32> {
+    let sum = (&x + &(&y * sign)) * 0.5;
+    mat_mul(sum.view(), z.view()) * 2.
+}
+
+/// Complex `a * b^T`, without conjugation, parts are given separately.
+/// Returns real and imaginary parts of the product
+pub fn mat_mul_complex<'a>(
+    a_re: ArrayView2<'a, f32>,
+    a_im: ArrayView2<'a, f32>,
+    b_re: ArrayView2<'a, f32>,
+    b_im: ArrayView2<'a, f32>,
+    mode: ComplexMode,
+) -> (Array2<f32>, Array2<f32>) {
+    assert_eq!(a_re.shape(), a_im.shape());
+    assert_eq!(b_re.shape(), b_im.shape());
+    match mode {
+        ComplexMode::Standard => {
+            let re = mat_mul(a_re, b_re) - mat_mul(a_im, b_im);
+            let im = mat_mul(a_re, b_im) + mat_mul(a_im, b_re);
+            (re, im)
+        }
+        ComplexMode::Gauss => {
+            let k1 = mat_mul_halved(a_re, a_im, 1., b_re);
+            let k2 = mat_mul_halved(b_im, b_re, -1., a_re).reversed_axes();
+            let k3 = mat_mul_halved(b_re, b_im, 1., a_im).reversed_axes();
+            (&k1 - &k3, &k1 + &k2)
+        }
+    }
+}
+
+/// Same as `mat_mul_complex`, for matrices of `Complex`
+pub fn mat_mul_complex_array<'a>(a: ArrayView2<'a, Complex>, b: ArrayView2<'a, Complex>, mode: ComplexMode) -> Array2<Complex> {
+    let (a_re, a_im) = (a.map(|x| x.re), a.map(|x| x.im));
+    let (b_re, b_im) = (b.map(|x| x.re), b.map(|x| x.im));
+    let (re, im) = mat_mul_complex(a_re.view(), a_im.view(), b_re.view(), b_im.view(), mode);
+    Zip::from(&re).and(&im).map_collect(|re, im| Complex::new(*re, *im))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intrinsics::counters::counted;
+    use crate::intrinsics::config::DIMENSION;
+
+    fn sample(rows: usize, cols: usize, seed: usize) -> Array2<Complex> {
+        Array2::from_shape_fn((rows, cols), |(i, j)| {
+            let h = i * 7919 + j * 104729 + seed * 31;
+            Complex::new((h % 41) as f32 / 50. - 0.4, (h % 37) as f32 / 45. - 0.4)
+        })
+    }
+
+    fn reference(a: &Array2<Complex>, b: &Array2<Complex>) -> Array2<Complex> {
+        Array2::from_shape_fn((a.nrows(), b.nrows()), |(i, j)| {
+            a.row(i).iter().zip(b.row(j)).fold(Complex::default(), |acc, (x, y)| acc + *x * *y)
+        })
+    }
+
+    #[test]
+    fn matches_reference() {
+        let (m, n, k) = (5, 7, 300);
+        let (a, b) = (sample(m, k, 1), sample(n, k, 2));
+        let expected = reference(&a, &b);
+        for (mode, tolerance) in [(ComplexMode::Standard, 0.1), (ComplexMode::Gauss, 0.15)] {
+            let (res, counters) = counted(|| mat_mul_complex_array(a.view(), b.view(), mode));
+            let error = res.iter().zip(&expected).map(|(x, y)| (*x - *y).abs()).fold(0., f32::max);
+            // Per-operand rounding error is 1/256, each of `k` terms sums 2 products
+            assert!(error < tolerance, "{:?} {}", mode, error);
+            assert_eq!(counters.opac, (mode.real_products() * k) as u64);
+        }
+    }
+
+    #[test]
+    fn exact_values() {
+        // Multiples of 1/64 stay exact after halving
+        let a = sample(3, DIMENSION + 2, 3).mapv(|x| Complex::new((x.re * 32.).round() / 64., (x.im * 32.).round() / 64.));
+        let b = sample(4, DIMENSION + 2, 4).mapv(|x| Complex::new((x.re * 32.).round() / 64., (x.im * 32.).round() / 64.));
+        let expected = reference(&a, &b);
+        for mode in [ComplexMode::Standard, ComplexMode::Gauss] {
+            let res = mat_mul_complex_array(a.view(), b.view(), mode);
+            for (x, y) in res.iter().zip(&expected) {
+                assert!((*x - *y).abs() < 1e-3, "{:?} {:?} {:?}", mode, x, y);
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-/// `OPAC` specific constants
+//! `OPAC` specific constants
 
 /// `OPAC` maximum matrix dimension
 /// Ex: limit on matrix dimension for multiplication of matrices on device
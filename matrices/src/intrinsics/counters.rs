@@ -0,0 +1,415 @@
+//! Counters of executed device instructions and host-device traffic.
+//!
+//! Instructions report themselves to thread local counters,
+//! so any kernel can be measured with `counted` without passing context around.
+//! `cost` turns counters into estimated cycles and energy.
+//!
+//! `Emulator` is the context of a measured run: it holds the cost table, which also
+//! annotates shuffles, and totals of all runs, so any kernel or wrapper can report
+//! its cost next to the result with `Emulator::run`.
+
+use std::cell::Cell;
+use super::shuffle::ShuffleCosts;
+
+/// Kinds of scalar core instructions
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VectorKind {
+    /// Add, sub, mul, min, max, abs, neg
+    Arithmetic,
+    /// Bitwise ops and shifts
+    Logic,
+    Compare,
+    Select,
+    Reduction,
+    Scan,
+    Lookup,
+}
+
+/// Scalar core instructions by `VectorKind`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct VectorCounts {
+    pub arithmetic: u64,
+    pub logic: u64,
+    pub compare: u64,
+    pub select: u64,
+    pub reduction: u64,
+    pub scan: u64,
+    pub lookup: u64,
+}
+
+impl VectorCounts {
+    pub fn get(&self, kind: VectorKind) -> u64 {
+        *self.field(kind)
+    }
+
+    fn field(&self, kind: VectorKind) -> &u64 {
+        match kind {
+            VectorKind::Arithmetic => &self.arithmetic,
+            VectorKind::Logic => &self.logic,
+            VectorKind::Compare => &self.compare,
+            VectorKind::Select => &self.select,
+            VectorKind::Reduction => &self.reduction,
+            VectorKind::Scan => &self.scan,
+            VectorKind::Lookup => &self.lookup,
+        }
+    }
+
+    fn field_mut(&mut self, kind: VectorKind) -> &mut u64 {
+        match kind {
+            VectorKind::Arithmetic => &mut self.arithmetic,
+            VectorKind::Logic => &mut self.logic,
+            VectorKind::Compare => &mut self.compare,
+            VectorKind::Select => &mut self.select,
+            VectorKind::Reduction => &mut self.reduction,
+            VectorKind::Scan => &mut self.scan,
+            VectorKind::Lookup => &mut self.lookup,
+        }
+    }
+}
+
+impl std::ops::Add for VectorCounts {
+    type Output = VectorCounts;
+
+    fn add(self, other: VectorCounts) -> VectorCounts {
+        VectorCounts {
+            arithmetic: self.arithmetic + other.arithmetic,
+            logic: self.logic + other.logic,
+            compare: self.compare + other.compare,
+            select: self.select + other.select,
+            reduction: self.reduction + other.reduction,
+            scan: self.scan + other.scan,
+            lookup: self.lookup + other.lookup,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Counters {
+    /// `OPAC` outer product passes
+    pub opac: u64,
+    /// Instructions of scalar core: pointwise, reductions, scans, lookups
+    pub vector: u64,
+    /// Same as `vector`, by kind
+    pub vectors: VectorCounts,
+    /// Lane permutations and shuffles
+    pub shuffle: u64,
+    /// Cost of shuffles, as annotated in `shuffle::ShuffleCosts`
+    pub shuffle_cost: u64,
+    /// Values converted between `f32` and chip formats
+    pub conversions: u64,
+    pub bytes_to_device: u64,
+    pub bytes_to_host: u64,
+}
+
+impl std::ops::Add for Counters {
+    type Output = Counters;
+
+    fn add(self, other: Counters) -> Counters {
+        Counters {
+            opac: self.opac + other.opac,
+            vector: self.vector + other.vector,
+            vectors: self.vectors + other.vectors,
+            shuffle: self.shuffle + other.shuffle,
+            shuffle_cost: self.shuffle_cost + other.shuffle_cost,
+            conversions: self.conversions + other.conversions,
+            bytes_to_device: self.bytes_to_device + other.bytes_to_device,
+            bytes_to_host: self.bytes_to_host + other.bytes_to_host,
+        }
+    }
+}
+
+thread_local! {
+    static COUNTERS: Cell<Counters> = Cell::new(Counters::default());
+    static COST_TABLE: Cell<CostTable> = Cell::new(CostTable::default());
+}
+
+fn update(f: impl FnOnce(&mut Counters)) {
+    COUNTERS.with(|c| {
+        let mut v = c.get();
+        f(&mut v);
+        c.set(v);
+    });
+}
+
+pub(crate) fn count_opac() {
+    update(|v| v.opac += 1);
+}
+
+pub(crate) fn count_vector(kind: VectorKind) {
+    update(|v| {
+        v.vector += 1;
+        *v.vectors.field_mut(kind) += 1;
+    });
+}
+
+pub(crate) fn count_shuffle(cost: u32) {
+    update(|v| {
+        v.shuffle += 1;
+        v.shuffle_cost += cost as u64;
+    });
+}
+
+pub(crate) fn count_conversions(values: u64) {
+    update(|v| v.conversions += values);
+}
+
+pub(crate) fn count_to_device(bytes: u64) {
+    update(|v| v.bytes_to_device += bytes);
+}
+
+pub(crate) fn count_to_host(bytes: u64) {
+    update(|v| v.bytes_to_host += bytes);
+}
+
+/// Runs `f` and returns instructions executed by it.
+/// Calls can be nested, outer call counts instructions of inner one too.
+pub fn counted<R>(f: impl FnOnce() -> R) -> (R, Counters) {
+    let outer = COUNTERS.with(|c| c.replace(Counters::default()));
+    let res = f();
+    let inner = COUNTERS.with(|c| c.replace(Counters::default()));
+    COUNTERS.with(|c| c.set(outer + inner));
+    (res, inner)
+}
+
+/// Estimated price of one event
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cost {
+    pub cycles: f64,
+    /// Picojoules
+    pub energy: f64,
+}
+
+impl Cost {
+    pub fn new(cycles: f64, energy: f64) -> Self {
+        Cost { cycles, energy }
+    }
+}
+
+impl std::ops::Add for Cost {
+    type Output = Cost;
+
+    fn add(self, other: Cost) -> Cost {
+        Cost::new(self.cycles + other.cycles, self.energy + other.energy)
+    }
+}
+
+impl std::ops::Mul<u64> for Cost {
+    type Output = Cost;
+
+    fn mul(self, times: u64) -> Cost {
+        Cost::new(self.cycles * times as f64, self.energy * times as f64)
+    }
+}
+
+/// Price of every counted event. Defaults are rough placeholders of the right order,
+/// they should be calibrated against the chip.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CostTable {
+    pub opac: Cost,
+    pub arithmetic: Cost,
+    pub logic: Cost,
+    pub compare: Cost,
+    pub select: Cost,
+    pub reduction: Cost,
+    pub scan: Cost,
+    pub lookup: Cost,
+    /// Per unit of `Counters::shuffle_cost`
+    pub shuffle: Cost,
+    /// Units of `shuffle` charged for each shuffle
+    pub shuffles: ShuffleCosts,
+    /// Per converted value
+    pub conversion: Cost,
+    /// Per byte moved between host and device
+    pub transfer: Cost,
+}
+
+impl CostTable {
+    pub fn vector(&self, kind: VectorKind) -> Cost {
+        match kind {
+            VectorKind::Arithmetic => self.arithmetic,
+            VectorKind::Logic => self.logic,
+            VectorKind::Compare => self.compare,
+            VectorKind::Select => self.select,
+            VectorKind::Reduction => self.reduction,
+            VectorKind::Scan => self.scan,
+            VectorKind::Lookup => self.lookup,
+        }
+    }
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        CostTable {
+            opac: Cost::new(4., 2000.),
+            arithmetic: Cost::new(1., 50.),
+            logic: Cost::new(1., 30.),
+            compare: Cost::new(1., 40.),
+            select: Cost::new(1., 40.),
+            // Tree of log2(DIMENSION) levels
+            reduction: Cost::new(10., 120.),
+            scan: Cost::new(10., 200.),
+            lookup: Cost::new(2., 150.),
+            shuffle: Cost::new(1., 60.),
+            shuffles: ShuffleCosts::default(),
+            conversion: Cost::new(0.01, 2.),
+            transfer: Cost::new(0.1, 20.),
+        }
+    }
+}
+
+const VECTOR_KINDS: [VectorKind; 7] = [
+    VectorKind::Arithmetic,
+    VectorKind::Logic,
+    VectorKind::Compare,
+    VectorKind::Select,
+    VectorKind::Reduction,
+    VectorKind::Scan,
+    VectorKind::Lookup,
+];
+
+impl Counters {
+    pub fn cost(&self, table: &CostTable) -> Cost {
+        let vector = VECTOR_KINDS
+            .iter()
+            .fold(Cost::default(), |acc, kind| acc + table.vector(*kind) * self.vectors.get(*kind));
+        table.opac * self.opac
+            + vector
+            + table.shuffle * self.shuffle_cost
+            + table.conversion * self.conversions
+            + table.transfer * (self.bytes_to_device + self.bytes_to_host)
+    }
+}
+
+/// Replaces cost table of this thread, returns previous one
+pub fn set_cost_table(table: CostTable) -> CostTable {
+    COST_TABLE.with(|c| c.replace(table))
+}
+
+pub fn cost_table() -> CostTable {
+    COST_TABLE.with(|c| c.get())
+}
+
+/// Counters of a run together with their cost by the current cost table
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CostSummary {
+    pub counters: Counters,
+    pub cost: Cost,
+}
+
+/// Same as `counted`, but also prices the counters.
+/// Ex: `costed(|| vec_add(a.view(), b.view()))`
+pub fn costed<R>(f: impl FnOnce() -> R) -> (R, CostSummary) {
+    let (res, counters) = counted(f);
+    (res, CostSummary { counters, cost: counters.cost(&cost_table()) })
+}
+
+/// Restores cost table of the thread, also on panic
+struct TableGuard(CostTable);
+
+impl Drop for TableGuard {
+    fn drop(&mut self) {
+        set_cost_table(self.0);
+    }
+}
+
+/// Context of emulated runs: cost table they are priced and annotated with,
+/// and counters of all runs so far
+#[derive(Clone, Debug, Default)]
+pub struct Emulator {
+    table: CostTable,
+    total: Counters,
+}
+
+impl Emulator {
+    pub fn new(table: CostTable) -> Self {
+        Emulator { table, total: Counters::default() }
+    }
+
+    /// Runs `f` under this context, returns its result with counters and cost of the run.
+    /// Ex: `emulator.run(|| mat_mul(a.view(), b.view()))`
+    pub fn run<R>(&mut self, f: impl FnOnce() -> R) -> (R, CostSummary) {
+        let _guard = TableGuard(set_cost_table(self.table));
+        let (res, summary) = costed(f);
+        self.total = self.total + summary.counters;
+        (res, summary)
+    }
+
+    pub fn table(&self) -> &CostTable {
+        &self.table
+    }
+
+    /// Counters of all runs
+    pub fn counters(&self) -> Counters {
+        self.total
+    }
+
+    /// Cost of all runs
+    pub fn summary(&self) -> CostSummary {
+        CostSummary { counters: self.total, cost: self.total.cost(&self.table) }
+    }
+
+    pub fn reset(&mut self) {
+        self.total = Counters::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested() {
+        let (_, outer) = counted(|| {
+            count_opac();
+            let (_, inner) = counted(|| {
+                count_vector(VectorKind::Arithmetic);
+                count_vector(VectorKind::Scan);
+            });
+            assert_eq!(inner.vector, 2);
+            assert_eq!(inner.vectors.get(VectorKind::Scan), 1);
+            count_vector(VectorKind::Arithmetic);
+            count_shuffle(5);
+        });
+        let vectors = VectorCounts { arithmetic: 2, scan: 1, ..Default::default() };
+        assert_eq!(outer, Counters { opac: 1, vector: 3, vectors, shuffle: 1, shuffle_cost: 5, ..Default::default() });
+    }
+
+    #[test]
+    fn cost_by_table() {
+        let table = CostTable {
+            opac: Cost::new(10., 100.),
+            lookup: Cost::new(3., 5.),
+            transfer: Cost::new(1., 1.),
+            ..CostTable::default()
+        };
+        let previous = set_cost_table(table);
+        let (_, summary) = costed(|| {
+            count_opac();
+            count_vector(VectorKind::Lookup);
+            count_to_device(7);
+        });
+        set_cost_table(previous);
+        assert_eq!(summary.cost, Cost::new(10. + 3. + 7., 100. + 5. + 7.));
+        assert_eq!(cost_table(), CostTable::default());
+    }
+
+    #[test]
+    fn emulator_context() {
+        let table = CostTable { opac: Cost::new(10., 100.), ..CostTable::default() };
+        let mut emulator = Emulator::new(table);
+        let (res, summary) = emulator.run(|| {
+            count_opac();
+            cost_table()
+        });
+        assert_eq!(res, table);
+        assert_eq!(summary.cost, Cost::new(10., 100.));
+        assert_eq!(cost_table(), CostTable::default());
+
+        emulator.run(|| count_vector(VectorKind::Logic));
+        let vectors = VectorCounts { logic: 1, ..Default::default() };
+        assert_eq!(emulator.counters(), Counters { opac: 1, vector: 1, vectors, ..Default::default() });
+        assert_eq!(emulator.summary().cost, Cost::new(10., 100.) + table.logic);
+        emulator.reset();
+        assert_eq!(emulator.counters(), Counters::default());
+    }
+}
@@ -0,0 +1,407 @@
+//! Fault injection into the emulator, for resilience studies.
+//!
+//! Faults flip bits in `OPAC` accumulator cells, in quantized `OPAC` operands,
+//! in result lanes of vector instructions (pointwise, scans, lookups and shuffles),
+//! or in scalar results of reductions. Each fault fires at a given call of its
+//! instruction, or at every call with some probability, drawn from a seeded PRNG,
+//! so runs are reproducible. `fault_report` compares faulty run with the clean one.
+
+use std::cell::RefCell;
+use ndarray::Array2;
+use super::intrinsics::{AccT, Array1D, ChipT, Matrix};
+
+/// Where the fault happens
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Site {
+    /// Cell of `OPAC` accumulator, after the pass
+    Accumulator,
+    /// Lane of the first `OPAC` operand, after quantization
+    OperandA,
+    /// Lane of the second `OPAC` operand, after quantization
+    OperandB,
+    /// Result lane of scalar core instruction.
+    /// Lanes of instructions with two result registers are numbered through both of them
+    VectorLane,
+    /// Scalar result of reduction, in `AccT` register
+    Reduction,
+}
+
+impl Site {
+    fn width(&self) -> u32 {
+        match self {
+            Site::Accumulator | Site::Reduction => AccT::BITS,
+            _ => ChipT::BITS,
+        }
+    }
+}
+
+/// When the fault fires
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    /// At call with this index, counted from 0: `opac` calls for accumulator and operands,
+    /// vector instructions, reductions included, for lanes and reductions
+    AtCall(u64),
+    /// At every call with this probability
+    Random(f64),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Location {
+    Random,
+    Cell(usize, usize),
+    Lane(usize),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BitFlip {
+    /// Flips bits of the mask, truncated to the value width
+    Mask(u32),
+    /// Flips this number of distinct random bits
+    Random(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fault {
+    pub site: Site,
+    pub trigger: Trigger,
+    pub location: Location,
+    pub flip: BitFlip,
+}
+
+impl Fault {
+    /// Single bit flip at given call and random location
+    pub fn single(site: Site, call: u64, bit: u32) -> Self {
+        Fault { site, trigger: Trigger::AtCall(call), location: Location::Random, flip: BitFlip::Mask(1 << bit) }
+    }
+}
+
+/// Faults of a run, and seed of their PRNG
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultPlan {
+    pub faults: Vec<Fault>,
+    pub seed: u64,
+}
+
+/// Injected fault
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FaultEvent {
+    pub site: Site,
+    pub call: u64,
+    /// `(row, col)` of accumulator, or `(lane, 0)`
+    pub location: (usize, usize),
+    pub mask: u32,
+}
+
+/// SplitMix64, good enough to pick fault locations and cheap to seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+struct Injector {
+    faults: Vec<Fault>,
+    rng: Rng,
+    opac_calls: u64,
+    vector_calls: u64,
+    events: Vec<FaultEvent>,
+}
+
+impl Injector {
+    /// Faults of `sites`, which fire at this call, with their locations and masks
+    fn fire(&mut self, sites: &[Site], call: u64, shape: (usize, usize)) -> Vec<FaultEvent> {
+        let mut res = Vec::new();
+        for fault in self.faults.clone() {
+            if !sites.contains(&fault.site) || shape.0 == 0 || shape.1 == 0 {
+                continue;
+            }
+            let fires = match fault.trigger {
+                Trigger::AtCall(n) => n == call,
+                Trigger::Random(p) => self.rng.chance(p),
+            };
+            if !fires {
+                continue;
+            }
+            let location = match fault.location {
+                Location::Random => (self.rng.below(shape.0), self.rng.below(shape.1)),
+                Location::Cell(r, c) => (r, c),
+                Location::Lane(i) => (i, 0),
+            };
+            if location.0 >= shape.0 || location.1 >= shape.1 {
+                continue;
+            }
+            let width = fault.site.width();
+            let mask = match fault.flip {
+                BitFlip::Mask(m) => m & (u32::MAX >> (32 - width)),
+                BitFlip::Random(n) => {
+                    assert!(n <= width, "Can't flip more bits than value has");
+                    let mut mask = 0u32;
+                    while mask.count_ones() < n {
+                        mask |= 1 << self.rng.below(width as usize);
+                    }
+                    mask
+                }
+            };
+            let event = FaultEvent { site: fault.site, call, location, mask };
+            self.events.push(event);
+            res.push(event);
+        }
+        res
+    }
+}
+
+thread_local! {
+    static INJECTOR: RefCell<Option<Injector>> = const { RefCell::new(None) };
+}
+
+fn flip_lane(x: &mut Array1D, lane: usize, mask: u32) {
+    x[lane] ^= mask as u8 as ChipT;
+}
+
+/// Hook of `opac`, before the pass: corrupts operands, returns index of the call
+pub(crate) fn opac_operands(a: &mut Array1D, b: &mut Array1D) -> Option<u64> {
+    INJECTOR.with(|i| {
+        let mut injector = i.borrow_mut();
+        let injector = injector.as_mut()?;
+        let call = injector.opac_calls;
+        injector.opac_calls += 1;
+        for event in injector.fire(&[Site::OperandA], call, (a.len(), 1)) {
+            flip_lane(a, event.location.0, event.mask);
+        }
+        for event in injector.fire(&[Site::OperandB], call, (b.len(), 1)) {
+            flip_lane(b, event.location.0, event.mask);
+        }
+        Some(call)
+    })
+}
+
+/// Hook of `opac`, after the pass
+pub(crate) fn opac_accumulator(call: Option<u64>, res: &mut Matrix) {
+    let Some(call) = call else {
+        return;
+    };
+    INJECTOR.with(|i| {
+        if let Some(injector) = i.borrow_mut().as_mut() {
+            for event in injector.fire(&[Site::Accumulator], call, (res.rows(), res.cols())) {
+                res[event.location] ^= event.mask as AccT;
+            }
+        }
+    });
+}
+
+/// Hook of scalar core instructions, corrupts result lanes
+pub(crate) fn vector_result(res: &mut Array1D) {
+    vector_results(&mut [res]);
+}
+
+/// Same as `vector_result`, for instructions with several result registers
+pub(crate) fn vector_results(res: &mut [&mut Array1D]) {
+    INJECTOR.with(|i| {
+        if let Some(injector) = i.borrow_mut().as_mut() {
+            let call = injector.vector_calls;
+            injector.vector_calls += 1;
+            let lanes = res.iter().map(|x| x.len()).sum();
+            for event in injector.fire(&[Site::VectorLane], call, (lanes, 1)) {
+                let mut lane = event.location.0;
+                for x in res.iter_mut() {
+                    if lane < x.len() {
+                        flip_lane(x, lane, event.mask);
+                        break;
+                    }
+                    lane -= x.len();
+                }
+            }
+        }
+    });
+}
+
+/// Hook of reductions, returns corrupted result
+pub(crate) fn reduction_result(res: AccT) -> AccT {
+    INJECTOR.with(|i| {
+        let mut injector = i.borrow_mut();
+        let Some(injector) = injector.as_mut() else {
+            return res;
+        };
+        let call = injector.vector_calls;
+        injector.vector_calls += 1;
+        injector
+            .fire(&[Site::Reduction], call, (1, 1))
+            .iter()
+            .fold(res, |acc, event| acc ^ event.mask as AccT)
+    })
+}
+
+/// Runs `f` with faults of `plan`, returns injected faults.
+/// Calls can't be nested.
+pub fn with_faults<R>(plan: &FaultPlan, f: impl FnOnce() -> R) -> (R, Vec<FaultEvent>) {
+    let injector = Injector {
+        faults: plan.faults.clone(),
+        rng: Rng(plan.seed),
+        opac_calls: 0,
+        vector_calls: 0,
+        events: Vec::new(),
+    };
+    assert!(INJECTOR.with(|i| i.borrow().is_none()), "Fault injection can't be nested");
+    INJECTOR.with(|i| i.replace(Some(injector)));
+    let _guard = InjectorGuard;
+    let res = f();
+    let injector = INJECTOR.with(|i| i.take()).unwrap();
+    (res, injector.events)
+}
+
+/// Removes injector of the thread, also on panic
+struct InjectorGuard;
+
+impl Drop for InjectorGuard {
+    fn drop(&mut self) {
+        INJECTOR.with(|i| i.take());
+    }
+}
+
+/// Output error caused by faults
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultReport {
+    pub events: Vec<FaultEvent>,
+    /// Outputs, which differ from the clean run
+    pub corrupted: usize,
+    pub max_error: f32,
+    pub mean_error: f32,
+}
+
+impl FaultReport {
+    /// Faults were injected, but output didn't change: they were masked
+    pub fn masked(&self) -> bool {
+        !self.events.is_empty() && self.corrupted == 0
+    }
+}
+
+/// Runs `f` clean and with faults of `plan`, and compares outputs
+pub fn fault_report(plan: &FaultPlan, f: impl Fn() -> Array2<f32>) -> FaultReport {
+    let clean = f();
+    let (faulty, events) = with_faults(plan, &f);
+    assert_eq!(clean.shape(), faulty.shape());
+    let errors: Vec<f32> = clean.iter().zip(&faulty).map(|(x, y)| (x - y).abs()).collect();
+    FaultReport {
+        events,
+        corrupted: errors.iter().filter(|e| **e != 0.).count(),
+        max_error: errors.iter().fold(0., |acc, e| acc.max(*e)),
+        mean_error: if errors.is_empty() { 0. } else { errors.iter().sum::<f32>() / errors.len() as f32 },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sample;
+    use crate::intrinsics::intrinsics::v_add;
+    use crate::intrinsics::reductions::{v_argmax, v_prefix_max, v_sum};
+    use crate::intrinsics::shuffle::{v_interleave, v_reverse};
+    use crate::intrinsics::wrappers::mat_mul;
+
+    #[test]
+    fn accumulator_bit_flip() {
+        let (a, b) = (sample(4, 6, 1, 1. / 16.), sample(5, 6, 2, 1. / 16.));
+        let fault = Fault {
+            site: Site::Accumulator,
+            trigger: Trigger::AtCall(5),
+            location: Location::Cell(1, 2),
+            flip: BitFlip::Mask(1 << 20),
+        };
+        let report = fault_report(&FaultPlan { faults: vec![fault], seed: 0 }, || mat_mul(a.view(), b.view()));
+        assert_eq!(report.events, vec![FaultEvent { site: Site::Accumulator, call: 5, location: (1, 2), mask: 1 << 20 }]);
+        assert_eq!(report.corrupted, 1);
+        // Bit 20 of accumulator with 14 fractional bits
+        assert_eq!(report.max_error, 64.);
+    }
+
+    #[test]
+    fn injector_removed_on_panic() {
+        let fault = Fault { location: Location::Lane(0), ..Fault::single(Site::VectorLane, 0, 0) };
+        let plan = FaultPlan { faults: vec![fault], seed: 0 };
+        let a = Array1D::from_slice(&[1, 2]);
+        let panicked = std::panic::catch_unwind(|| with_faults(&plan, || panic!("inside")));
+        assert!(panicked.is_err());
+        // Clean run after the panic, and injection still works
+        assert_eq!(v_add(&a, &a).as_slice(), &[2, 4]);
+        let (res, events) = with_faults(&plan, || v_add(&a, &a));
+        assert_eq!(res.as_slice(), &[3, 4]);
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn operand_flip_corrupts_row() {
+        let (a, b) = (sample(4, 6, 1, 1. / 16.), sample(5, 6, 2, 1. / 16.));
+        let fault = Fault { location: Location::Lane(3), ..Fault::single(Site::OperandA, 0, 6) };
+        let report = fault_report(&FaultPlan { faults: vec![fault], seed: 0 }, || mat_mul(a.view(), b.view()));
+        // Operand lane feeds a whole row of outer product, except where `b` is zero
+        let nonzero = b.column(0).iter().filter(|x| **x != 0.).count();
+        assert_eq!(report.corrupted, nonzero);
+    }
+
+    #[test]
+    fn random_faults_reproducible() {
+        let (a, b) = (sample(8, 40, 1, 1. / 16.), sample(8, 40, 2, 1. / 16.));
+        let fault = Fault {
+            site: Site::Accumulator,
+            trigger: Trigger::Random(0.2),
+            location: Location::Random,
+            flip: BitFlip::Random(2),
+        };
+        let plan = FaultPlan { faults: vec![fault], seed: 42 };
+        let first = fault_report(&plan, || mat_mul(a.view(), b.view()));
+        let second = fault_report(&plan, || mat_mul(a.view(), b.view()));
+        assert_eq!(first, second);
+        assert!(!first.events.is_empty() && first.events.len() < 40);
+        assert!(first.events.iter().all(|e| e.mask.count_ones() == 2));
+        let other = fault_report(&FaultPlan { seed: 7, ..plan }, || mat_mul(a.view(), b.view()));
+        assert_ne!(first.events, other.events);
+    }
+
+    #[test]
+    fn vector_lane() {
+        let x = Array1D::from_slice(&[1, 2, 3]);
+        let fault = Fault { location: Location::Lane(2), ..Fault::single(Site::VectorLane, 1, 7) };
+        let (res, events) = with_faults(&FaultPlan { faults: vec![fault], seed: 0 }, || {
+            let y = v_add(&x, &x);
+            v_add(&y, &x)
+        });
+        assert_eq!(res.as_slice(), &[3, 6, 9 ^ ChipT::MIN]);
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn reductions_scans_shuffles() {
+        let x = Array1D::from_slice(&[1, 2, 3]);
+        let plan = |site, call, location| FaultPlan {
+            faults: vec![Fault { location, ..Fault::single(site, call, 4) }],
+            seed: 0,
+        };
+        let (sum, events) = with_faults(&plan(Site::Reduction, 1, Location::Random), || {
+            v_reverse(&x);
+            v_sum(&x)
+        });
+        assert_eq!((sum, events.len()), (6 ^ 16, 1));
+        // Corrupted index stays in bounds
+        let (i, _) = with_faults(&plan(Site::Reduction, 0, Location::Random), || v_argmax(&x));
+        assert_eq!(i, (2 ^ 16) % 3);
+        let (scan, _) = with_faults(&plan(Site::VectorLane, 0, Location::Lane(0)), || v_prefix_max(&x));
+        assert_eq!(scan.as_slice(), &[1 ^ 16, 2, 3]);
+        // Lanes of both halves are numbered in sequence
+        let (halves, _) = with_faults(&plan(Site::VectorLane, 0, Location::Lane(4)), || v_interleave(&x, &x));
+        assert_eq!(halves.1.as_slice(), &[2, 3 ^ 16, 3]);
+    }
+}
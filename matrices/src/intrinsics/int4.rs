@@ -0,0 +1,206 @@
+//! Packed int4 operands of the 4-bit chip variant.
+//!
+//! Two values share a byte: even lane in the low nibble, odd lane in the high one.
+//! Operands are in fixed point with 3 fractional bits, and `OPAC` products are shifted
+//! into the same accumulator format as int8 ones, so `Matrix` is shared by both.
+
+use std::iter::zip;
+use ndarray::{ArrayView1, Ix1, ArrayView};
+use super::config::DIMENSION;
+use super::fault;
+use super::counters::{count_conversions, count_opac, count_to_device};
+use super::intrinsics::{AccT, Array1D, ChipT, Matrix};
+use super::quant::{Element, Quantizer};
+use super::trace::{record, record_opac, NodeId, TraceOp};
+
+/// Shift of int4 product to accumulator scale: `2 * (7 - 3)` fractional bits
+const PRODUCT_SHIFT: u32 = 2 * (Element::Int8.bits() - Element::Int4.bits());
+
+/// Sign extended nibble of lane `index` in its byte
+fn lane(byte: u8, index: usize) -> ChipT {
+    let byte = byte as ChipT;
+    if index.is_multiple_of(2) { (byte << 4) >> 4 } else { byte >> 4 }
+}
+
+/// Vector register of int4 lanes, two per byte
+#[derive(Clone, Debug)]
+pub struct PackedArray1D {
+    data: [u8; DIMENSION.div_ceil(2)],
+    sz: usize,
+    /// Trace node, which produced the value
+    node: Option<NodeId>,
+}
+
+impl PartialEq for PackedArray1D {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes() && self.sz == other.sz
+    }
+}
+
+impl PackedArray1D {
+    pub fn zeros(sz: usize) -> Self {
+        assert!(sz <= DIMENSION);
+        PackedArray1D {
+            data: [0; DIMENSION.div_ceil(2)],
+            sz,
+            node: None,
+        }
+    }
+
+    /// Values must be in int4 range
+    pub fn from_slice(values: &[ChipT]) -> Result<Self, &'static str> {
+        if values.len() > DIMENSION {
+            return Err("Tried to create OPAC array from higher dimension");
+        }
+        count_to_device(values.len().div_ceil(2) as u64);
+        let mut res = Self::zeros(values.len());
+        for (i, x) in values.iter().enumerate() {
+            res.set(i, *x)?;
+        }
+        res.node = record(TraceOp::Input, &[]);
+        Ok(res)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sz
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sz == 0
+    }
+
+    /// Sign extended lane value
+    pub fn get(&self, index: usize) -> ChipT {
+        assert!(index < self.sz);
+        lane(self.data[index / 2], index)
+    }
+
+    pub fn set(&mut self, index: usize, value: ChipT) -> Result<(), &'static str> {
+        assert!(index < self.sz);
+        if !(Element::Int4.min()..=Element::Int4.max()).contains(&value) {
+            return Err("Value doesn't fit into int4");
+        }
+        let nibble = value as u8 & 0xf;
+        let byte = &mut self.data[index / 2];
+        *byte = if index.is_multiple_of(2) { (*byte & 0xf0) | nibble } else { (*byte & 0x0f) | (nibble << 4) };
+        Ok(())
+    }
+
+    /// Raw packed bytes, odd length vector has zero high nibble in the last byte
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.sz.div_ceil(2)]
+    }
+
+    /// Quantizes values with `q`, which must be int4 quantizer
+    pub fn quantize(value: ArrayView1<f32>, q: Quantizer) -> Result<Self, &'static str> {
+        assert_eq!(q.element(), Element::Int4);
+        count_conversions(value.len() as u64);
+        let values: Vec<ChipT> = value.iter().map(|x| q.quantize(*x)).collect();
+        Self::from_slice(&values)
+    }
+}
+
+impl<'a> TryFrom<ArrayView<'a, f32, Ix1>> for PackedArray1D {
+    type Error = &'static str;
+
+    /// Quantizes to `OPAC` operand format, with 3 fractional bits
+    fn try_from(value: ArrayView<'a, f32, Ix1>) -> Result<Self, Self::Error> {
+        Self::quantize(value, Element::Int4.quantizer())
+    }
+}
+
+impl TryFrom<&Array1D> for PackedArray1D {
+    type Error = &'static str;
+
+    /// Packs int8 lanes, all of them must be in int4 range
+    fn try_from(value: &Array1D) -> Result<Self, Self::Error> {
+        Self::from_slice(value.as_slice())
+    }
+}
+
+impl From<&PackedArray1D> for Array1D {
+    /// Unpacks lanes on device, keeping raw int4 values
+    fn from(value: &PackedArray1D) -> Self {
+        let mut res = Array1D::zeros(value.len());
+        for i in 0..value.len() {
+            res[i] = value.get(i);
+        }
+        res.node = value.node;
+        res
+    }
+}
+
+/// `OPAC` of int4 operands: products are accumulated at the same scale as int8 ones.
+/// Operand faults of `fault` module are injected into unpacked lanes
+pub fn opac_int4(res: &mut Matrix, a: &PackedArray1D, b: &PackedArray1D) {
+    count_opac();
+    record_opac(res, &[a.node, b.node]);
+    let (mut a, mut b) = (Array1D::from(a), Array1D::from(b));
+    let call = fault::opac_operands(&mut a, &mut b);
+    for i in 0..res.rows() {
+        for j in 0..res.cols() {
+            let product = (a[i] as AccT * b[j] as AccT) << PRODUCT_SHIFT;
+            res[(i, j)] = res[(i, j)].wrapping_add(product);
+        }
+    }
+    fault::opac_accumulator(call, res);
+}
+
+/// Packs vector of any length on host, for storage size comparisons
+pub fn pack(values: ArrayView1<ChipT>) -> Result<Vec<u8>, &'static str> {
+    let range = Element::Int4.min()..=Element::Int4.max();
+    if !values.iter().all(|x| range.contains(x)) {
+        return Err("Value doesn't fit into int4");
+    }
+    let values = values.to_vec();
+    Ok(values
+        .chunks(2)
+        .map(|pair| (pair[0] as u8 & 0xf) | (pair.get(1).map_or(0, |x| *x as u8 & 0xf) << 4))
+        .collect())
+}
+
+/// Inverse of `pack`, `len` is the number of lanes
+pub fn unpack(bytes: &[u8], len: usize) -> Vec<ChipT> {
+    assert_eq!(bytes.len(), len.div_ceil(2));
+    let mut res = Vec::with_capacity(len);
+    for (i, byte) in zip(0..len, bytes.iter().flat_map(|b| [*b, *b])) {
+        res.push(lane(byte, i));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{arr1, Array2};
+    use crate::intrinsics::intrinsics::opac;
+
+    #[test]
+    fn pack_all_values() {
+        let values: Vec<ChipT> = (-8..8).collect();
+        let packed = PackedArray1D::from_slice(&values).unwrap();
+        assert_eq!(packed.as_bytes().len(), 8);
+        assert_eq!(Array1D::from(&packed).as_slice(), values.as_slice());
+        assert_eq!(unpack(&pack(arr1(&values[..15]).view()).unwrap(), 15), &values[..15]);
+        assert!(PackedArray1D::from_slice(&[8]).is_err());
+        assert!(PackedArray1D::try_from(&Array1D::from_slice(&[-9])).is_err());
+    }
+
+    #[test]
+    fn opac_same_scale_as_int8() {
+        let a = [-8, 3, 7];
+        let b = [5, -1];
+        let mut res4 = Matrix::zeros(3, 2);
+        opac_int4(&mut res4, &PackedArray1D::from_slice(&a).unwrap(), &PackedArray1D::from_slice(&b).unwrap());
+        // Same real values in int8 format: int4 value `x` is `16 * x`
+        let mut res8 = Matrix::zeros(3, 2);
+        let widen = |x: &[ChipT]| Array1D::from_slice(&x.iter().map(|v| v.saturating_mul(16)).collect::<Vec<_>>());
+        opac(&mut res8, widen(&[-8, 3, 7]), widen(&b));
+
+        let (mut out4, mut out8) = (Array2::zeros((3, 2)), Array2::zeros((3, 2)));
+        res4.convert(&mut out4.view_mut());
+        res8.convert(&mut out8.view_mut());
+        assert_eq!(out4, out8);
+        assert_eq!(out4[[0, 0]], -8. / 8. * 5. / 8.);
+    }
+}
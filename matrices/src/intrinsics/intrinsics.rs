@@ -1,14 +1,62 @@
 use super::config::DIMENSION;
+use super::fault;
+use super::trace::{add_sources, record, record_opac, record_vector, NodeId, TraceOp};
+use super::counters::{count_conversions, count_opac, count_to_device, count_to_host, count_vector, VectorKind};
 use ndarray::{ArrayView, ArrayView2, ArrayViewMut2, Ix1};
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::iter::zip;
-use std::ops::{Index, IndexMut};
+use std::ops::{AddAssign, Index, IndexMut};
 
-type ChipT = i8;
+pub type ChipT = i8;
+/// Widened accumulator type of `OPAC` matrix and reductions
+pub type AccT = i32;
 
+/// Vector register of the scalar core.
+/// Only first `sz` lanes are meaningful, the rest are kept zeroed.
+#[derive(Clone, Debug)]
 pub struct Array1D {
     data: [ChipT; DIMENSION],
     sz: usize,
+    /// Trace node, which produced the value
+    pub(crate) node: Option<NodeId>,
+}
+
+impl PartialEq for Array1D {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Array1D {
+    pub fn zeros(sz: usize) -> Self {
+        assert!(sz <= DIMENSION);
+        Array1D {
+            data: [0; DIMENSION],
+            sz,
+            node: None,
+        }
+    }
+
+    /// Loads values from host
+    pub fn from_slice(values: &[ChipT]) -> Self {
+        count_to_device(values.len() as u64);
+        let mut res = Self::zeros(values.len());
+        res.data[..values.len()].copy_from_slice(values);
+        res.node = record(TraceOp::Input, &[]);
+        res
+    }
+
+    pub fn len(&self) -> usize {
+        self.sz
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sz == 0
+    }
+
+    pub fn as_slice(&self) -> &[ChipT] {
+        &self.data[..self.sz]
+    }
 }
 
 impl Index<usize> for Array1D
@@ -22,31 +70,85 @@ impl Index<usize> for Array1D
     }
 }
 
+impl IndexMut<usize> for Array1D
+{
+    #[inline(always)]
+    fn index_mut(&mut self, index: usize) -> &mut ChipT {
+        assert!(index < self.sz);
+        self.data.index_mut(index)
+    }
+}
+
+/// Accumulator matrix of `OPAC` unit.
+/// Holds sums of products, so its cells are `AccT` with twice more fractional bits than operands.
 pub struct Matrix {
-    data: [ChipT; DIMENSION * DIMENSION],
+    data: Box<[AccT]>,
     rows: usize,
     cols: usize,
+    /// Trace node of the last pass into accumulator
+    pub(crate) node: Option<NodeId>,
 }
 
 impl Matrix {
     pub fn zeros(rows: usize, cols: usize) -> Self {
+        assert!(rows <= DIMENSION);
+        assert!(cols <= DIMENSION);
         Matrix {
-            data: [0; DIMENSION * DIMENSION],
+            data: vec![0; DIMENSION * DIMENSION].into_boxed_slice(),
             rows,
             cols,
+            node: None,
+        }
+    }
+
+    /// Zeroes accumulator for the next product of `rows` by `cols`, keeping its storage
+    pub fn reset(&mut self, rows: usize, cols: usize) {
+        assert!(rows <= DIMENSION);
+        assert!(cols <= DIMENSION);
+        // Cells out of the current shape are never written, so only it has to be cleared
+        for row in 0..self.rows {
+            self.data[row * DIMENSION..row * DIMENSION + self.cols].fill(0);
         }
+        self.rows = rows;
+        self.cols = cols;
+        self.node = None;
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    fn count_read_back(&self) {
+        let cells = (self.rows * self.cols) as u64;
+        count_conversions(cells);
+        count_to_host(cells * size_of::<AccT>() as u64);
     }
 
-    fn at(&self, row: usize, col: usize) -> ChipT {
+    fn at(&self, row: usize, col: usize) -> AccT {
         assert!(row < self.rows);
         assert!(col < self.cols);
         self.data[row * DIMENSION + col]
     }
 
     pub fn convert(&self, res: &mut ArrayViewMut2<f32>) {
+        self.count_read_back();
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                res[[row, col]] = scaled_to_f32(self.at(row, col));
+            }
+        }
+    }
+
+    /// Same as `convert`, but adds values to `res`
+    pub fn add_to<A: From<f32> + AddAssign>(&self, res: &mut ArrayViewMut2<A>) {
+        self.count_read_back();
         for row in 0..self.rows {
             for col in 0..self.cols {
-                res[[row, col]] = scaled_to_f32(self.at(row, col) as i16);
+                res[[row, col]] += A::from(scaled_to_f32(self.at(row, col)));
             }
         }
     }
@@ -55,7 +157,7 @@ impl Matrix {
 
 impl Index<(usize, usize)> for Matrix
 {
-    type Output = ChipT;
+    type Output = AccT;
 
     #[inline(always)]
     fn index(&self, index: (usize, usize)) -> &Self::Output {
@@ -67,7 +169,7 @@ impl Index<(usize, usize)> for Matrix
 impl IndexMut<(usize, usize)> for Matrix
 {
     #[inline(always)]
-    fn index_mut(&mut self, index: (usize, usize)) -> &mut ChipT {
+    fn index_mut(&mut self, index: (usize, usize)) -> &mut AccT {
         assert!(index.0 < self.rows);
         assert!(index.1 < self.cols);
         &mut self.data[index.0 * DIMENSION + index.1]
@@ -79,42 +181,62 @@ impl<'a> TryFrom<ArrayView<'a, f32, Ix1>> for Array1D {
     type Error = &'static str;
 
     fn try_from(value: ArrayView<'a, f32, Ix1>) -> Result<Self, Self::Error> {
-        if value.shape().len() > DIMENSION {
+        if value.len() > DIMENSION {
             Err("Tried to create OPAC array from higher dimension")
         } else {
+            count_conversions(value.len() as u64);
+            count_to_device(value.len() as u64);
             let mut d: [i8; DIMENSION] = [0;DIMENSION];
-            let values = value
-                .map(|x| f32_to_chip(*x))
-                .into_iter();
+            let values = value.iter().map(|x| f32_to_chip(*x));
             for (dst, src) in zip(&mut d, values) {
                 *dst = src;
             }
             Ok(Array1D {
                 data: d,
-                sz: DIMENSION,
+                sz: value.len(),
+                node: record(TraceOp::Input, &[]),
             })
         }
     }
 }
 
+impl<'a> TryFrom<ArrayView<'a, ChipT, Ix1>> for Array1D {
+    type Error = &'static str;
+
+    /// Loads raw chip values, without quantization
+    fn try_from(value: ArrayView<'a, ChipT, Ix1>) -> Result<Self, Self::Error> {
+        if value.len() > DIMENSION {
+            Err("Tried to create OPAC array from higher dimension")
+        } else {
+            count_to_device(value.len() as u64);
+            let mut res = Array1D::zeros(value.len());
+            for (dst, src) in zip(&mut res.data, value) {
+                *dst = *src;
+            }
+            res.node = record(TraceOp::Input, &[]);
+            Ok(res)
+        }
+    }
+}
+
 impl<'a> TryFrom<ArrayView2<'a, f32>> for Matrix {
     type Error = &'static str;
 
+    /// Preloads accumulator with values, ex: with bias
     fn try_from(value: ArrayView2<'a, f32>) -> Result<Self, Self::Error> {
-        if value.shape().len() > DIMENSION {
+        if value.nrows() > DIMENSION || value.ncols() > DIMENSION {
             Err("Tried to create OPAC array from higher dimension")
         } else {
-            let mut d = [0; DIMENSION * DIMENSION];
-            for i in 0..value.shape()[0] {
-                for j in 0..value.shape()[1] {
-                    d[i * DIMENSION + j] = f32_to_chip(value[[i, j]]);
+            count_conversions(value.len() as u64);
+            count_to_device((value.len() * size_of::<AccT>()) as u64);
+            let mut res = Matrix::zeros(value.nrows(), value.ncols());
+            for i in 0..value.nrows() {
+                for j in 0..value.ncols() {
+                    res[(i, j)] = f32_to_acc(value[[i, j]]);
                 }
             }
-            Ok(Matrix {
-                data: d,
-                rows: value.shape()[0],
-                cols: value.shape()[1],
-            })
+            res.node = record(TraceOp::Input, &[]);
+            Ok(res)
         }
     }
 }
@@ -124,41 +246,481 @@ fn f32_to_chip(x: f32) -> ChipT {
     (x * 128.0).round() as i8
 }
 
-fn scaled_to_f32(x: i16) -> f32 {
-    println!("Back {}", x);
+fn f32_to_acc(x: f32) -> AccT {
+    (x * 128.0 * 128.0).round() as AccT
+}
+
+fn scaled_to_f32(x: AccT) -> f32 {
     x as f32 / 128.0 / 128.0
 }
 
-pub fn opac(res: &mut Matrix, a: Array1D, b: Array1D) {
+/// Outer product pass: `res += a * b^T`. Accumulator wraps on overflow
+pub fn opac(res: &mut Matrix, mut a: Array1D, mut b: Array1D) {
+    count_opac();
+    record_opac(res, &[a.node, b.node]);
+    let call = fault::opac_operands(&mut a, &mut b);
     for i in 0..res.rows {
         for j in 0..res.cols {
-            println!("{} {} {}", i, j, a[i] * b[j]);
-            res[(j, i)] += a[i] * b[j];
+            res[(i, j)] = res[(i, j)].wrapping_add(a[i] as AccT * b[j] as AccT);
         }
     }
+    fault::opac_accumulator(call, res);
+}
+
+/// What happens to lanes disabled by `LaneMask`
+#[derive(Clone, Copy, Debug)]
+pub enum Inactive<'a> {
+    /// Lane keeps value of destination register
+    Keep(&'a Array1D),
+    /// Lane is set to zero
+    Zero,
+}
+
+/// Predicate for vector instructions.
+/// Lane is active when `mask` lane is non-zero, so masks from `v_cmp_*` can be used directly.
+#[derive(Clone, Copy, Debug)]
+pub struct LaneMask<'a> {
+    mask: &'a Array1D,
+    inactive: Inactive<'a>,
 }
 
-pub fn sca_mul(a: &Array1D, b: &Array1D) -> Array1D {
-    let mut res = [0; DIMENSION];
-    for (i, (a, b)) in zip(a.data, b.data).enumerate() {
-        res[i] = a * b;
+impl<'a> LaneMask<'a> {
+    pub fn new(mask: &'a Array1D, inactive: Inactive<'a>) -> Self {
+        LaneMask { mask, inactive }
+    }
+
+    pub fn is_active(&self, lane: usize) -> bool {
+        self.mask.data[lane] != MASK_FALSE
+    }
+
+    /// Mask register, checked against operand of `sz` lanes
+    pub(crate) fn lanes(&self, sz: usize) -> &'a Array1D {
+        assert_eq!(self.mask.sz, sz, "Mask and operand have different length");
+        self.mask
+    }
+
+    /// Checks lengths against result of `sz` lanes, returns the registers read by the mask
+    fn sources(&self, sz: usize) -> Vec<&'a Array1D> {
+        assert_eq!(self.mask.sz, sz, "Mask and operand have different length");
+        match self.inactive {
+            Inactive::Keep(dst) => {
+                assert_eq!(dst.sz, sz, "Destination and operand have different length");
+                vec![self.mask, dst]
+            }
+            Inactive::Zero => vec![self.mask],
+        }
+    }
+
+    /// Value of inactive `lane`
+    fn inactive_lane(&self, lane: usize) -> ChipT {
+        match self.inactive {
+            Inactive::Keep(dst) => dst.data[lane],
+            Inactive::Zero => 0,
+        }
     }
-    Array1D { data: res, sz: DIMENSION }
 }
 
-pub fn v_min(a: &Array1D, b: &Array1D) -> Array1D {
+/// Masked variant of instruction, which has computed all lanes of `res`:
+/// inactive lanes are replaced, and the mask becomes a source of the result
+pub(crate) fn mask_result(mut res: Array1D, mask: &LaneMask) -> Array1D {
+    add_sources(&res, &mask.sources(res.sz));
+    for i in 0..res.sz {
+        if !mask.is_active(i) {
+            res.data[i] = mask.inactive_lane(i);
+        }
+    }
+    res
+}
 
-    let mut res = [0; DIMENSION];
-    for (i, (a, b)) in zip(a.data, b.data).enumerate() {
-        res[i] = max(a, b);
+/// Mask with first `active` of `sz` lanes enabled.
+/// Useful for the tail of vector, which is shorter than `DIMENSION`.
+pub fn tail_mask(active: usize, sz: usize) -> Array1D {
+    assert!(active <= sz);
+    let mut res = Array1D::zeros(sz);
+    for i in 0..active {
+        res.data[i] = MASK_TRUE;
     }
-    Array1D { data: res, sz: DIMENSION }
+    res
 }
 
-pub fn v_max(a: &Array1D, b: &Array1D) -> Array1D {
-    let mut res = [0; DIMENSION];
-    for (i, (a, b)) in zip(a.data, b.data).enumerate() {
-        res[i] = max(a, b);
+/// Applies `op` lane-wise to the first `sz` lanes, only to active ones when there is `mask`.
+/// Both operands must have the same length.
+#[inline(always)]
+fn binary_op(
+    a: &Array1D,
+    b: &Array1D,
+    mask: Option<&LaneMask>,
+    kind: VectorKind,
+    trace: TraceOp,
+    op: impl Fn(ChipT, ChipT) -> ChipT,
+) -> Array1D {
+    assert_eq!(a.sz, b.sz, "Vector operands have different length");
+    let mut sources = vec![a, b];
+    sources.extend(mask.map(|m| m.sources(a.sz)).unwrap_or_default());
+    count_vector(kind);
+    let mut res = Array1D::zeros(a.sz);
+    for i in 0..a.sz {
+        res.data[i] = match mask {
+            Some(m) if !m.is_active(i) => m.inactive_lane(i),
+            _ => op(a.data[i], b.data[i]),
+        };
+    }
+    record_vector(trace, &sources, &mut res);
+    fault::vector_result(&mut res);
+    res
+}
+
+#[inline(always)]
+fn unary_op(a: &Array1D, mask: Option<&LaneMask>, kind: VectorKind, trace: TraceOp, op: impl Fn(ChipT) -> ChipT) -> Array1D {
+    let mut sources = vec![a];
+    sources.extend(mask.map(|m| m.sources(a.sz)).unwrap_or_default());
+    count_vector(kind);
+    let mut res = Array1D::zeros(a.sz);
+    for i in 0..a.sz {
+        res.data[i] = match mask {
+            Some(m) if !m.is_active(i) => m.inactive_lane(i),
+            _ => op(a.data[i]),
+        };
+    }
+    record_vector(trace, &sources, &mut res);
+    fault::vector_result(&mut res);
+    res
+}
+
+/// Defines binary instruction and its `_masked` variant, which computes only active lanes
+macro_rules! binary {
+    ($(#[$doc:meta])* $name:ident, $masked:ident, $kind:ident, $trace:ident, $op:expr) => {
+        $(#[$doc])*
+        pub fn $name(a: &Array1D, b: &Array1D) -> Array1D {
+            binary_op(a, b, None, VectorKind::$kind, TraceOp::$trace, $op)
+        }
+
+        #[doc = concat!("`", stringify!($name), "` under `mask`")]
+        pub fn $masked(a: &Array1D, b: &Array1D, mask: &LaneMask) -> Array1D {
+            binary_op(a, b, Some(mask), VectorKind::$kind, TraceOp::$trace, $op)
+        }
+    };
+}
+
+/// Same as `binary!`, for instructions with one operand
+macro_rules! unary {
+    ($(#[$doc:meta])* $name:ident, $masked:ident, $kind:ident, $trace:ident, $op:expr) => {
+        $(#[$doc])*
+        pub fn $name(a: &Array1D) -> Array1D {
+            unary_op(a, None, VectorKind::$kind, TraceOp::$trace, $op)
+        }
+
+        #[doc = concat!("`", stringify!($name), "` under `mask`")]
+        pub fn $masked(a: &Array1D, mask: &LaneMask) -> Array1D {
+            unary_op(a, Some(mask), VectorKind::$kind, TraceOp::$trace, $op)
+        }
+    };
+}
+
+/// Mask lane value for `true`, all bits set
+pub const MASK_TRUE: ChipT = -1;
+/// Mask lane value for `false`
+pub const MASK_FALSE: ChipT = 0;
+
+fn to_mask(x: bool) -> ChipT {
+    if x { MASK_TRUE } else { MASK_FALSE }
+}
+
+binary!(
+    /// Lane-wise product, keeps lower 8 bits (wrapping)
+    sca_mul, sca_mul_masked, Arithmetic, ScaMul, |a, b| a.wrapping_mul(b)
+);
+
+binary!(
+    /// Lane-wise product, keeps upper 8 bits of 16-bit result
+    v_mulhi, v_mulhi_masked, Arithmetic, MulHi, |a, b| ((a as i16 * b as i16) >> 8) as ChipT
+);
+
+binary!(
+    /// Lane-wise minimum, compare-exchange together with `v_max`
+    v_min, v_min_masked, Arithmetic, Min, min
+);
+
+binary!(
+    /// Lane-wise maximum
+    v_max, v_max_masked, Arithmetic, Max, max
+);
+
+binary!(
+    /// Wrapping addition
+    v_add, v_add_masked, Arithmetic, Add, ChipT::wrapping_add
+);
+
+binary!(
+    /// Wrapping subtraction
+    v_sub, v_sub_masked, Arithmetic, Sub, ChipT::wrapping_sub
+);
+
+binary!(
+    /// Addition clamped to `[ChipT::MIN, ChipT::MAX]`
+    v_add_sat, v_add_sat_masked, Arithmetic, AddSat, ChipT::saturating_add
+);
+
+binary!(
+    /// Subtraction clamped to `[ChipT::MIN, ChipT::MAX]`
+    v_sub_sat, v_sub_sat_masked, Arithmetic, SubSat, ChipT::saturating_sub
+);
+
+unary!(
+    /// Wrapping absolute value, so `abs(MIN) == MIN`
+    v_abs, v_abs_masked, Arithmetic, Abs, ChipT::wrapping_abs
+);
+
+unary!(
+    /// Absolute value clamped, so `abs(MIN) == MAX`
+    v_abs_sat, v_abs_sat_masked, Arithmetic, AbsSat, ChipT::saturating_abs
+);
+
+unary!(
+    /// Wrapping negation, so `-MIN == MIN`
+    v_neg, v_neg_masked, Arithmetic, Neg, ChipT::wrapping_neg
+);
+
+unary!(
+    /// Negation clamped, so `-MIN == MAX`
+    v_neg_sat, v_neg_sat_masked, Arithmetic, NegSat, ChipT::saturating_neg
+);
+
+binary!(
+    /// Left shift of `a` by amount from `b`.
+    /// Amount is unsigned, shifts by 8 or more produce 0
+    v_shl, v_shl_masked, Logic, Vector, |a, b| {
+        let amount = b as u8 as u32;
+        if amount >= ChipT::BITS { 0 } else { a << amount }
+    }
+);
+
+binary!(
+    /// Arithmetic right shift of `a` by amount from `b`.
+    /// Amount is unsigned, shifts by 8 or more produce sign fill
+    v_shr, v_shr_masked, Logic, Vector, |a, b| {
+        let amount = b as u8 as u32;
+        a >> min(amount, ChipT::BITS - 1)
+    }
+);
+
+binary!(
+    /// Lane-wise bitwise and
+    v_and, v_and_masked, Logic, Vector, |a, b| a & b
+);
+
+binary!(
+    /// Lane-wise bitwise or
+    v_or, v_or_masked, Logic, Vector, |a, b| a | b
+);
+
+binary!(
+    /// Lane-wise bitwise xor
+    v_xor, v_xor_masked, Logic, Vector, |a, b| a ^ b
+);
+
+binary!(
+    /// Lane-wise `a == b`, returns mask with `MASK_TRUE`/`MASK_FALSE` lanes
+    v_cmp_eq, v_cmp_eq_masked, Compare, Vector, |a, b| to_mask(a == b)
+);
+
+binary!(
+    /// Lane-wise `a < b`, returns mask with `MASK_TRUE`/`MASK_FALSE` lanes
+    v_cmp_lt, v_cmp_lt_masked, Compare, Vector, |a, b| to_mask(a < b)
+);
+
+binary!(
+    /// Lane-wise `a <= b`, returns mask with `MASK_TRUE`/`MASK_FALSE` lanes
+    v_cmp_le, v_cmp_le_masked, Compare, Vector, |a, b| to_mask(a <= b)
+);
+
+binary!(
+    /// Lane-wise `a > b`, returns mask with `MASK_TRUE`/`MASK_FALSE` lanes
+    v_cmp_gt, v_cmp_gt_masked, Compare, Vector, |a, b| to_mask(a > b)
+);
+
+/// Takes lane from `a` where `mask` lane is non-zero, otherwise from `b`
+pub fn v_select(mask: &Array1D, a: &Array1D, b: &Array1D) -> Array1D {
+    assert_eq!(mask.sz, a.sz, "Mask and operand have different length");
+    assert_eq!(a.sz, b.sz, "Vector operands have different length");
+    count_vector(VectorKind::Select);
+    let mut res = Array1D::zeros(a.sz);
+    for i in 0..a.sz {
+        res.data[i] = if mask.data[i] != MASK_FALSE { a.data[i] } else { b.data[i] };
+    }
+    record_vector(TraceOp::Vector, &[mask, a, b], &mut res);
+    fault::vector_result(&mut res);
+    res
+}
+
+/// `v_select` under `lanes`
+pub fn v_select_masked(mask: &Array1D, a: &Array1D, b: &Array1D, lanes: &LaneMask) -> Array1D {
+    mask_result(v_select(mask, a, b), lanes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intrinsics::counters::counted;
+
+    /// All `(a, b)` pairs of chip values, split by vectors of `DIMENSION` lanes
+    fn all_pairs() -> Vec<(Array1D, Array1D)> {
+        let pairs: Vec<(ChipT, ChipT)> = (ChipT::MIN..=ChipT::MAX)
+            .flat_map(|a| (ChipT::MIN..=ChipT::MAX).map(move |b| (a, b)))
+            .collect();
+        pairs
+            .chunks(DIMENSION)
+            .map(|chunk| {
+                let a: Vec<_> = chunk.iter().map(|x| x.0).collect();
+                let b: Vec<_> = chunk.iter().map(|x| x.1).collect();
+                (Array1D::from_slice(&a), Array1D::from_slice(&b))
+            })
+            .collect()
+    }
+
+    fn check_binary(op: fn(&Array1D, &Array1D) -> Array1D, expected: impl Fn(i16, i16) -> i16) {
+        for (a, b) in all_pairs() {
+            let res = op(&a, &b);
+            assert_eq!(res.len(), a.len());
+            for i in 0..a.len() {
+                assert_eq!(res[i] as i16, expected(a[i] as i16, b[i] as i16), "a={} b={}", a[i], b[i]);
+            }
+        }
+    }
+
+    fn check_unary(op: fn(&Array1D) -> Array1D, expected: impl Fn(i16) -> i16) {
+        let values: Vec<ChipT> = (ChipT::MIN..=ChipT::MAX).collect();
+        let a = Array1D::from_slice(&values);
+        let res = op(&a);
+        assert_eq!(res.len(), a.len());
+        for i in 0..a.len() {
+            assert_eq!(res[i] as i16, expected(a[i] as i16), "a={}", a[i]);
+        }
+    }
+
+    fn wrap(x: i16) -> i16 {
+        x as i8 as i16
+    }
+
+    fn sat(x: i16) -> i16 {
+        x.clamp(ChipT::MIN as i16, ChipT::MAX as i16)
+    }
+
+    fn mask(x: bool) -> i16 {
+        if x { -1 } else { 0 }
+    }
+
+    #[test]
+    fn arithmetic() {
+        check_binary(v_add, |a, b| wrap(a + b));
+        check_binary(v_sub, |a, b| wrap(a - b));
+        check_binary(v_add_sat, |a, b| sat(a + b));
+        check_binary(v_sub_sat, |a, b| sat(a - b));
+        check_binary(sca_mul, |a, b| wrap(a * b));
+        check_binary(v_mulhi, |a, b| (a * b) >> 8);
+        check_unary(v_abs, |a| wrap(a.abs()));
+        check_unary(v_abs_sat, |a| sat(a.abs()));
+        check_unary(v_neg, |a| wrap(-a));
+        check_unary(v_neg_sat, |a| sat(-a));
+    }
+
+    #[test]
+    fn min_max() {
+        check_binary(v_min, |a, b| a.min(b));
+        check_binary(v_max, |a, b| a.max(b));
+    }
+
+    #[test]
+    fn shifts() {
+        check_binary(v_shl, |a, b| {
+            let amount = b as u8 as u32;
+            if amount >= 8 { 0 } else { wrap(a << amount) }
+        });
+        check_binary(v_shr, |a, b| a >> (b as u8).min(7));
+    }
+
+    #[test]
+    fn bitwise() {
+        check_binary(v_and, |a, b| a & b);
+        check_binary(v_or, |a, b| a | b);
+        check_binary(v_xor, |a, b| a ^ b);
+    }
+
+    #[test]
+    fn compare() {
+        check_binary(v_cmp_eq, |a, b| mask(a == b));
+        check_binary(v_cmp_lt, |a, b| mask(a < b));
+        check_binary(v_cmp_le, |a, b| mask(a <= b));
+        check_binary(v_cmp_gt, |a, b| mask(a > b));
+    }
+
+    #[test]
+    fn select() {
+        for (a, b) in all_pairs() {
+            let m = v_cmp_lt(&a, &b);
+            let res = v_select(&m, &a, &b);
+            assert_eq!(res, v_min(&a, &b));
+        }
+    }
+
+    #[test]
+    fn length_tracking() {
+        let a = Array1D::from_slice(&[1, -2, 3]);
+        let b = Array1D::from_slice(&[-1, 2, -3]);
+        let res = v_min(&a, &b);
+        assert_eq!(res.len(), 3);
+        assert_eq!(res.as_slice(), &[-1, -2, -3]);
+        assert_eq!(v_max(&a, &b).as_slice(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn masked() {
+        let a = Array1D::from_slice(&[5, -5, 7, 100]);
+        let b = Array1D::from_slice(&[1, 1, 1, 100]);
+        let dst = Array1D::from_slice(&[9, 9, 9, 9]);
+        let m = v_cmp_gt(&a, &b);
+
+        let keep = LaneMask::new(&m, Inactive::Keep(&dst));
+        assert_eq!(v_sub_masked(&a, &b, &keep).as_slice(), &[4, 9, 6, 9]);
+        assert_eq!(v_neg_masked(&a, &keep).as_slice(), &[-5, 9, -7, 9]);
+        let zero = LaneMask::new(&m, Inactive::Zero);
+        assert_eq!(v_sub_masked(&a, &b, &zero).as_slice(), &[4, 0, 6, 0]);
+        assert_eq!(v_cmp_eq_masked(&a, &a, &zero).as_slice(), &[MASK_TRUE, 0, MASK_TRUE, 0]);
+        let sel = tail_mask(1, 4);
+        assert_eq!(v_select_masked(&sel, &a, &b, &keep).as_slice(), &[5, 9, 1, 9]);
+    }
+
+    #[test]
+    fn masked_counts_once() {
+        let a = Array1D::from_slice(&[1, 2, 3]);
+        let m = tail_mask(1, 3);
+        let (res, counters) = counted(|| v_add_masked(&a, &a, &LaneMask::new(&m, Inactive::Zero)));
+        assert_eq!(res.as_slice(), &[2, 0, 0]);
+        assert_eq!(counters.vector, 1);
+        assert_eq!(counters.vectors.arithmetic, 1);
+    }
+
+    #[test]
+    fn masked_tail() {
+        // Only first 2 lanes hold data, tail keeps the accumulator instead of zero padding
+        let acc = Array1D::from_slice(&[-3, -4, -5, -6]);
+        let x = Array1D::from_slice(&[1, -7, 0, 0]);
+        let tail = tail_mask(2, 4);
+        let m = LaneMask::new(&tail, Inactive::Keep(&acc));
+        assert_eq!(v_max_masked(&acc, &x, &m).as_slice(), &[1, -4, -5, -6]);
+        assert_eq!(v_min_masked(&acc, &x, &m).as_slice(), &[-3, -7, -5, -6]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn mask_length_mismatch() {
+        let m = tail_mask(1, 2);
+        v_add_masked(&Array1D::zeros(3), &Array1D::zeros(3), &LaneMask::new(&m, Inactive::Zero));
+    }
+
+    #[test]
+    #[should_panic]
+    fn length_mismatch() {
+        v_add(&Array1D::zeros(2), &Array1D::zeros(3));
     }
-    Array1D { data: res, sz: DIMENSION }
 }
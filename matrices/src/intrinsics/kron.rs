@@ -0,0 +1,114 @@
+//! Kronecker and Khatri-Rao products on `OPAC`.
+//!
+//! Both are made of vector outer products: Kronecker product is the outer product of
+//! flattened operands with rearranged entries, Khatri-Rao product is one outer product
+//! per column. Operands are brought into operand range by a factor, which is applied back
+//! to results, and outer products are tiled by `DIMENSION`.
+
+use std::cmp::min;
+use ndarray::{s, Array2, ArrayView1, ArrayView2};
+use super::config::DIMENSION;
+use super::intrinsics::{opac, Array1D, Matrix};
+use super::quant::range_factor;
+
+/// Outer product `x * y^T` of any length vectors, one `OPAC` pass per tile.
+/// Every tile is passed to `write` with its offset
+fn outer(x: ArrayView1<f32>, y: ArrayView1<f32>, mut write: impl FnMut((usize, usize), ArrayView2<f32>)) {
+    let (fx, fy) = (range_factor(x), range_factor(y));
+    let (x, y) = (&x / fx, &y / fy);
+    let mut tile = Array2::zeros((min(x.len(), DIMENSION), min(y.len(), DIMENSION)));
+    for i in (0..x.len()).step_by(DIMENSION) {
+        let rows = i..min(i + DIMENSION, x.len());
+        // Register is loaded once and reused by all passes of the row of tiles
+        let xi: Array1D = x.slice(s![rows.clone()]).try_into().unwrap();
+        for j in (0..y.len()).step_by(DIMENSION) {
+            let cols = j..min(j + DIMENSION, y.len());
+            let mut res_mat = Matrix::zeros(rows.len(), cols.len());
+            opac(&mut res_mat, xi.clone(), y.slice(s![cols.clone()]).try_into().unwrap());
+            let mut block = tile.slice_mut(s![..rows.len(), ..cols.len()]);
+            res_mat.convert(&mut block);
+            block *= fx * fy;
+            write((i, j), block.view());
+        }
+    }
+}
+
+/// Kronecker product: `res[i * r + k, j * s + l] = a[i, j] * b[k, l]`,
+/// for `a` of shape `[p, q]` and `b` of shape `[r, s]`
+pub fn kron(a: ArrayView2<f32>, b: ArrayView2<f32>) -> Array2<f32> {
+    let ((p, q), (r, s)) = (a.dim(), b.dim());
+    let x: Vec<f32> = a.iter().copied().collect();
+    let y: Vec<f32> = b.iter().copied().collect();
+    let mut res = Array2::zeros((p * r, q * s));
+    outer(ArrayView1::from(&x), ArrayView1::from(&y), |(i0, j0), tile| {
+        for ((di, dj), value) in tile.indexed_iter() {
+            let (ij, kl) = (i0 + di, j0 + dj);
+            res[[ij / q * r + kl / s, ij % q * s + kl % s]] = *value;
+        }
+    });
+    res
+}
+
+/// Column-wise Kronecker product: `res[i * J + j, c] = a[i, c] * b[j, c]`,
+/// for `a` of shape `[I, R]` and `b` of shape `[J, R]`
+pub fn khatri_rao(a: ArrayView2<f32>, b: ArrayView2<f32>) -> Array2<f32> {
+    assert_eq!(a.ncols(), b.ncols(), "Operands have different number of columns");
+    let rows_b = b.nrows();
+    let mut res = Array2::zeros((a.nrows() * rows_b, a.ncols()));
+    for (c, (x, y)) in a.columns().into_iter().zip(b.columns()).enumerate() {
+        outer(x, y, |(i0, j0), tile| {
+            for ((di, dj), value) in tile.indexed_iter() {
+                res[[(i0 + di) * rows_b + j0 + dj, c]] = *value;
+            }
+        });
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sample;
+    use crate::intrinsics::counters::counted;
+
+    fn assert_close(res: &Array2<f32>, expected: &Array2<f32>, tol: f32) {
+        assert_eq!(res.dim(), expected.dim());
+        for (x, y) in res.iter().zip(expected) {
+            assert!((x - y).abs() <= tol, "{} {}", x, y);
+        }
+    }
+
+    #[test]
+    fn kronecker() {
+        let (a, b) = (sample(3, 2, 1, 2.), sample(2, 4, 2, 2.));
+        let mut expected = Array2::zeros((6, 8));
+        for ((i, j), x) in a.indexed_iter() {
+            expected.slice_mut(s![i * 2..i * 2 + 2, j * 4..j * 4 + 4]).assign(&(&b * *x));
+        }
+        // Rounding of each operand is half a step of its range
+        assert_close(&kron(a.view(), b.view()), &expected, 2. * 4. / 128.);
+
+        // Flattened operands are longer than DIMENSION
+        let (a, b) = (sample(40, 30, 3, 2.), sample(30, 40, 4, 2.));
+        let (res, counters) = counted(|| kron(a.view(), b.view()));
+        assert_eq!(res.dim(), (1200, 1200));
+        assert_eq!(counters.opac, 4);
+        assert!((res[[1199, 1199]] - a[[39, 29]] * b[[29, 39]]).abs() <= 4. / 64.);
+    }
+
+    #[test]
+    fn khatri_rao_columns() {
+        let (a, b) = (sample(5, 3, 1, 2.), sample(4, 3, 2, 2.));
+        let mut expected = Array2::zeros((20, 3));
+        for c in 0..3 {
+            for i in 0..5 {
+                for j in 0..4 {
+                    expected[[i * 4 + j, c]] = a[[i, c]] * b[[j, c]];
+                }
+            }
+        }
+        let (res, counters) = counted(|| khatri_rao(a.view(), b.view()));
+        assert_close(&res, &expected, 2. * 4. / 128.);
+        assert_eq!(counters.opac, 3);
+    }
+}
@@ -0,0 +1,177 @@
+//! Lookup table unit of the scalar core.
+//!
+//! Table has an entry for every `ChipT` value, so any function of one lane
+//! is computed exactly up to quantization of its input and output.
+//! Tables are generated on host from `f32` functions.
+
+use super::counters::{count_vector, VectorKind};
+use super::trace::{record_vector, TraceOp};
+use super::intrinsics::{mask_result, Array1D, ChipT, LaneMask};
+use super::fault;
+use super::quant::Quantizer;
+
+const TABLE_SIZE: usize = 1 << ChipT::BITS;
+
+pub struct Lut {
+    table: [ChipT; TABLE_SIZE],
+    input: Quantizer,
+    output: Quantizer,
+    max_error: f32,
+}
+
+impl Lut {
+    /// Tabulates `f` for inputs quantized with `input` and results quantized with `output`.
+    /// Results out of output range are saturated.
+    pub fn from_fn(f: impl Fn(f32) -> f32, input: Quantizer, output: Quantizer) -> Self {
+        let mut table = [0; TABLE_SIZE];
+        let mut max_error: f32 = 0.;
+        for x in ChipT::MIN..=ChipT::MAX {
+            let expected = f(input.dequantize(x));
+            let y = output.quantize(expected);
+            table[Self::slot(x)] = y;
+            // Poles (ex: reciprocal of 0) can't be represented at all, so they aren't counted
+            if expected.is_finite() {
+                max_error = max_error.max((output.dequantize(y) - expected).abs());
+            }
+        }
+        Lut { table, input, output, max_error }
+    }
+
+    pub fn exp(input: Quantizer, output: Quantizer) -> Self {
+        Self::from_fn(f32::exp, input, output)
+    }
+
+    pub fn sigmoid(input: Quantizer, output: Quantizer) -> Self {
+        Self::from_fn(|x| 1. / (1. + (-x).exp()), input, output)
+    }
+
+    pub fn tanh(input: Quantizer, output: Quantizer) -> Self {
+        Self::from_fn(f32::tanh, input, output)
+    }
+
+    /// GELU with tanh approximation, as used by most int8 transformer runtimes
+    pub fn gelu(input: Quantizer, output: Quantizer) -> Self {
+        Self::from_fn(gelu, input, output)
+    }
+
+    /// `1 / x`, entry for `0` is saturated
+    pub fn reciprocal(input: Quantizer, output: Quantizer) -> Self {
+        Self::from_fn(f32::recip, input, output)
+    }
+
+    #[inline(always)]
+    fn slot(x: ChipT) -> usize {
+        x as u8 as usize
+    }
+
+    pub fn lookup(&self, x: ChipT) -> ChipT {
+        self.table[Self::slot(x)]
+    }
+
+    pub fn input(&self) -> Quantizer {
+        self.input
+    }
+
+    pub fn output(&self) -> Quantizer {
+        self.output
+    }
+
+    /// Maximum absolute error of table against `f32` function,
+    /// over all representable inputs
+    pub fn max_error(&self) -> f32 {
+        self.max_error
+    }
+}
+
+fn gelu(x: f32) -> f32 {
+    let c = (2. / std::f32::consts::PI).sqrt();
+    0.5 * x * (1. + (c * (x + 0.044715 * x * x * x)).tanh())
+}
+
+/// Lane-wise table lookup
+pub fn v_lut(lut: &Lut, a: &Array1D) -> Array1D {
+    count_vector(VectorKind::Lookup);
+    let mut res = Array1D::zeros(a.len());
+    for i in 0..a.len() {
+        res[i] = lut.lookup(a[i]);
+    }
+    record_vector(TraceOp::Vector, &[a], &mut res);
+    fault::vector_result(&mut res);
+    res
+}
+
+/// `v_lut` under `mask`
+pub fn v_lut_masked(lut: &Lut, a: &Array1D, mask: &LaneMask) -> Array1D {
+    mask_result(v_lut(lut, a), mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intrinsics::intrinsics::Inactive;
+
+    fn check(lut: &Lut, f: impl Fn(f32) -> f32) {
+        let mut max_error: f32 = 0.;
+        let values: Vec<ChipT> = (ChipT::MIN..=ChipT::MAX).collect();
+        let res = v_lut(lut, &Array1D::from_slice(&values));
+        for (i, x) in values.iter().enumerate() {
+            let expected = f(lut.input().dequantize(*x));
+            if !expected.is_finite() {
+                continue;
+            }
+            max_error = max_error.max((lut.output().dequantize(res[i]) - expected).abs());
+        }
+        assert_eq!(max_error, lut.max_error());
+    }
+
+    #[test]
+    fn identity_is_exact() {
+        let q = Quantizer::default();
+        let lut = Lut::from_fn(|x| x, q, q);
+        assert_eq!(lut.max_error(), 0.);
+        for x in ChipT::MIN..=ChipT::MAX {
+            assert_eq!(lut.lookup(x), x);
+        }
+    }
+
+    #[test]
+    fn bounded_functions() {
+        // Inputs in [-8, 8), outputs in [-1, 1): only rounding and saturation at 1 remain
+        let input = Quantizer::new(1. / 16.);
+        let output = Quantizer::new(1. / 128.);
+        let sigmoid = Lut::sigmoid(input, output);
+        let tanh = Lut::tanh(input, output);
+        check(&sigmoid, |x| 1. / (1. + (-x).exp()));
+        check(&tanh, f32::tanh);
+        assert!(sigmoid.max_error() <= 1. / 128. + f32::EPSILON);
+        assert!(tanh.max_error() <= 1. / 128. + f32::EPSILON);
+    }
+
+    #[test]
+    fn unbounded_functions() {
+        let input = Quantizer::new(1. / 16.);
+        let exp = Lut::exp(input, Quantizer::new(1. / 128.));
+        let gelu_lut = Lut::gelu(input, Quantizer::new(1. / 16.));
+        let recip = Lut::reciprocal(input, Quantizer::new(1. / 8.));
+        check(&exp, f32::exp);
+        check(&gelu_lut, gelu);
+        check(&recip, f32::recip);
+        // Negative inputs of exp, as in softmax, don't saturate
+        for x in ChipT::MIN..0 {
+            let expected = input.dequantize(x).exp();
+            assert!((exp.output().dequantize(exp.lookup(x)) - expected).abs() <= 0.5 / 128. + f32::EPSILON);
+        }
+        assert!(gelu_lut.max_error() <= 0.5 / 16. + f32::EPSILON);
+        assert_eq!(recip.lookup(0), ChipT::MAX);
+    }
+
+    #[test]
+    fn masked() {
+        let q = Quantizer::default();
+        let negate = Lut::from_fn(|x| -x, q, q);
+        let a = Array1D::from_slice(&[1, 2, 3]);
+        let m = Array1D::from_slice(&[0, -1, 0]);
+        let res = v_lut_masked(&negate, &a, &LaneMask::new(&m, Inactive::Keep(&a)));
+        assert_eq!(res.as_slice(), &[1, -2, 3]);
+    }
+}
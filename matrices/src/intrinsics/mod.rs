@@ -1,4 +1,21 @@
-pub(crate) mod config;
-pub(crate) mod wrappers;
+pub mod abft;
+pub mod bound;
+pub mod chain;
+pub mod complex;
+pub mod config;
+pub mod counters;
+pub mod lut;
+pub mod fault;
+pub mod int4;
+pub mod kron;
+pub mod quant;
+pub mod reductions;
+pub mod shuffle;
+pub mod sparse;
+pub mod strassen;
+pub mod streaming;
+pub mod trace;
+pub mod wrappers;
 
-mod intrinsics;
+#[allow(clippy::module_inception)]
+pub mod intrinsics;
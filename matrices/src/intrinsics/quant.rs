@@ -0,0 +1,135 @@
+//! Linear symmetric quantization between `f32` and chip values
+
+use super::intrinsics::ChipT;
+
+/// Element type of `OPAC` operands
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Element {
+    Int8,
+    /// Packed two per byte, see `int4::PackedArray1D`
+    Int4,
+}
+
+impl Element {
+    pub const fn bits(&self) -> u32 {
+        match self {
+            Element::Int8 => 8,
+            Element::Int4 => 4,
+        }
+    }
+
+    pub fn min(&self) -> ChipT {
+        -(1 << (self.bits() - 1)) as ChipT
+    }
+
+    pub fn max(&self) -> ChipT {
+        ((1 << (self.bits() - 1)) - 1) as ChipT
+    }
+
+    /// Fixed point format of `OPAC` operands: all bits except sign are fractional
+    pub fn quantizer(&self) -> Quantizer {
+        Quantizer::with_element(1. / (1 << (self.bits() - 1)) as f32, *self)
+    }
+}
+
+/// Largest magnitude of `OPAC` operand in `Q0.7`
+pub const OPERAND_MAX: f32 = ChipT::MAX as f32 / 128.;
+
+/// Largest absolute value of `x`, zero when it's empty
+pub fn max_abs<'a>(x: impl IntoIterator<Item = &'a f32>) -> f32 {
+    x.into_iter().fold(0., |acc, v| acc.max(v.abs()))
+}
+
+/// Factor to divide `x` by, so that it fits into `[-OPERAND_MAX, OPERAND_MAX]`.
+/// It's 1 when `x` is all zeros.
+pub fn range_factor<'a>(x: impl IntoIterator<Item = &'a f32>) -> f32 {
+    let max_abs = max_abs(x);
+    if max_abs > 0. { max_abs / OPERAND_MAX } else { 1. }
+}
+
+/// Maps real value `x` to `round(x / scale)`, clamped to element range.
+/// So `scale` is the real value of one quantization step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quantizer {
+    scale: f32,
+    element: Element,
+}
+
+impl Quantizer {
+    pub fn new(scale: f32) -> Self {
+        Self::with_element(scale, Element::Int8)
+    }
+
+    pub fn int4(scale: f32) -> Self {
+        Self::with_element(scale, Element::Int4)
+    }
+
+    pub fn with_element(scale: f32, element: Element) -> Self {
+        assert!(scale > 0. && scale.is_finite(), "Quantization scale must be positive");
+        Quantizer { scale, element }
+    }
+
+    /// Quantizer which covers `[-max_abs, max_abs]` range
+    pub fn for_range(max_abs: f32) -> Self {
+        Self::new(max_abs / ChipT::MAX as f32)
+    }
+
+    /// Quantizer which covers all values of `x`, or `[-1, 1]` when they are all zero
+    pub fn for_values<'a>(x: impl IntoIterator<Item = &'a f32>) -> Self {
+        let max_abs = max_abs(x);
+        Self::for_range(if max_abs > 0. { max_abs } else { 1. })
+    }
+
+    /// Same as `for_range`, but for int4 values
+    pub fn int4_for_range(max_abs: f32) -> Self {
+        Self::int4(max_abs / Element::Int4.max() as f32)
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    pub fn element(&self) -> Element {
+        self.element
+    }
+
+    pub fn quantize(&self, x: f32) -> ChipT {
+        let (min, max) = (self.element.min() as f32, self.element.max() as f32);
+        // `as` maps NaN to 0
+        (x / self.scale).round().clamp(min, max) as ChipT
+    }
+
+    pub fn dequantize(&self, x: ChipT) -> f32 {
+        x as f32 * self.scale
+    }
+}
+
+impl Default for Quantizer {
+    /// Same fixed point format, which is used for `OPAC` operands: 7 fractional bits
+    fn default() -> Self {
+        Element::Int8.quantizer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn element_ranges() {
+        assert_eq!((Element::Int8.min(), Element::Int8.max()), (ChipT::MIN, ChipT::MAX));
+        assert_eq!((Element::Int4.min(), Element::Int4.max()), (-8, 7));
+        assert_eq!(Quantizer::default(), Quantizer::new(1. / 128.));
+        assert_eq!(Element::Int4.quantizer().scale(), 1. / 8.);
+    }
+
+    #[test]
+    fn int4_saturates() {
+        let q = Quantizer::int4_for_range(1.);
+        assert_eq!(q.quantize(1.), 7);
+        assert_eq!(q.quantize(5.), 7);
+        assert_eq!(q.quantize(-5.), -8);
+        assert_eq!(q.quantize(f32::NAN), 0);
+        assert_eq!(Quantizer::new(1.).quantize(1000.), ChipT::MAX);
+    }
+}
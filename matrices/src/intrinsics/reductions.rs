@@ -0,0 +1,307 @@
+//! Reductions and scans over `Array1D` lanes.
+//!
+//! Overflow policy of every operation is stated in its doc:
+//! - sums and dot products are exact, they are accumulated in `AccT`,
+//!   which can't overflow for `DIMENSION` lanes of `ChipT`
+//! - min/max based operations can't overflow
+//! - scans return `ChipT` lanes, so caller chooses `Overflow` policy
+//!
+//! `_masked` variants reduce or scan only active lanes of `LaneMask`. Reductions ignore its
+//! policy for inactive lanes, scans write inactive lanes by it and leave their values out.
+//!
+//! Results pass through fault hooks: scans as result lanes, reductions as their `AccT`
+//! register, so narrow values keep its low bits and corrupted indices wrap to the lanes.
+
+use std::cmp::{max, min};
+use super::counters::{count_vector, VectorKind};
+use super::fault::{self, reduction_result};
+use super::trace::{record, record_vector, TraceOp};
+use super::intrinsics::{mask_result, AccT, Array1D, ChipT, LaneMask};
+
+/// What to do when result doesn't fit into `ChipT`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Overflow {
+    /// Keep lower bits, two's complement
+    Wrap,
+    /// Clamp to `[ChipT::MIN, ChipT::MAX]`
+    Saturate,
+}
+
+impl Overflow {
+    fn add(&self, a: ChipT, b: ChipT) -> ChipT {
+        match self {
+            Overflow::Wrap => a.wrapping_add(b),
+            Overflow::Saturate => a.saturating_add(b),
+        }
+    }
+}
+
+/// Counts and traces reduction of `sources`. Its scalar result goes to host,
+/// so the node has no users
+fn issue<const N: usize>(sources: [&Array1D; N]) {
+    count_vector(VectorKind::Reduction);
+    record(TraceOp::Vector, &sources.map(|x| x.node));
+}
+
+/// Value reduction of `ChipT` lanes, through `AccT` register
+fn narrow(x: ChipT) -> ChipT {
+    reduction_result(x as AccT) as ChipT
+}
+
+/// Index reduction of `len` lanes, through `AccT` register
+fn index(i: usize, len: usize) -> usize {
+    reduction_result(i as AccT).rem_euclid(len as AccT) as usize
+}
+
+/// Sum of all lanes. Exact.
+pub fn v_sum(a: &Array1D) -> AccT {
+    issue([a]);
+    reduction_result(a.as_slice().iter().map(|x| *x as AccT).sum())
+}
+
+/// Maximum lane value. Panics on empty vector.
+pub fn v_reduce_max(a: &Array1D) -> ChipT {
+    issue([a]);
+    narrow(*a.as_slice().iter().max().expect("Reduction of empty vector"))
+}
+
+/// Minimum lane value. Panics on empty vector.
+pub fn v_reduce_min(a: &Array1D) -> ChipT {
+    issue([a]);
+    narrow(*a.as_slice().iter().min().expect("Reduction of empty vector"))
+}
+
+/// Index of first maximum lane. Panics on empty vector.
+pub fn v_argmax(a: &Array1D) -> usize {
+    issue([a]);
+    assert!(!a.is_empty(), "Reduction of empty vector");
+    let mut res = 0;
+    for i in 1..a.len() {
+        if a[i] > a[res] {
+            res = i;
+        }
+    }
+    index(res, a.len())
+}
+
+/// Index of first minimum lane. Panics on empty vector.
+pub fn v_argmin(a: &Array1D) -> usize {
+    issue([a]);
+    assert!(!a.is_empty(), "Reduction of empty vector");
+    let mut res = 0;
+    for i in 1..a.len() {
+        if a[i] < a[res] {
+            res = i;
+        }
+    }
+    index(res, a.len())
+}
+
+/// Sum of lane-wise products. Exact.
+pub fn v_dot(a: &Array1D, b: &Array1D) -> AccT {
+    issue([a, b]);
+    assert_eq!(a.len(), b.len(), "Vector operands have different length");
+    reduction_result(a.as_slice().iter().zip(b.as_slice()).map(|(x, y)| *x as AccT * *y as AccT).sum())
+}
+
+/// Active lanes of `a`, with their indices
+fn active<'a>(a: &'a Array1D, mask: &'a LaneMask) -> impl Iterator<Item = (usize, ChipT)> + 'a {
+    mask.lanes(a.len());
+    a.as_slice().iter().enumerate().filter(|(i, _)| mask.is_active(*i)).map(|(i, x)| (i, *x))
+}
+
+/// Sum of active lanes. Exact.
+pub fn v_sum_masked(a: &Array1D, mask: &LaneMask) -> AccT {
+    issue([a, mask.lanes(a.len())]);
+    reduction_result(active(a, mask).map(|(_, x)| x as AccT).sum())
+}
+
+/// Maximum of active lanes, `ChipT::MIN` when none is active
+pub fn v_reduce_max_masked(a: &Array1D, mask: &LaneMask) -> ChipT {
+    issue([a, mask.lanes(a.len())]);
+    narrow(active(a, mask).map(|(_, x)| x).max().unwrap_or(ChipT::MIN))
+}
+
+/// Minimum of active lanes, `ChipT::MAX` when none is active
+pub fn v_reduce_min_masked(a: &Array1D, mask: &LaneMask) -> ChipT {
+    issue([a, mask.lanes(a.len())]);
+    narrow(active(a, mask).map(|(_, x)| x).min().unwrap_or(ChipT::MAX))
+}
+
+/// Index of first maximum active lane, `None` when none is active
+pub fn v_argmax_masked(a: &Array1D, mask: &LaneMask) -> Option<usize> {
+    issue([a, mask.lanes(a.len())]);
+    let res = active(a, mask).reduce(|best, x| if x.1 > best.1 { x } else { best });
+    res.map(|(i, _)| index(i, a.len()))
+}
+
+/// Index of first minimum active lane, `None` when none is active
+pub fn v_argmin_masked(a: &Array1D, mask: &LaneMask) -> Option<usize> {
+    issue([a, mask.lanes(a.len())]);
+    let res = active(a, mask).reduce(|best, x| if x.1 < best.1 { x } else { best });
+    res.map(|(i, _)| index(i, a.len()))
+}
+
+/// Sum of lane-wise products over active lanes. Exact.
+pub fn v_dot_masked(a: &Array1D, b: &Array1D, mask: &LaneMask) -> AccT {
+    issue([a, b, mask.lanes(a.len())]);
+    assert_eq!(a.len(), b.len(), "Vector operands have different length");
+    reduction_result(active(a, mask).map(|(i, x)| x as AccT * b[i] as AccT).sum())
+}
+
+/// Runs scan over active lanes, `step` takes accumulator and lane value.
+/// Exclusive scan writes accumulator before the step, inclusive one after it.
+fn scan(a: &Array1D, mask: Option<&LaneMask>, init: ChipT, exclusive: bool, step: impl Fn(ChipT, ChipT) -> ChipT) -> Array1D {
+    count_vector(VectorKind::Scan);
+    let mut res = Array1D::zeros(a.len());
+    let mut acc = init;
+    for i in 0..a.len() {
+        if mask.is_some_and(|m| !m.is_active(i)) {
+            continue;
+        }
+        let next = step(acc, a[i]);
+        res[i] = if exclusive { acc } else { next };
+        acc = next;
+    }
+    record_vector(TraceOp::Vector, &[a], &mut res);
+    fault::vector_result(&mut res);
+    match mask {
+        Some(mask) => mask_result(res, mask),
+        None => res,
+    }
+}
+
+/// Inclusive prefix sum: `res[i] = a[0] + ... + a[i]`.
+/// Every partial sum is computed with `overflow` policy,
+/// so saturation is sticky in the same way as sequential hardware adder.
+pub fn v_prefix_sum(a: &Array1D, overflow: Overflow) -> Array1D {
+    scan(a, None, 0, false, |acc, x| overflow.add(acc, x))
+}
+
+/// `v_prefix_sum` over active lanes
+pub fn v_prefix_sum_masked(a: &Array1D, overflow: Overflow, mask: &LaneMask) -> Array1D {
+    scan(a, Some(mask), 0, false, |acc, x| overflow.add(acc, x))
+}
+
+/// Exclusive prefix sum: `res[0] = 0`, `res[i] = a[0] + ... + a[i - 1]`.
+/// Overflow is handled as in `v_prefix_sum`.
+pub fn v_prefix_sum_exclusive(a: &Array1D, overflow: Overflow) -> Array1D {
+    scan(a, None, 0, true, |acc, x| overflow.add(acc, x))
+}
+
+/// `v_prefix_sum_exclusive` over active lanes
+pub fn v_prefix_sum_exclusive_masked(a: &Array1D, overflow: Overflow, mask: &LaneMask) -> Array1D {
+    scan(a, Some(mask), 0, true, |acc, x| overflow.add(acc, x))
+}
+
+/// Inclusive prefix maximum: `res[i] = max(a[0], ..., a[i])`. Can't overflow.
+pub fn v_prefix_max(a: &Array1D) -> Array1D {
+    scan(a, None, ChipT::MIN, false, max)
+}
+
+/// `v_prefix_max` over active lanes
+pub fn v_prefix_max_masked(a: &Array1D, mask: &LaneMask) -> Array1D {
+    scan(a, Some(mask), ChipT::MIN, false, max)
+}
+
+/// Inclusive prefix minimum: `res[i] = min(a[0], ..., a[i])`. Can't overflow.
+pub fn v_prefix_min(a: &Array1D) -> Array1D {
+    scan(a, None, ChipT::MAX, false, min)
+}
+
+/// `v_prefix_min` over active lanes
+pub fn v_prefix_min_masked(a: &Array1D, mask: &LaneMask) -> Array1D {
+    scan(a, Some(mask), ChipT::MAX, false, min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intrinsics::config::DIMENSION;
+    use crate::intrinsics::intrinsics::Inactive;
+    use crate::intrinsics::trace::traced;
+
+    fn sample() -> Array1D {
+        let values: Vec<ChipT> = (0..DIMENSION).map(|i| ((i * 37 + 11) % 256) as u8 as ChipT).collect();
+        Array1D::from_slice(&values)
+    }
+
+    #[test]
+    fn reductions() {
+        let a = sample();
+        let values = a.as_slice();
+        assert_eq!(v_sum(&a), values.iter().map(|x| *x as i64).sum::<i64>() as AccT);
+        assert_eq!(v_reduce_max(&a), ChipT::MAX);
+        assert_eq!(v_reduce_min(&a), ChipT::MIN);
+        assert_eq!(a[v_argmax(&a)], ChipT::MAX);
+        assert_eq!(a[v_argmin(&a)], ChipT::MIN);
+        assert!(values[..v_argmax(&a)].iter().all(|x| *x < ChipT::MAX));
+    }
+
+    #[test]
+    fn exact_widened() {
+        let a = Array1D::from_slice(&[ChipT::MIN; DIMENSION]);
+        assert_eq!(v_sum(&a), ChipT::MIN as AccT * DIMENSION as AccT);
+        assert_eq!(v_dot(&a, &a), 128 * 128 * DIMENSION as AccT);
+    }
+
+    #[test]
+    fn ties_pick_first() {
+        let a = Array1D::from_slice(&[1, 5, -3, 5, -3]);
+        assert_eq!(v_argmax(&a), 1);
+        assert_eq!(v_argmin(&a), 2);
+    }
+
+    #[test]
+    fn masked() {
+        let a = Array1D::from_slice(&[1, 9, -3, 9, -8]);
+        let b = Array1D::from_slice(&[2, 2, 2, 2, 2]);
+        let m = Array1D::from_slice(&[-1, 0, -1, -1, 0]);
+        let m = LaneMask::new(&m, Inactive::Zero);
+        assert_eq!(v_sum_masked(&a, &m), 7);
+        assert_eq!(v_dot_masked(&a, &b, &m), 14);
+        assert_eq!(v_reduce_max_masked(&a, &m), 9);
+        assert_eq!(v_reduce_min_masked(&a, &m), -3);
+        assert_eq!(v_argmax_masked(&a, &m), Some(3));
+        assert_eq!(v_argmin_masked(&a, &m), Some(2));
+
+        let none = Array1D::zeros(5);
+        let none = LaneMask::new(&none, Inactive::Zero);
+        assert_eq!(v_reduce_max_masked(&a, &none), ChipT::MIN);
+        assert_eq!(v_argmin_masked(&a, &none), None);
+    }
+
+    #[test]
+    fn scans() {
+        let a = Array1D::from_slice(&[100, 50, -20, -128, 3]);
+        assert_eq!(v_prefix_sum(&a, Overflow::Wrap).as_slice(), &[100, -106, -126, 2, 5]);
+        assert_eq!(v_prefix_sum(&a, Overflow::Saturate).as_slice(), &[100, 127, 107, -21, -18]);
+        assert_eq!(v_prefix_sum_exclusive(&a, Overflow::Saturate).as_slice(), &[0, 100, 127, 107, -21]);
+        assert_eq!(v_prefix_max(&a).as_slice(), &[100, 100, 100, 100, 100]);
+        assert_eq!(v_prefix_min(&a).as_slice(), &[100, 50, -20, -128, -128]);
+    }
+
+    #[test]
+    fn masked_scans() {
+        let a = Array1D::from_slice(&[100, 50, -20, -128, 3]);
+        let old = Array1D::from_slice(&[7, 7, 7, 7, 7]);
+        let m = Array1D::from_slice(&[-1, 0, -1, 0, -1]);
+        let keep = LaneMask::new(&m, Inactive::Keep(&old));
+        let zero = LaneMask::new(&m, Inactive::Zero);
+        assert_eq!(v_prefix_sum_masked(&a, Overflow::Wrap, &keep).as_slice(), &[100, 7, 80, 7, 83]);
+        assert_eq!(v_prefix_sum_exclusive_masked(&a, Overflow::Wrap, &zero).as_slice(), &[0, 0, 100, 0, 80]);
+        assert_eq!(v_prefix_max_masked(&a, &zero).as_slice(), &[100, 0, 100, 0, 100]);
+        assert_eq!(v_prefix_min_masked(&a, &keep).as_slice(), &[100, 7, -20, 7, -20]);
+    }
+
+    #[test]
+    fn reductions_traced() {
+        let (_, trace) = traced(|| {
+            let a = Array1D::from_slice(&[1, 2, 3]);
+            v_dot(&a, &v_prefix_max(&a));
+            v_argmax(&a)
+        });
+        assert_eq!(trace.nodes(), &[TraceOp::Input, TraceOp::Vector, TraceOp::Vector, TraceOp::Vector]);
+        assert_eq!(trace.edges(), &[(0, 1), (0, 2), (1, 2), (0, 3)]);
+    }
+}
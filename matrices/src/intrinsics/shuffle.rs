@@ -0,0 +1,346 @@
+//! Data movement between lanes of `Array1D`.
+//!
+//! Every shuffle is charged to `Counters` with its cost from `CostTable::shuffles`,
+//! which can be reconfigured to match timing model of the chip.
+//! Indices which come from host are `usize`, indices from vector registers
+//! are lanes reinterpreted as unsigned, so they can address first 256 lanes.
+//! `_masked` variants apply `LaneMask` to result lanes.
+
+use super::config::DIMENSION;
+use super::counters::{cost_table, count_shuffle};
+use super::fault;
+use super::trace::{add_sources, record_vector, TraceOp};
+use super::intrinsics::{mask_result, Array1D, ChipT, LaneMask};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Shuffle {
+    Permute,
+    Rotate,
+    Reverse,
+    Interleave,
+    Deinterleave,
+    Broadcast,
+    Gather,
+    Scatter,
+}
+
+/// Cost annotation of each shuffle, in cycles of scalar core
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ShuffleCosts {
+    pub permute: u32,
+    pub rotate: u32,
+    pub reverse: u32,
+    pub interleave: u32,
+    pub deinterleave: u32,
+    pub broadcast: u32,
+    pub gather: u32,
+    pub scatter: u32,
+}
+
+impl Default for ShuffleCosts {
+    /// Fixed patterns are wired, arbitrary ones go through crossbar,
+    /// data dependent ones also wait for index register
+    fn default() -> Self {
+        ShuffleCosts {
+            permute: 2,
+            rotate: 1,
+            reverse: 1,
+            interleave: 1,
+            deinterleave: 1,
+            broadcast: 1,
+            gather: 4,
+            scatter: 4,
+        }
+    }
+}
+
+impl ShuffleCosts {
+    pub fn cost(&self, op: Shuffle) -> u32 {
+        match op {
+            Shuffle::Permute => self.permute,
+            Shuffle::Rotate => self.rotate,
+            Shuffle::Reverse => self.reverse,
+            Shuffle::Interleave => self.interleave,
+            Shuffle::Deinterleave => self.deinterleave,
+            Shuffle::Broadcast => self.broadcast,
+            Shuffle::Gather => self.gather,
+            Shuffle::Scatter => self.scatter,
+        }
+    }
+}
+
+fn charge(op: Shuffle) {
+    count_shuffle(cost_table().shuffles.cost(op));
+}
+
+/// Lane of index register
+fn register_index(x: ChipT) -> usize {
+    x as u8 as usize
+}
+
+/// Lane permutation: `res[i] = a[index[i]]`
+pub fn v_permute(a: &Array1D, index: &[usize]) -> Result<Array1D, &'static str> {
+    if index.len() > DIMENSION {
+        return Err("Permutation is longer than vector register");
+    }
+    if index.iter().any(|i| *i >= a.len()) {
+        return Err("Permutation index out of bounds");
+    }
+    charge(Shuffle::Permute);
+    let mut res = Array1D::zeros(index.len());
+    for (i, src) in index.iter().enumerate() {
+        res[i] = a[*src];
+    }
+    record_vector(TraceOp::Vector, &[a], &mut res);
+    fault::vector_result(&mut res);
+    Ok(res)
+}
+
+/// `v_permute` under `mask`
+pub fn v_permute_masked(a: &Array1D, index: &[usize], mask: &LaneMask) -> Result<Array1D, &'static str> {
+    v_permute(a, index).map(|res| mask_result(res, mask))
+}
+
+/// Rotation towards lower lanes: `res[i] = a[(i + shift) mod len]`.
+/// Negative `shift` rotates towards higher lanes.
+pub fn v_rotate(a: &Array1D, shift: isize) -> Array1D {
+    charge(Shuffle::Rotate);
+    let n = a.len();
+    let mut res = Array1D::zeros(n);
+    if n > 0 {
+        let shift = shift.rem_euclid(n as isize) as usize;
+        for i in 0..n {
+            res[i] = a[(i + shift) % n];
+        }
+    }
+    record_vector(TraceOp::Vector, &[a], &mut res);
+    fault::vector_result(&mut res);
+    res
+}
+
+/// `v_rotate` under `mask`
+pub fn v_rotate_masked(a: &Array1D, shift: isize, mask: &LaneMask) -> Array1D {
+    mask_result(v_rotate(a, shift), mask)
+}
+
+pub fn v_reverse(a: &Array1D) -> Array1D {
+    charge(Shuffle::Reverse);
+    let n = a.len();
+    let mut res = Array1D::zeros(n);
+    for i in 0..n {
+        res[i] = a[n - 1 - i];
+    }
+    record_vector(TraceOp::Vector, &[a], &mut res);
+    fault::vector_result(&mut res);
+    res
+}
+
+/// `v_reverse` under `mask`
+pub fn v_reverse_masked(a: &Array1D, mask: &LaneMask) -> Array1D {
+    mask_result(v_reverse(a), mask)
+}
+
+/// Interleaves lanes: `a0 b0 a1 b1 ...`.
+/// Result is twice longer than operands, so it's returned as lower and upper halves.
+pub fn v_interleave(a: &Array1D, b: &Array1D) -> (Array1D, Array1D) {
+    assert_eq!(a.len(), b.len(), "Vector operands have different length");
+    charge(Shuffle::Interleave);
+    let n = a.len();
+    let mut res = [Array1D::zeros(n), Array1D::zeros(n)];
+    for i in 0..n {
+        let (even, odd) = (2 * i, 2 * i + 1);
+        res[even / n][even % n] = a[i];
+        res[odd / n][odd % n] = b[i];
+    }
+    let [mut low, mut high] = res;
+    record_vector(TraceOp::Vector, &[a, b], &mut low);
+    record_vector(TraceOp::Vector, &[a, b], &mut high);
+    fault::vector_results(&mut [&mut low, &mut high]);
+    (low, high)
+}
+
+/// `v_interleave` with a mask for each half of the result
+pub fn v_interleave_masked(a: &Array1D, b: &Array1D, low: &LaneMask, high: &LaneMask) -> (Array1D, Array1D) {
+    let res = v_interleave(a, b);
+    (mask_result(res.0, low), mask_result(res.1, high))
+}
+
+/// Inverse of `v_interleave`: splits lanes of `low` followed by `high`
+/// into even and odd ones
+pub fn v_deinterleave(low: &Array1D, high: &Array1D) -> (Array1D, Array1D) {
+    assert_eq!(low.len(), high.len(), "Vector operands have different length");
+    charge(Shuffle::Deinterleave);
+    let n = low.len();
+    let lane = |i: usize| if i < n { low[i] } else { high[i - n] };
+    let mut even = Array1D::zeros(n);
+    let mut odd = Array1D::zeros(n);
+    for i in 0..n {
+        even[i] = lane(2 * i);
+        odd[i] = lane(2 * i + 1);
+    }
+    record_vector(TraceOp::Vector, &[low, high], &mut even);
+    record_vector(TraceOp::Vector, &[low, high], &mut odd);
+    fault::vector_results(&mut [&mut even, &mut odd]);
+    (even, odd)
+}
+
+/// `v_deinterleave` with masks for even and odd results
+pub fn v_deinterleave_masked(low: &Array1D, high: &Array1D, even: &LaneMask, odd: &LaneMask) -> (Array1D, Array1D) {
+    let res = v_deinterleave(low, high);
+    (mask_result(res.0, even), mask_result(res.1, odd))
+}
+
+/// Vector of `sz` copies of `value`
+pub fn v_broadcast(value: ChipT, sz: usize) -> Array1D {
+    charge(Shuffle::Broadcast);
+    let mut res = Array1D::zeros(sz);
+    for i in 0..sz {
+        res[i] = value;
+    }
+    record_vector(TraceOp::Vector, &[], &mut res);
+    fault::vector_result(&mut res);
+    res
+}
+
+/// `v_broadcast` under `mask`
+pub fn v_broadcast_masked(value: ChipT, sz: usize, mask: &LaneMask) -> Array1D {
+    mask_result(v_broadcast(value, sz), mask)
+}
+
+/// Vector with all lanes equal to `a[lane]`
+pub fn v_broadcast_lane(a: &Array1D, lane: usize) -> Result<Array1D, &'static str> {
+    if lane >= a.len() {
+        return Err("Broadcast lane out of bounds");
+    }
+    let res = v_broadcast(a[lane], a.len());
+    add_sources(&res, &[a]);
+    Ok(res)
+}
+
+/// `v_broadcast_lane` under `mask`
+pub fn v_broadcast_lane_masked(a: &Array1D, lane: usize, mask: &LaneMask) -> Result<Array1D, &'static str> {
+    v_broadcast_lane(a, lane).map(|res| mask_result(res, mask))
+}
+
+/// Data dependent permutation: `res[i] = table[index[i]]`
+pub fn v_gather(table: &Array1D, index: &Array1D) -> Result<Array1D, &'static str> {
+    if index.as_slice().iter().any(|i| register_index(*i) >= table.len()) {
+        return Err("Gather index out of bounds");
+    }
+    charge(Shuffle::Gather);
+    let mut res = Array1D::zeros(index.len());
+    for i in 0..index.len() {
+        res[i] = table[register_index(index[i])];
+    }
+    record_vector(TraceOp::Vector, &[table, index], &mut res);
+    fault::vector_result(&mut res);
+    Ok(res)
+}
+
+/// `v_gather` under `mask`
+pub fn v_gather_masked(table: &Array1D, index: &Array1D, mask: &LaneMask) -> Result<Array1D, &'static str> {
+    v_gather(table, index).map(|res| mask_result(res, mask))
+}
+
+/// Data dependent store: `dst[index[i]] = values[i]`, other lanes of `dst` are kept.
+/// When indices collide, the highest lane wins.
+pub fn v_scatter(dst: &Array1D, index: &Array1D, values: &Array1D) -> Result<Array1D, &'static str> {
+    assert_eq!(index.len(), values.len(), "Vector operands have different length");
+    if index.as_slice().iter().any(|i| register_index(*i) >= dst.len()) {
+        return Err("Scatter index out of bounds");
+    }
+    charge(Shuffle::Scatter);
+    let mut res = dst.clone();
+    for i in 0..index.len() {
+        res[register_index(index[i])] = values[i];
+    }
+    record_vector(TraceOp::Vector, &[dst, index, values], &mut res);
+    fault::vector_result(&mut res);
+    Ok(res)
+}
+
+/// `v_scatter` under `mask`, which applies to lanes of the result
+pub fn v_scatter_masked(dst: &Array1D, index: &Array1D, values: &Array1D, mask: &LaneMask) -> Result<Array1D, &'static str> {
+    v_scatter(dst, index, values).map(|res| mask_result(res, mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intrinsics::counters::{counted, CostTable, Emulator};
+    use crate::intrinsics::intrinsics::{tail_mask, Inactive};
+
+    #[test]
+    fn fixed_patterns() {
+        let a = Array1D::from_slice(&[0, 1, 2, 3, 4]);
+        assert_eq!(v_rotate(&a, 2).as_slice(), &[2, 3, 4, 0, 1]);
+        assert_eq!(v_rotate(&a, -1).as_slice(), &[4, 0, 1, 2, 3]);
+        assert_eq!(v_reverse(&a).as_slice(), &[4, 3, 2, 1, 0]);
+        assert_eq!(v_broadcast_lane(&a, 3).unwrap().as_slice(), &[3; 5]);
+        assert!(v_broadcast_lane(&a, 5).is_err());
+        assert_eq!(v_permute(&a, &[4, 4, 0]).unwrap().as_slice(), &[4, 4, 0]);
+        assert!(v_permute(&a, &[5]).is_err());
+    }
+
+    #[test]
+    fn interleave_roundtrip() {
+        let a = Array1D::from_slice(&[0, 2, 4, 6, 8]);
+        let b = Array1D::from_slice(&[1, 3, 5, 7, 9]);
+        let (low, high) = v_interleave(&a, &b);
+        assert_eq!(low.as_slice(), &[0, 1, 2, 3, 4]);
+        assert_eq!(high.as_slice(), &[5, 6, 7, 8, 9]);
+        let (even, odd) = v_deinterleave(&low, &high);
+        assert_eq!(even, a);
+        assert_eq!(odd, b);
+    }
+
+    #[test]
+    fn gather_scatter() {
+        let table = Array1D::from_slice(&[10, 20, 30]);
+        let index = Array1D::from_slice(&[2, 0, 2, 1]);
+        assert_eq!(v_gather(&table, &index).unwrap().as_slice(), &[30, 10, 30, 20]);
+        assert!(v_gather(&table, &Array1D::from_slice(&[3])).is_err());
+        // Negative lanes are large unsigned indices
+        assert!(v_gather(&table, &Array1D::from_slice(&[-1])).is_err());
+
+        let values = Array1D::from_slice(&[1, 2, 3, 4]);
+        assert_eq!(v_scatter(&table, &index, &values).unwrap().as_slice(), &[2, 4, 3]);
+        assert!(v_scatter(&table, &Array1D::from_slice(&[7, 0, 0, 0]), &values).is_err());
+    }
+
+    #[test]
+    fn masked() {
+        let a = Array1D::from_slice(&[0, 1, 2, 3, 4]);
+        let dst = Array1D::from_slice(&[9, 9, 9, 9, 9]);
+        let m = tail_mask(3, 5);
+        let keep = LaneMask::new(&m, Inactive::Keep(&dst));
+        let zero = LaneMask::new(&m, Inactive::Zero);
+        assert_eq!(v_reverse_masked(&a, &keep).as_slice(), &[4, 3, 2, 9, 9]);
+        assert_eq!(v_rotate_masked(&a, 1, &zero).as_slice(), &[1, 2, 3, 0, 0]);
+        assert_eq!(v_broadcast_masked(5, 5, &zero).as_slice(), &[5, 5, 5, 0, 0]);
+        let index = Array1D::from_slice(&[4, 4, 0, 1, 1]);
+        assert_eq!(v_gather_masked(&a, &index, &keep).unwrap().as_slice(), &[4, 4, 0, 9, 9]);
+        // Mask applies to the written vector, not to the scattered values
+        let values = Array1D::from_slice(&[7, 7, 7, 7, 7]);
+        let scattered = v_scatter_masked(&dst, &Array1D::from_slice(&[3, 3, 3, 3, 3]), &values, &zero).unwrap();
+        assert_eq!(scattered.as_slice(), &[9, 9, 9, 0, 0]);
+    }
+
+    #[test]
+    fn configurable_cost() {
+        let a = Array1D::from_slice(&[1, 2, 3]);
+        let (_, counters) = counted(|| {
+            v_reverse(&a);
+            v_gather(&a, &a).unwrap_err();
+            v_gather(&a, &Array1D::from_slice(&[0])).unwrap();
+        });
+        assert_eq!(counters.shuffle, 2);
+        assert_eq!(counters.shuffle_cost, (ShuffleCosts::default().reverse + ShuffleCosts::default().gather) as u64);
+
+        let shuffles = ShuffleCosts { reverse: 10, ..ShuffleCosts::default() };
+        let mut emulator = Emulator::new(CostTable { shuffles, ..CostTable::default() });
+        let (_, summary) = emulator.run(|| v_reverse(&a));
+        assert_eq!(summary.counters.shuffle_cost, 10);
+        assert_eq!(summary.cost, emulator.table().shuffle * 10);
+    }
+}
@@ -0,0 +1,467 @@
+//! Sparse operands of `mat_mul`.
+//!
+//! `OPAC` pass of common column `k` adds outer product of `k`-th columns of both tiles,
+//! so it can be skipped when either column is all zeros, and the whole tile product
+//! can be skipped when no column survives. Formats only differ in how cheaply they
+//! tell which columns of a tile are non-zero.
+//!
+//! Structured 2:4 sparsity is along the common dimension of each row. `OPAC` shares
+//! a column of the other operand between all rows, so different per-row patterns
+//! can't be compacted, and passes are only saved where whole tile columns are zero.
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use ndarray::{s, Array2, ArrayView2};
+use crate::intrinsics::config::DIMENSION;
+use crate::intrinsics::counters::counted;
+use crate::intrinsics::intrinsics::{opac, Matrix};
+
+/// Operand of `mat_mul_sparse`, in `mat_mul` layout: common dimension along columns
+pub trait SparseOperand {
+    fn shape(&self) -> (usize, usize);
+
+    /// Dense copy of a tile
+    fn tile(&self, rows: Range<usize>, cols: Range<usize>) -> Array2<f32>;
+
+    /// Sorted columns of the tile which have non-zero values, relative to `cols.start`
+    fn active_columns(&self, rows: Range<usize>, cols: Range<usize>) -> Vec<usize>;
+}
+
+impl SparseOperand for ArrayView2<'_, f32> {
+    fn shape(&self) -> (usize, usize) {
+        self.dim()
+    }
+
+    fn tile(&self, rows: Range<usize>, cols: Range<usize>) -> Array2<f32> {
+        self.slice(s![rows, cols]).to_owned()
+    }
+
+    fn active_columns(&self, rows: Range<usize>, cols: Range<usize>) -> Vec<usize> {
+        let tile = self.slice(s![rows, cols]);
+        (0..tile.ncols()).filter(|c| tile.column(*c).iter().any(|x| *x != 0.)).collect()
+    }
+}
+
+/// Compressed sparse rows
+#[derive(Clone, Debug)]
+pub struct Csr {
+    shape: (usize, usize),
+    indptr: Vec<usize>,
+    indices: Vec<usize>,
+    values: Vec<f32>,
+}
+
+impl Csr {
+    pub fn from_dense(x: ArrayView2<f32>) -> Self {
+        let mut res = Csr { shape: x.dim(), indptr: vec![0], indices: Vec::new(), values: Vec::new() };
+        for row in x.rows() {
+            for (c, v) in row.iter().enumerate() {
+                if *v != 0. {
+                    res.indices.push(c);
+                    res.values.push(*v);
+                }
+            }
+            res.indptr.push(res.indices.len());
+        }
+        res
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Non-zeros of row `r` with columns in `cols`
+    fn row(&self, r: usize, cols: &Range<usize>) -> impl Iterator<Item = (usize, f32)> + '_ {
+        let range = self.indptr[r]..self.indptr[r + 1];
+        let (cols, indices, values) = (cols.clone(), &self.indices[range.clone()], &self.values[range]);
+        indices.iter().zip(values).filter(move |(c, _)| cols.contains(c)).map(|(c, v)| (*c, *v))
+    }
+}
+
+impl SparseOperand for Csr {
+    fn shape(&self) -> (usize, usize) {
+        self.shape
+    }
+
+    fn tile(&self, rows: Range<usize>, cols: Range<usize>) -> Array2<f32> {
+        let mut res = Array2::zeros((rows.len(), cols.len()));
+        for r in rows.clone() {
+            for (c, v) in self.row(r, &cols) {
+                res[[r - rows.start, c - cols.start]] = v;
+            }
+        }
+        res
+    }
+
+    fn active_columns(&self, rows: Range<usize>, cols: Range<usize>) -> Vec<usize> {
+        let mut active = vec![false; cols.len()];
+        for r in rows {
+            for (c, _) in self.row(r, &cols) {
+                active[c - cols.start] = true;
+            }
+        }
+        (0..cols.len()).filter(|c| active[*c]).collect()
+    }
+}
+
+/// Compressed sparse columns
+#[derive(Clone, Debug)]
+pub struct Csc {
+    shape: (usize, usize),
+    indptr: Vec<usize>,
+    indices: Vec<usize>,
+    values: Vec<f32>,
+}
+
+impl Csc {
+    pub fn from_dense(x: ArrayView2<f32>) -> Self {
+        let mut res = Csc { shape: x.dim(), indptr: vec![0], indices: Vec::new(), values: Vec::new() };
+        for column in x.columns() {
+            for (r, v) in column.iter().enumerate() {
+                if *v != 0. {
+                    res.indices.push(r);
+                    res.values.push(*v);
+                }
+            }
+            res.indptr.push(res.indices.len());
+        }
+        res
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Positions in `indices` of column `c` with rows in `rows`, rows are sorted
+    fn column(&self, c: usize, rows: &Range<usize>) -> Range<usize> {
+        let column = &self.indices[self.indptr[c]..self.indptr[c + 1]];
+        let start = column.partition_point(|r| *r < rows.start);
+        let end = column.partition_point(|r| *r < rows.end);
+        self.indptr[c] + start..self.indptr[c] + end
+    }
+}
+
+impl SparseOperand for Csc {
+    fn shape(&self) -> (usize, usize) {
+        self.shape
+    }
+
+    fn tile(&self, rows: Range<usize>, cols: Range<usize>) -> Array2<f32> {
+        let mut res = Array2::zeros((rows.len(), cols.len()));
+        for c in cols.clone() {
+            for i in self.column(c, &rows) {
+                res[[self.indices[i] - rows.start, c - cols.start]] = self.values[i];
+            }
+        }
+        res
+    }
+
+    fn active_columns(&self, rows: Range<usize>, cols: Range<usize>) -> Vec<usize> {
+        cols.clone().filter(|c| !self.column(*c, &rows).is_empty()).map(|c| c - cols.start).collect()
+    }
+}
+
+/// Dense blocks of fixed size, all-zero blocks aren't stored
+#[derive(Clone, Debug)]
+pub struct BlockSparse {
+    shape: (usize, usize),
+    block: (usize, usize),
+    blocks: BTreeMap<(usize, usize), Array2<f32>>,
+}
+
+impl BlockSparse {
+    pub fn from_dense(x: ArrayView2<f32>, block: (usize, usize)) -> Self {
+        assert!(block.0 > 0 && block.1 > 0);
+        let mut blocks = BTreeMap::new();
+        for i in (0..x.nrows()).step_by(block.0) {
+            for j in (0..x.ncols()).step_by(block.1) {
+                let b = x.slice(s![i..min(i + block.0, x.nrows()), j..min(j + block.1, x.ncols())]);
+                if b.iter().any(|v| *v != 0.) {
+                    blocks.insert((i / block.0, j / block.1), b.to_owned());
+                }
+            }
+        }
+        BlockSparse { shape: x.dim(), block, blocks }
+    }
+
+    pub fn stored_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Stored blocks, which intersect the tile
+    fn blocks_in(&self, rows: &Range<usize>, cols: &Range<usize>) -> impl Iterator<Item = (&(usize, usize), &Array2<f32>)> {
+        let (rows, cols, block) = (rows.clone(), cols.clone(), self.block);
+        self.blocks.iter().filter(move |((bi, bj), _)| {
+            bi * block.0 < rows.end && (bi + 1) * block.0 > rows.start
+                && bj * block.1 < cols.end && (bj + 1) * block.1 > cols.start
+        })
+    }
+}
+
+impl SparseOperand for BlockSparse {
+    fn shape(&self) -> (usize, usize) {
+        self.shape
+    }
+
+    fn tile(&self, rows: Range<usize>, cols: Range<usize>) -> Array2<f32> {
+        let mut res = Array2::zeros((rows.len(), cols.len()));
+        for ((bi, bj), b) in self.blocks_in(&rows, &cols) {
+            for ((i, j), v) in b.indexed_iter() {
+                let (r, c) = (bi * self.block.0 + i, bj * self.block.1 + j);
+                if rows.contains(&r) && cols.contains(&c) {
+                    res[[r - rows.start, c - cols.start]] = *v;
+                }
+            }
+        }
+        res
+    }
+
+    fn active_columns(&self, rows: Range<usize>, cols: Range<usize>) -> Vec<usize> {
+        let mut active = vec![false; cols.len()];
+        for ((_, bj), b) in self.blocks_in(&rows, &cols) {
+            for j in 0..b.ncols() {
+                let c = bj * self.block.1 + j;
+                if cols.contains(&c) && b.column(j).iter().any(|v| *v != 0.) {
+                    active[c - cols.start] = true;
+                }
+            }
+        }
+        (0..cols.len()).filter(|c| active[*c]).collect()
+    }
+}
+
+/// Group of structured sparsity: at most 2 non-zeros out of 4 consecutive columns
+const GROUP: usize = 4;
+const KEEP: usize = 2;
+
+/// 2:4 structured sparse matrix: for every row and group of 4 columns
+/// 2 values and their positions in the group are stored
+#[derive(Clone, Debug)]
+pub struct Sparse24 {
+    shape: (usize, usize),
+    values: Array2<[f32; KEEP]>,
+    positions: Array2<[u8; KEEP]>,
+}
+
+impl Sparse24 {
+    /// Keeps 2 largest by magnitude values of each group
+    pub fn prune(x: ArrayView2<f32>) -> Self {
+        let groups = x.ncols().div_ceil(GROUP);
+        let mut values = Array2::from_elem((x.nrows(), groups), [0.; KEEP]);
+        let mut positions = Array2::from_elem((x.nrows(), groups), [0; KEEP]);
+        for r in 0..x.nrows() {
+            for g in 0..groups {
+                let start = g * GROUP;
+                let mut order: Vec<usize> = (0..min(GROUP, x.ncols() - start)).collect();
+                order.sort_by(|a, b| x[[r, start + b]].abs().total_cmp(&x[[r, start + a]].abs()));
+                for (slot, p) in order.iter().take(KEEP).enumerate() {
+                    values[[r, g]][slot] = x[[r, start + p]];
+                    positions[[r, g]][slot] = *p as u8;
+                }
+            }
+        }
+        Sparse24 { shape: x.dim(), values, positions }
+    }
+
+    /// Checks that `x` already has 2:4 structure
+    pub fn from_dense(x: ArrayView2<f32>) -> Result<Self, &'static str> {
+        for row in x.rows() {
+            for group in row.to_vec().chunks(GROUP) {
+                if group.iter().filter(|v| **v != 0.).count() > KEEP {
+                    return Err("Matrix doesn't have 2:4 structure");
+                }
+            }
+        }
+        Ok(Self::prune(x))
+    }
+
+    pub fn to_dense(&self) -> Array2<f32> {
+        self.tile(0..self.shape.0, 0..self.shape.1)
+    }
+
+    /// Stored `(column, value)` pairs of row `r`.
+    /// Last group can be shorter than `KEEP`, then its extra slots are empty and skipped.
+    fn row(&self, r: usize) -> impl Iterator<Item = (usize, f32)> + '_ {
+        (0..self.values.ncols()).flat_map(move |g| {
+            let slots = min(KEEP, self.shape.1 - g * GROUP);
+            (0..slots).map(move |slot| (g * GROUP + self.positions[[r, g]][slot] as usize, self.values[[r, g]][slot]))
+        })
+    }
+}
+
+impl SparseOperand for Sparse24 {
+    fn shape(&self) -> (usize, usize) {
+        self.shape
+    }
+
+    fn tile(&self, rows: Range<usize>, cols: Range<usize>) -> Array2<f32> {
+        let mut res = Array2::zeros((rows.len(), cols.len()));
+        for r in rows.clone() {
+            for (c, v) in self.row(r).filter(|(c, _)| cols.contains(c)) {
+                res[[r - rows.start, c - cols.start]] = v;
+            }
+        }
+        res
+    }
+
+    fn active_columns(&self, rows: Range<usize>, cols: Range<usize>) -> Vec<usize> {
+        let mut active = vec![false; cols.len()];
+        for r in rows {
+            for (c, v) in self.row(r) {
+                if v != 0. && cols.contains(&c) {
+                    active[c - cols.start] = true;
+                }
+            }
+        }
+        (0..cols.len()).filter(|c| active[*c]).collect()
+    }
+}
+
+/// `OPAC` usage of sparse product against the dense run
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SparseReport {
+    pub opac: u64,
+    pub opac_dense: u64,
+    /// Tile products skipped entirely
+    pub skipped_blocks: u64,
+}
+
+impl SparseReport {
+    pub fn opac_saved(&self) -> u64 {
+        self.opac_dense - self.opac
+    }
+}
+
+/// `a * b^T` as `mat_mul`, skips `OPAC` passes of zero columns and all-zero tiles
+pub fn mat_mul_sparse(a: &dyn SparseOperand, b: &dyn SparseOperand) -> (Array2<f32>, SparseReport) {
+    let ((m, k), (n, k_b)) = (a.shape(), b.shape());
+    assert_eq!(k, k_b);
+    let mut report = SparseReport::default();
+    let (res, counters) = counted(|| {
+        let mut res = Array2::zeros((m, n));
+        for i in (0..m).step_by(DIMENSION) {
+            for j in (0..n).step_by(DIMENSION) {
+                let rows_a = i..min(i + DIMENSION, m);
+                let rows_b = j..min(j + DIMENSION, n);
+                let mut res_mat = Matrix::zeros(rows_a.len(), rows_b.len());
+                for kk in (0..k).step_by(DIMENSION) {
+                    let cols = kk..min(kk + DIMENSION, k);
+                    report.opac_dense += cols.len() as u64;
+                    let active_b = b.active_columns(rows_b.clone(), cols.clone());
+                    let active: Vec<usize> = a.active_columns(rows_a.clone(), cols.clone())
+                        .into_iter()
+                        .filter(|c| active_b.binary_search(c).is_ok())
+                        .collect();
+                    if active.is_empty() {
+                        report.skipped_blocks += 1;
+                        continue;
+                    }
+                    let tile_a = a.tile(rows_a.clone(), cols.clone());
+                    let tile_b = b.tile(rows_b.clone(), cols.clone());
+                    for c in active {
+                        opac(&mut res_mat, tile_a.column(c).try_into().unwrap(), tile_b.column(c).try_into().unwrap());
+                    }
+                }
+                res_mat.add_to(&mut res.slice_mut(s![rows_a, rows_b]));
+            }
+        }
+        res
+    });
+    report.opac = counters.opac;
+    (res, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intrinsics::wrappers::mat_mul;
+
+    /// Multiples of 1/128, so products are exact
+    fn sample(rows: usize, cols: usize, seed: usize, density: usize) -> Array2<f32> {
+        Array2::from_shape_fn((rows, cols), |(i, j)| {
+            let h = (i * 7919 + j * 104729 + seed * 31) % 100;
+            if h < density { ((h % 15) as f32 - 7.) / 128. } else { 0. }
+        })
+    }
+
+    fn check(a: &dyn SparseOperand, b: &dyn SparseOperand, dense_a: &Array2<f32>, dense_b: &Array2<f32>) -> SparseReport {
+        let (res, report) = mat_mul_sparse(a, b);
+        assert_eq!(res, mat_mul(dense_a.view(), dense_b.view()));
+        assert_eq!(report.opac_dense, dense_a.ncols() as u64);
+        report
+    }
+
+    #[test]
+    fn all_formats() {
+        let mut a = sample(20, 30, 1, 40);
+        // Zero columns are the ones OPAC can skip
+        for c in [0, 3, 4, 17] {
+            a.column_mut(c).fill(0.);
+        }
+        let b = sample(25, 30, 2, 60);
+        let expected_saved = 30 - a.view().active_columns(0..20, 0..30).len() as u64;
+
+        let report = check(&a.view(), &b.view(), &a, &b);
+        assert!(report.opac_saved() >= expected_saved);
+        let report_csr = check(&Csr::from_dense(a.view()), &Csc::from_dense(b.view()), &a, &b);
+        assert_eq!(report_csr, report);
+        let report_block = check(&BlockSparse::from_dense(a.view(), (4, 4)), &b.view(), &a, &b);
+        assert_eq!(report_block, report);
+    }
+
+    #[test]
+    fn formats_roundtrip() {
+        let a = sample(9, 11, 3, 30);
+        assert_eq!(Csr::from_dense(a.view()).tile(2..7, 1..10), a.slice(s![2..7, 1..10]));
+        assert_eq!(Csc::from_dense(a.view()).tile(2..7, 1..10), a.slice(s![2..7, 1..10]));
+        assert_eq!(BlockSparse::from_dense(a.view(), (2, 3)).tile(2..7, 1..10), a.slice(s![2..7, 1..10]));
+        assert_eq!(Csr::from_dense(a.view()).nnz(), a.iter().filter(|v| **v != 0.).count());
+    }
+
+    #[test]
+    fn block_sparse_skips_tiles() {
+        let mut a = Array2::zeros((8, 16));
+        a.slice_mut(s![.., 4..8]).assign(&sample(8, 4, 1, 100));
+        let b = sample(8, 16, 2, 100);
+        let sparse = BlockSparse::from_dense(a.view(), (8, 4));
+        assert_eq!(sparse.stored_blocks(), 1);
+        let report = check(&sparse, &b.view(), &a, &b);
+        assert_eq!(report.opac, 4);
+    }
+
+    #[test]
+    fn structured_2_4() {
+        let dense = sample(6, 13, 4, 100);
+        let pruned = Sparse24::prune(dense.view());
+        let pruned_dense = pruned.to_dense();
+        for (row, expected) in pruned_dense.rows().into_iter().zip(dense.rows()) {
+            for (group, expected) in row.to_vec().chunks(GROUP).zip(expected.to_vec().chunks(GROUP)) {
+                // Values are kept in place, dropped ones are not larger than kept
+                let kept: Vec<usize> = (0..group.len()).filter(|c| group[*c] != 0.).collect();
+                assert_eq!(kept.len(), min(KEEP, expected.iter().filter(|v| **v != 0.).count()));
+                for c in 0..group.len() {
+                    if kept.contains(&c) {
+                        assert_eq!(group[c], expected[c]);
+                    } else {
+                        assert!(kept.iter().all(|k| expected[*k].abs() >= expected[c].abs()));
+                    }
+                }
+            }
+        }
+        // Last group of 5 columns has a single column
+        let short = Array2::from_shape_fn((2, 5), |(i, j)| if j % 2 == 0 { (i + j + 1) as f32 / 16. } else { 0. });
+        assert_eq!(Sparse24::from_dense(short.view()).unwrap().to_dense(), short);
+        assert!(Sparse24::from_dense(dense.view()).is_err());
+        assert_eq!(Sparse24::from_dense(pruned_dense.view()).unwrap().to_dense(), pruned_dense);
+
+        // Same pattern in all rows makes half of the columns zero
+        let mut shared = sample(6, 16, 5, 100);
+        for c in (0..16).filter(|c| c % 4 >= 2) {
+            shared.column_mut(c).fill(0.);
+        }
+        let b = sample(7, 16, 6, 100);
+        let report = check(&Sparse24::from_dense(shared.view()).unwrap(), &b.view(), &shared, &b);
+        assert_eq!(report.opac_saved(), 8);
+    }
+}
@@ -0,0 +1,298 @@
+//! Strassen-Winograd recursion above the blocked `mat_mul`.
+//!
+//! Each level replaces 8 block products by 7 at the price of 15 block additions.
+//! Additions of operands run on vector units, so operands are kept as
+//! block floating point `ChipT` matrices: addition aligns exponents, and when
+//! the sum overflows, it gains one bit of range and loses the lowest one.
+//! Products are read out in accumulator precision and combined on host, as `mat_mul`
+//! combines its partial products, so only operand sums lose bits. That's why accuracy of this mode
+//! degrades with depth, `strassen_report` measures it together with `OPAC` savings.
+//! Recursion stops where halving doesn't reduce `OPAC` passes, so small products run `mat_mul` directly.
+
+use std::cmp::max;
+use ndarray::{s, Array1, Array2, ArrayView1, ArrayView2, Axis};
+use crate::intrinsics::config::DIMENSION;
+use crate::intrinsics::counters::counted;
+use crate::intrinsics::intrinsics::{ChipT, MASK_FALSE};
+use crate::intrinsics::quant::{max_abs, OPERAND_MAX};
+use crate::intrinsics::wrappers::{
+    mat_mul, vec_add, vec_add_sat, vec_and, vec_cmp_eq, vec_reduce_max, vec_shr, vec_sub, vec_sub_sat,
+};
+
+/// Block floating point matrix: `value = q / 128 * 2^exp`
+#[derive(Clone)]
+struct BlockQ {
+    q: Array2<ChipT>,
+    /// `None` for all-zero matrix, which has no exponent to align to
+    exp: Option<i32>,
+}
+
+impl BlockQ {
+    fn from_f32(x: ArrayView2<f32>) -> Self {
+        let max_abs = max_abs(x);
+        if max_abs == 0. {
+            return BlockQ { q: Array2::zeros(x.raw_dim()), exp: None };
+        }
+        let exp = (max_abs / OPERAND_MAX).log2().ceil() as i32;
+        let scale = 128. / 2f32.powi(exp);
+        BlockQ { q: x.map(|v| (v * scale).round() as ChipT), exp: Some(exp) }
+    }
+
+    /// Operand of `mat_mul`, which is exactly representable
+    fn mantissa(&self) -> Array2<f32> {
+        self.q.map(|v| *v as f32 / 128.)
+    }
+
+    fn block(&self, rows: usize, cols: usize) -> BlockQ {
+        let (h, w) = (self.q.nrows() / 2, self.q.ncols() / 2);
+        BlockQ {
+            q: self.q.slice(s![rows * h..(rows + 1) * h, cols * w..(cols + 1) * w]).to_owned(),
+            exp: self.exp,
+        }
+    }
+
+    /// Pads with zeros to even dimensions
+    fn pad_even(&self) -> BlockQ {
+        let (h, w) = self.q.dim();
+        let mut q = Array2::zeros((h + h % 2, w + w % 2));
+        q.slice_mut(s![..h, ..w]).assign(&self.q);
+        BlockQ { q, exp: self.exp }
+    }
+}
+
+/// Arithmetic right shift, rounded to nearest, so alignment doesn't bias sums
+fn shr_round(x: ArrayView1<ChipT>, shift: ChipT) -> Array1<ChipT> {
+    let n = x.len();
+    if shift == 0 {
+        return x.to_owned();
+    }
+    let truncated = vec_shr(x, Array1::from_elem(n, shift).view());
+    let half = vec_shr(x, Array1::from_elem(n, shift - 1).view());
+    let round = vec_and(half.view(), Array1::from_elem(n, 1).view());
+    vec_add_sat(truncated.view(), round.view())
+}
+
+/// `x + y` or `x - y` on vector units, row by row, at exponent `exp`.
+/// Returns `None` if any lane saturates.
+fn add_at(x: &BlockQ, y: &BlockQ, subtract: bool, exp: i32) -> Option<BlockQ> {
+    // Mantissa of zero matrix is zero at any exponent
+    let shift = |e: Option<i32>| e.map_or(0, |e| (exp - e).min(ChipT::MAX as i32) as ChipT);
+    let mut q = Array2::zeros(x.q.raw_dim());
+    for (i, mut row) in q.axis_iter_mut(Axis(0)).enumerate() {
+        let n = row.len();
+        let a = shr_round(x.q.row(i), shift(x.exp));
+        let b = shr_round(y.q.row(i), shift(y.exp));
+        let (saturated, wrapped) = if subtract {
+            (vec_sub_sat(a.view(), b.view()), vec_sub(a.view(), b.view()))
+        } else {
+            (vec_add_sat(a.view(), b.view()), vec_add(a.view(), b.view()))
+        };
+        if n > 0 && vec_reduce_max(vec_cmp_eq(saturated.view(), wrapped.view()).view()) == MASK_FALSE {
+            return None;
+        }
+        row.assign(&saturated);
+    }
+    Some(BlockQ { q, exp: Some(exp) })
+}
+
+/// `x + y` or `x - y` on vector units.
+/// Operands are aligned to the larger exponent, while the sum overflows
+/// it is recomputed with one more bit of range. One bit is not always enough,
+/// as rounding of aligned operands can carry them up to the boundary again.
+fn add(x: &BlockQ, y: &BlockQ, subtract: bool) -> BlockQ {
+    assert_eq!(x.q.dim(), y.q.dim());
+    let Some(mut exp) = max(x.exp, y.exp) else {
+        return x.clone();
+    };
+    loop {
+        if let Some(res) = add_at(x, y, subtract, exp) {
+            return res;
+        }
+        exp += 1;
+    }
+}
+
+fn tiles(size: usize) -> u64 {
+    size.div_ceil(DIMENSION) as u64
+}
+
+/// `OPAC` passes of blocked `[m, k] * [n, k]^T` product
+fn passes(m: usize, n: usize, k: usize) -> u64 {
+    tiles(m) * tiles(n) * k as u64
+}
+
+/// Whether one level of recursion needs fewer `OPAC` passes than `mat_mul`
+fn splits(m: usize, n: usize, k: usize) -> bool {
+    7 * passes(m.div_ceil(2), n.div_ceil(2), k.div_ceil(2)) < passes(m, n, k)
+}
+
+/// `a * b^T` on the blocked path, products of zero matrices aren't issued
+fn leaf(a: &BlockQ, b: &BlockQ) -> Array2<f32> {
+    match (a.exp, b.exp) {
+        (Some(ea), Some(eb)) => mat_mul(a.mantissa().view(), b.mantissa().view()) * 2f32.powi(ea + eb),
+        _ => Array2::zeros((a.q.nrows(), b.q.nrows())),
+    }
+}
+
+/// `a * b^T`, operands are `[m, k]` and `[n, k]` as in `mat_mul`
+fn winograd(a: &BlockQ, b: &BlockQ, depth: usize) -> Array2<f32> {
+    let (m, n) = (a.q.nrows(), b.q.nrows());
+    if depth == 0 || !splits(m, n, a.q.ncols()) {
+        return leaf(a, b);
+    }
+    let (a, b) = (a.pad_even(), b.pad_even());
+    let (a11, a12, a21, a22) = (a.block(0, 0), a.block(0, 1), a.block(1, 0), a.block(1, 1));
+    // Blocks of right operand `B = b^T`, kept transposed: `B12 = b21^T`, `B21 = b12^T`
+    let (b11, b12, b21, b22) = (b.block(0, 0), b.block(1, 0), b.block(0, 1), b.block(1, 1));
+
+    let s1 = add(&a21, &a22, false);
+    let s2 = add(&s1, &a11, true);
+    let s3 = add(&a11, &a21, true);
+    let s4 = add(&a12, &s2, true);
+    let t1 = add(&b12, &b11, true);
+    let t2 = add(&b22, &t1, true);
+    let t3 = add(&b22, &b12, true);
+    let t4 = add(&t2, &b21, true);
+
+    let p1 = winograd(&a11, &b11, depth - 1);
+    let p2 = winograd(&a12, &b21, depth - 1);
+    let p3 = winograd(&s4, &b22, depth - 1);
+    let p4 = winograd(&a22, &t4, depth - 1);
+    let p5 = winograd(&s1, &t1, depth - 1);
+    let p6 = winograd(&s2, &t2, depth - 1);
+    let p7 = winograd(&s3, &t3, depth - 1);
+
+    let u2 = &p1 + &p6;
+    let u3 = &u2 + &p7;
+    let quadrants = [&p1 + &p2, &u2 + &p5 + &p3, &u3 - &p4, &u3 + &p5];
+
+    let (h, w) = (p1.nrows(), p1.ncols());
+    let mut res = Array2::zeros((2 * h, 2 * w));
+    for (u, (r, c)) in quadrants.iter().zip([(0, 0), (0, 1), (1, 0), (1, 1)]) {
+        res.slice_mut(s![r * h..(r + 1) * h, c * w..(c + 1) * w]).assign(u);
+    }
+    res.slice(s![..m, ..n]).to_owned()
+}
+
+/// `a * b^T` with up to `depth` levels of Strassen-Winograd recursion above `mat_mul`
+pub fn mat_mul_strassen<'a>(a: ArrayView2<'a, f32>, b: ArrayView2<'a, f32>, depth: usize) -> Array2<f32> {
+    assert_eq!(a.ncols(), b.ncols());
+    if depth == 0 || !splits(a.nrows(), b.nrows(), a.ncols()) {
+        return mat_mul(a, b);
+    }
+    winograd(&BlockQ::from_f32(a), &BlockQ::from_f32(b), depth)
+}
+
+/// Number of `DIMENSION` block products, which `mat_mul_strassen` issues for
+/// `[m, k] * [n, k]^T` product: `7` products of the halved sizes on every level it splits
+pub fn block_products(m: usize, n: usize, k: usize, depth: usize) -> u64 {
+    if depth == 0 || !splits(m, n, k) {
+        return tiles(m) * tiles(n) * tiles(k);
+    }
+    7 * block_products(m.div_ceil(2), n.div_ceil(2), k.div_ceil(2), depth - 1)
+}
+
+/// Comparison of Strassen mode with the standard blocked `mat_mul`
+#[derive(Clone, Debug)]
+pub struct StrassenReport {
+    pub depth: usize,
+    pub block_products: u64,
+    pub block_products_standard: u64,
+    pub opac: u64,
+    pub opac_standard: u64,
+    /// Maximum elementwise error against `f32` product on host
+    pub max_error: f32,
+    pub max_error_standard: f32,
+}
+
+/// Runs both modes and `f32` reference
+pub fn strassen_report<'a>(a: ArrayView2<'a, f32>, b: ArrayView2<'a, f32>, depth: usize) -> StrassenReport {
+    let (m, n, k) = (a.nrows(), b.nrows(), a.ncols());
+    let expected = a.dot(&b.t());
+    let max_error = |x: &Array2<f32>| (x - &expected).iter().fold(0f32, |acc, v| acc.max(v.abs()));
+    let (fast, fast_counters) = counted(|| mat_mul_strassen(a, b, depth));
+    let (standard, standard_counters) = counted(|| mat_mul(a, b));
+    StrassenReport {
+        depth,
+        block_products: block_products(m, n, k, depth),
+        block_products_standard: block_products(m, n, k, 0),
+        opac: fast_counters.opac,
+        opac_standard: standard_counters.opac,
+        max_error: max_error(&fast),
+        max_error_standard: max_error(&standard),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(rows: usize, cols: usize, seed: usize) -> Array2<f32> {
+        Array2::from_shape_fn((rows, cols), |(i, j)| {
+            let x = ((i * 7919 + j * 104729 + seed * 31) % 1000) as f32 / 1000.;
+            (2. * x - 1.) * 0.9
+        })
+    }
+
+    /// Operands are quantized at power of two steps, and every level of operand sums
+    /// may lose one more bit, so error in output steps doubles with depth
+    fn tolerance(a: &Array2<f32>, b: &Array2<f32>, depth: usize) -> f32 {
+        let step = |x: &Array2<f32>| 2f32.powi((max_abs(x) / OPERAND_MAX).log2().ceil() as i32) / 128.;
+        a.ncols() as f32 * (max_abs(a) * step(b) + max_abs(b) * step(a)) / 2. * 2f32.powi(depth as i32)
+    }
+
+    #[test]
+    fn matches_standard() {
+        // Single output tile is never split
+        let a = sample(13, 9, 1);
+        let b = sample(11, 9, 2);
+        let report = strassen_report(a.view(), b.view(), 2);
+        assert_eq!(report.max_error, report.max_error_standard);
+        assert_eq!(report.opac, report.opac_standard);
+        assert_eq!(report.block_products, 1);
+
+        // 2x2 output tiles split once, odd `k` is padded
+        let a = sample(2 * DIMENSION, 9, 1);
+        let b = sample(2 * DIMENSION, 9, 2);
+        let report = strassen_report(a.view(), b.view(), 2);
+        assert!(report.max_error <= tolerance(&a, &b, 1), "{:?}", report);
+        assert_eq!(report.block_products, 7);
+        assert_eq!(report.opac, 7 * 5);
+        assert!(report.opac < report.opac_standard);
+    }
+
+    #[test]
+    fn top_of_range() {
+        // Aligned operands round up to the boundary, so a single extra bit isn't enough
+        let x = BlockQ::from_f32(Array2::from_elem((4, 4), 127. / 128.).view());
+        let y = BlockQ::from_f32(Array2::from_elem((4, 4), 1. / 128.).view());
+        let sum = add(&add(&x, &x, false), &y, false);
+        let exp = sum.exp.unwrap();
+        assert!(sum.q.iter().all(|v| (*v as f32 / 128. * 2f32.powi(exp) - 255. / 128.).abs() <= 2f32.powi(exp) / 256.));
+    }
+
+    #[test]
+    fn zero_blocks() {
+        let zero = BlockQ::from_f32(Array2::zeros((3, 2)).view());
+        assert_eq!(zero.exp, None);
+        let x = BlockQ::from_f32(Array2::from_elem((3, 2), 0.5).view());
+        let diff = add(&zero, &x, true);
+        assert_eq!(diff.exp, x.exp);
+        assert!(diff.q.iter().zip(&x.q).all(|(d, v)| *d == -*v));
+        assert_eq!(add(&zero, &zero, false).exp, None);
+        let (res, counters) = counted(|| leaf(&zero, &x));
+        assert!(res.iter().all(|v| *v == 0.));
+        assert_eq!(counters.opac, 0);
+    }
+
+    #[test]
+    fn saves_block_products() {
+        let n = 4 * DIMENSION;
+        assert_eq!(block_products(n, n, n, 0), 64);
+        assert_eq!(block_products(n, n, n, 1), 56);
+        assert_eq!(block_products(n, n, n, 2), 49);
+        // Blocks of `DIMENSION` aren't split further
+        assert_eq!(block_products(n, n, n, 3), 49);
+        assert_eq!(block_products(DIMENSION, DIMENSION, DIMENSION, 2), 1);
+    }
+}
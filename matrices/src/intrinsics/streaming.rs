@@ -0,0 +1,213 @@
+//! Out-of-core `mat_mul` for matrices stored in files.
+//!
+//! Files hold row-major little-endian `f32` values without header. Tiles of the operands
+//! are read on demand into a bounded buffer, and each result tile is written back as soon
+//! as it is complete: the same tiling which `DIMENSION` forces on chip, one level up.
+
+use std::cmp::min;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use ndarray::{Array2, ArrayView2};
+use crate::intrinsics::config::DIMENSION;
+use crate::intrinsics::wrappers::mat_mul;
+
+const VALUE_BYTES: u64 = size_of::<f32>() as u64;
+
+/// Matrix stored in a file
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatrixFile {
+    pub path: PathBuf,
+    pub rows: usize,
+    pub cols: usize,
+}
+
+impl MatrixFile {
+    pub fn new(path: impl Into<PathBuf>, rows: usize, cols: usize) -> Self {
+        MatrixFile { path: path.into(), rows, cols }
+    }
+
+    /// Writes the whole matrix, for inputs which fit in memory
+    pub fn create(path: impl Into<PathBuf>, x: ArrayView2<f32>) -> io::Result<Self> {
+        let res = Self::new(path, x.nrows(), x.ncols());
+        let mut file = io::BufWriter::new(File::create(&res.path)?);
+        for v in x.iter() {
+            file.write_all(&v.to_le_bytes())?;
+        }
+        file.flush()?;
+        Ok(res)
+    }
+
+    pub fn load(&self) -> io::Result<Array2<f32>> {
+        let mut file = File::open(&self.path)?;
+        read_tile(&mut file, self.cols, 0..self.rows, 0..self.cols)
+    }
+
+    fn check_size(&self) -> io::Result<()> {
+        let expected = (self.rows * self.cols) as u64 * VALUE_BYTES;
+        if std::fs::metadata(&self.path)?.len() != expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Matrix file size doesn't match its shape"));
+        }
+        Ok(())
+    }
+}
+
+fn read_tile(file: &mut File, cols: usize, rows: Range<usize>, tile_cols: Range<usize>) -> io::Result<Array2<f32>> {
+    let mut res = Array2::zeros((rows.len(), tile_cols.len()));
+    let mut buf = vec![0u8; tile_cols.len() * VALUE_BYTES as usize];
+    for (i, r) in rows.enumerate() {
+        file.seek(SeekFrom::Start((r * cols + tile_cols.start) as u64 * VALUE_BYTES))?;
+        file.read_exact(&mut buf)?;
+        for (j, bytes) in buf.chunks_exact(VALUE_BYTES as usize).enumerate() {
+            res[[i, j]] = f32::from_le_bytes(bytes.try_into().unwrap());
+        }
+    }
+    Ok(res)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamConfig {
+    /// Tile side, no more than `DIMENSION`
+    pub tile: usize,
+    /// Capacity of operand tile buffer, at least one tile of each operand
+    pub buffer_tiles: usize,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        StreamConfig { tile: DIMENSION, buffer_tiles: 4 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoReport {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub tile_loads: u64,
+    /// Operand tiles found in the buffer
+    pub tile_hits: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operand {
+    A,
+    B,
+}
+
+/// Least recently used operand tiles, keyed by operand, row tile and column tile
+struct TileBuffer {
+    capacity: usize,
+    tiles: Vec<((Operand, usize, usize), Array2<f32>)>,
+}
+
+impl TileBuffer {
+    /// Makes tile `key` the most recently used one, loading it when it isn't in the buffer.
+    /// With capacity of 2 or more, the tile fetched before it stays in the buffer.
+    fn fetch(
+        &mut self,
+        key: (Operand, usize, usize),
+        load: impl FnOnce() -> io::Result<Array2<f32>>,
+        report: &mut IoReport,
+    ) -> io::Result<()> {
+        if let Some(pos) = self.tiles.iter().position(|(k, _)| *k == key) {
+            report.tile_hits += 1;
+            let tile = self.tiles.remove(pos);
+            self.tiles.push(tile);
+        } else {
+            let tile = load()?;
+            report.tile_loads += 1;
+            report.bytes_read += tile.len() as u64 * VALUE_BYTES;
+            if self.tiles.len() == self.capacity {
+                self.tiles.remove(0);
+            }
+            self.tiles.push((key, tile));
+        }
+        Ok(())
+    }
+
+    /// Tile which is in the buffer
+    fn tile(&self, key: (Operand, usize, usize)) -> &Array2<f32> {
+        &self.tiles.iter().find(|(k, _)| *k == key).expect("Tile isn't fetched").1
+    }
+}
+
+/// `a * b^T` as `mat_mul`, written to `out`. Memory holds at most
+/// `config.buffer_tiles` operand tiles and one result tile
+pub fn mat_mul_streaming(a: &MatrixFile, b: &MatrixFile, out: &Path, config: StreamConfig) -> io::Result<(MatrixFile, IoReport)> {
+    assert_eq!(a.cols, b.cols);
+    assert!(config.tile > 0 && config.tile <= DIMENSION);
+    assert!(config.buffer_tiles >= 2);
+    a.check_size()?;
+    b.check_size()?;
+
+    let (m, n, k, t) = (a.rows, b.rows, a.cols, config.tile);
+    let (mut file_a, mut file_b) = (File::open(&a.path)?, File::open(&b.path)?);
+    let mut res_file = OpenOptions::new().create(true).write(true).truncate(true).open(out)?;
+    res_file.set_len((m * n) as u64 * VALUE_BYTES)?;
+
+    let mut report = IoReport::default();
+    let mut buffer = TileBuffer { capacity: config.buffer_tiles, tiles: Vec::new() };
+    for i in (0..m).step_by(t) {
+        for j in (0..n).step_by(t) {
+            let (rows_a, rows_b) = (i..min(i + t, m), j..min(j + t, n));
+            let mut res = Array2::<f32>::zeros((rows_a.len(), rows_b.len()));
+            for kk in (0..k).step_by(t) {
+                let cols = kk..min(kk + t, k);
+                let (key_a, key_b) = ((Operand::A, i, kk), (Operand::B, j, kk));
+                buffer.fetch(key_a, || read_tile(&mut file_a, k, rows_a.clone(), cols.clone()), &mut report)?;
+                buffer.fetch(key_b, || read_tile(&mut file_b, k, rows_b.clone(), cols.clone()), &mut report)?;
+                res += &mat_mul(buffer.tile(key_a).view(), buffer.tile(key_b).view());
+            }
+            for (r, row) in rows_a.clone().zip(res.rows()) {
+                res_file.seek(SeekFrom::Start((r * n + j) as u64 * VALUE_BYTES))?;
+                let bytes: Vec<u8> = row.iter().flat_map(|v| v.to_le_bytes()).collect();
+                res_file.write_all(&bytes)?;
+                report.bytes_written += bytes.len() as u64;
+            }
+        }
+    }
+    res_file.flush()?;
+    Ok((MatrixFile::new(out, m, n), report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sample;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("streaming-{}-{}.bin", std::process::id(), name))
+    }
+
+    #[test]
+    fn streamed_matches_in_memory() {
+        let (a, b) = (sample(21, 30, 1, 1. / 16.), sample(17, 30, 2, 1. / 16.));
+        let fa = MatrixFile::create(temp("a"), a.view()).unwrap();
+        let fb = MatrixFile::create(temp("b"), b.view()).unwrap();
+        let config = StreamConfig { tile: 8, buffer_tiles: 2 };
+        let (out, report) = mat_mul_streaming(&fa, &fb, &temp("out"), config).unwrap();
+        let res = out.load().unwrap();
+        assert_eq!(res, mat_mul(a.view(), b.view()));
+        assert_eq!(report.bytes_written, 21 * 17 * VALUE_BYTES);
+
+        // Buffer of all tiles reads every operand value once
+        let config = StreamConfig { tile: 8, buffer_tiles: 3 * 4 + 3 * 4 };
+        let (_, full) = mat_mul_streaming(&fa, &fb, &temp("out"), config).unwrap();
+        assert_eq!(full.bytes_read, (21 + 17) * 30 * VALUE_BYTES);
+        assert!(report.bytes_read > full.bytes_read);
+        assert_eq!(full.tile_loads + full.tile_hits, report.tile_loads + report.tile_hits);
+
+        for name in ["a", "b", "out"] {
+            std::fs::remove_file(temp(name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_size() {
+        let fa = MatrixFile::create(temp("short"), sample(3, 4, 0, 1. / 16.).view()).unwrap();
+        let wrong = MatrixFile::new(temp("short"), 4, 4);
+        assert!(mat_mul_streaming(&wrong, &fa, &temp("short-out"), StreamConfig::default()).is_err());
+        std::fs::remove_file(temp("short")).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+//! Trace of issued instructions as a dependency graph.
+//!
+//! Registers remember the trace node which produced them, so every instruction
+//! records edges from producers of its operands. `OPAC` also depends on the previous
+//! pass over the same accumulator. Values loaded from host are input nodes.
+//! The trace exports to the text input of the `optimizer` crate scheduler.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt::Write;
+use super::intrinsics::{Array1D, Matrix};
+
+/// Traced instruction. Every arithmetic instruction has its own tag,
+/// logic, compare, select and the rest of scalar core instructions are `Vector`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TraceOp {
+    /// Value loaded from host
+    Input,
+    Opac,
+    Add,
+    Sub,
+    AddSat,
+    SubSat,
+    Min,
+    Max,
+    ScaMul,
+    MulHi,
+    Abs,
+    AbsSat,
+    Neg,
+    NegSat,
+    Vector,
+}
+
+impl TraceOp {
+    /// Name in optimizer input, `None` for inputs
+    pub fn optimizer_name(&self) -> Option<&'static str> {
+        match self {
+            TraceOp::Input => None,
+            TraceOp::Opac => Some("opac"),
+            TraceOp::Add => Some("v_add"),
+            TraceOp::Sub => Some("v_sub"),
+            TraceOp::AddSat => Some("v_add_sat"),
+            TraceOp::SubSat => Some("v_sub_sat"),
+            TraceOp::Min => Some("v_min"),
+            TraceOp::Max => Some("v_max"),
+            TraceOp::ScaMul => Some("sca_mul"),
+            TraceOp::MulHi => Some("v_mulhi"),
+            TraceOp::Abs => Some("v_abs"),
+            TraceOp::AbsSat => Some("v_abs_sat"),
+            TraceOp::Neg => Some("v_neg"),
+            TraceOp::NegSat => Some("v_neg_sat"),
+            TraceOp::Vector => Some("vector"),
+        }
+    }
+}
+
+pub type NodeId = usize;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Trace {
+    nodes: Vec<TraceOp>,
+    /// `(source, user)` pairs
+    edges: Vec<(NodeId, NodeId)>,
+    /// Same edges, so that repeated operands are recorded once
+    edge_set: HashSet<(NodeId, NodeId)>,
+}
+
+impl Trace {
+    pub fn nodes(&self) -> &[TraceOp] {
+        &self.nodes
+    }
+
+    pub fn edges(&self) -> &[(NodeId, NodeId)] {
+        &self.edges
+    }
+
+    fn add_edge(&mut self, source: NodeId, user: NodeId) {
+        if self.edge_set.insert((source, user)) {
+            self.edges.push((source, user));
+        }
+    }
+
+    pub fn count(&self, op: TraceOp) -> usize {
+        self.nodes.iter().filter(|x| **x == op).count()
+    }
+
+    /// Text input of optimizer: `node <id> <op>` lines, with `input` for loaded values,
+    /// then `edge <source> <user>` lines
+    pub fn to_optimizer_input(&self) -> String {
+        let mut res = String::new();
+        for (id, op) in self.nodes.iter().enumerate() {
+            writeln!(res, "node {} {}", id, op.optimizer_name().unwrap_or("input")).unwrap();
+        }
+        for (from, to) in &self.edges {
+            writeln!(res, "edge {} {}", from, to).unwrap();
+        }
+        res
+    }
+}
+
+thread_local! {
+    static TRACE: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+/// Adds node with edges from `sources`. Does nothing, when tracing is off
+pub(crate) fn record(op: TraceOp, sources: &[Option<NodeId>]) -> Option<NodeId> {
+    TRACE.with(|t| {
+        let mut trace = t.borrow_mut();
+        let trace = trace.as_mut()?;
+        let id = trace.nodes.len();
+        trace.nodes.push(op);
+        for source in sources.iter().flatten() {
+            trace.add_edge(*source, id);
+        }
+        Some(id)
+    })
+}
+
+/// Records vector instruction, which produced `res` from `sources`
+pub(crate) fn record_vector(op: TraceOp, sources: &[&Array1D], res: &mut Array1D) {
+    let sources: Vec<_> = sources.iter().map(|x| x.node).collect();
+    res.node = record(op, &sources);
+}
+
+/// Adds edges from `sources` to the node of `res`, for instructions with implicit operands
+pub(crate) fn add_sources(res: &Array1D, sources: &[&Array1D]) {
+    let Some(id) = res.node else {
+        return;
+    };
+    TRACE.with(|t| {
+        if let Some(trace) = t.borrow_mut().as_mut() {
+            for source in sources.iter().filter_map(|x| x.node) {
+                trace.add_edge(source, id);
+            }
+        }
+    });
+}
+
+/// Records `OPAC` pass into `res`
+pub(crate) fn record_opac(res: &mut Matrix, [a, b]: &[Option<NodeId>; 2]) {
+    res.node = record(TraceOp::Opac, &[res.node, *a, *b]);
+}
+
+/// Runs `f` and returns trace of instructions issued by it.
+/// Calls can't be nested: inner call takes the instructions.
+pub fn traced<R>(f: impl FnOnce() -> R) -> (R, Trace) {
+    let outer = TRACE.with(|t| t.replace(Some(Trace::default())));
+    let res = f();
+    let trace = TRACE.with(|t| t.replace(outer)).unwrap();
+    (res, trace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array2;
+    use crate::intrinsics::intrinsics::{sca_mul, v_abs, v_add, v_add_sat, v_max, v_min, v_mulhi, v_sub, v_xor};
+    use crate::intrinsics::wrappers::{mat_mul, vec_max};
+
+    #[test]
+    fn mat_mul_chain_of_opac() {
+        let (k, m, n) = (3, 2, 4);
+        let a = Array2::from_elem((m, k), 0.5);
+        let b = Array2::from_elem((n, k), 0.25);
+        let (_, trace) = traced(|| mat_mul(a.view(), b.view()));
+        assert_eq!(trace.count(TraceOp::Opac), k);
+        assert_eq!(trace.count(TraceOp::Input), 2 * k);
+        // Each pass depends on two loaded columns and the previous pass
+        assert_eq!(trace.edges().len(), 2 * k + k - 1);
+        let text = trace.to_optimizer_input();
+        assert!(text.starts_with("node 0 input\nnode 1 input\nnode 2 opac\n"));
+        assert!(text.contains("edge 2 5\n"));
+    }
+
+    #[test]
+    fn vector_dependencies() {
+        let (_, trace) = traced(|| {
+            let a = Array1D::from_slice(&[1, 2]);
+            let b = Array1D::from_slice(&[3, 4]);
+            let c = sca_mul(&a, &b);
+            let d = v_min(&c, &a);
+            v_max(&d, &d)
+        });
+        assert_eq!(
+            trace.nodes(),
+            &[TraceOp::Input, TraceOp::Input, TraceOp::ScaMul, TraceOp::Min, TraceOp::Max]
+        );
+        assert_eq!(trace.edges(), &[(0, 2), (1, 2), (2, 3), (0, 3), (3, 4)]);
+
+        let x = ndarray::Array1::from_elem(5, 1);
+        let (_, trace) = traced(|| vec_max(x.view(), x.view()));
+        assert_eq!(trace.count(TraceOp::Max), 1);
+        // Nothing is recorded without tracing
+        assert_eq!(traced(|| ()).1, Trace::default());
+    }
+
+    #[test]
+    fn arithmetic_tags() {
+        let (_, trace) = traced(|| {
+            let a = Array1D::from_slice(&[1, 2]);
+            [v_add(&a, &a), v_sub(&a, &a), v_add_sat(&a, &a), v_mulhi(&a, &a), v_abs(&a), v_xor(&a, &a)]
+        });
+        let ops = [TraceOp::Add, TraceOp::Sub, TraceOp::AddSat, TraceOp::MulHi, TraceOp::Abs, TraceOp::Vector];
+        assert_eq!(&trace.nodes()[1..], &ops);
+        assert!(trace.to_optimizer_input().contains("node 2 v_sub\n"));
+    }
+}
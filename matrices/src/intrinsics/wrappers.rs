@@ -1,35 +1,350 @@
 use std::cmp::min;
 use std::iter::zip;
-use ndarray::{s, Array2, ArrayView2, ArrayViewMut2};
+use std::ops::{AddAssign, Range};
+use ndarray::{s, Array1, Array2, ArrayBase, ArrayView1, ArrayView2, ArrayViewMut2, Data, Ix2, ShapeBuilder};
 use crate::intrinsics::config::DIMENSION;
-use super::intrinsics::{opac, Matrix};
+use super::int4::{opac_int4, PackedArray1D};
+use super::counters::count_to_host;
+use super::intrinsics::{self, opac, AccT, Array1D, ChipT, Inactive, LaneMask, Matrix};
+use super::lut::{v_lut, Lut};
+use super::quant::Element;
+use super::reductions::{self, Overflow};
 
-/// Makes blocks of size no more than DIMENSION multiplication
-fn block_mul<'a>(mut res: ArrayViewMut2<f32>, a: ArrayView2<'a, f32>, b: ArrayView2<'a, f32>) {
+/// Element type of host matrices
+pub trait HostFloat: Copy + From<f32> + AddAssign {
+    fn to_f32(self) -> f32;
+
+    /// Same view, when it's already `f32`
+    fn as_f32(view: ArrayView2<Self>) -> Option<ArrayView2<f32>>;
+}
+
+impl HostFloat for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn as_f32(view: ArrayView2<f32>) -> Option<ArrayView2<f32>> {
+        Some(view)
+    }
+}
+
+impl HostFloat for f64 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn as_f32(_: ArrayView2<f64>) -> Option<ArrayView2<f32>> {
+        None
+    }
+}
+
+/// Host buffer of a block in `f32` with contiguous columns, as they are loaded to vector registers
+struct Panel {
+    data: Vec<f32>,
+}
+
+impl Panel {
+    fn new() -> Self {
+        Panel { data: Vec::new() }
+    }
+
+    /// Block as is, when it is `f32` and either in column-major order or read only once,
+    /// otherwise its packed copy
+    fn load<'p, A: HostFloat>(&'p mut self, block: ArrayView2<'p, A>, reused: bool) -> ArrayView2<'p, f32> {
+        if let Some(view) = A::as_f32(block) {
+            if !reused || block.nrows() <= 1 || block.strides()[0] == 1 {
+                return view;
+            }
+        }
+        // Rows are read in memory order of row-major blocks, and written with a stride
+        let rows = block.nrows();
+        self.data.resize(block.len(), 0.);
+        for (r, row) in block.rows().into_iter().enumerate() {
+            for (c, x) in row.iter().enumerate() {
+                self.data[c * rows + r] = x.to_f32();
+            }
+        }
+        ArrayView2::from_shape(block.dim().f(), &self.data[..block.len()]).unwrap()
+    }
+}
+
+/// Host element of operand columns, as they are loaded to vector registers
+pub(crate) trait Lanes: Copy {
+    fn int8(column: ArrayView1<Self>) -> Array1D;
+    fn int4(column: ArrayView1<Self>) -> PackedArray1D;
+}
+
+impl Lanes for f32 {
+    fn int8(column: ArrayView1<f32>) -> Array1D {
+        column.try_into().unwrap()
+    }
+
+    fn int4(column: ArrayView1<f32>) -> PackedArray1D {
+        column.try_into().unwrap()
+    }
+}
+
+impl Lanes for ChipT {
+    /// Loaded as is, without conversion
+    fn int8(column: ArrayView1<ChipT>) -> Array1D {
+        column.try_into().unwrap()
+    }
+
+    fn int4(column: ArrayView1<ChipT>) -> PackedArray1D {
+        (&Self::int8(column)).try_into().unwrap()
+    }
+}
+
+/// Operand of the blocked product, which gives its blocks with common dimension along columns
+trait Blocks {
+    type Elem: Lanes;
+
+    fn dim(&self) -> (usize, usize);
+
+    /// `reused` blocks are read for several result blocks
+    fn block(&mut self, rows: Range<usize>, cols: Range<usize>, reused: bool) -> ArrayView2<'_, Self::Elem>;
+}
+
+/// Host float operand, blocks are loaded through `Panel`
+struct Packed<'a, A, S: Data<Elem = A>> {
+    x: &'a ArrayBase<S, Ix2>,
+    panel: &'a mut Panel,
+}
+
+impl<A: HostFloat, S: Data<Elem = A>> Blocks for Packed<'_, A, S> {
+    type Elem = f32;
+
+    fn dim(&self) -> (usize, usize) {
+        self.x.dim()
+    }
+
+    fn block(&mut self, rows: Range<usize>, cols: Range<usize>, reused: bool) -> ArrayView2<'_, f32> {
+        self.panel.load(self.x.slice(s![rows, cols]), reused)
+    }
+}
+
+impl Blocks for ArrayView2<'_, ChipT> {
+    type Elem = ChipT;
+
+    fn dim(&self) -> (usize, usize) {
+        ArrayView2::dim(self)
+    }
+
+    fn block(&mut self, rows: Range<usize>, cols: Range<usize>, _: bool) -> ArrayView2<'_, ChipT> {
+        self.slice(s![rows, cols])
+    }
+}
+
+/// Operand of `mat_mul_operands`
+#[derive(Clone, Copy, Debug)]
+pub enum Operand<'a> {
+    /// Quantized on every load, as in `mat_mul`
+    F32(ArrayView2<'a, f32>),
+    /// Already in chip format, loaded as is and read by `OPAC` as `value / 128`
+    Chip(ArrayView2<'a, ChipT>),
+}
+
+impl Operand<'_> {
+    pub fn dim(&self) -> (usize, usize) {
+        match self {
+            Operand::F32(v) => v.dim(),
+            Operand::Chip(v) => v.dim(),
+        }
+    }
+}
+
+/// Hooks of the blocked product on accumulator of each result block
+pub(crate) trait Epilogue {
+    /// Accumulator before the first block of the common dimension
+    fn preload(&mut self, acc: &mut Matrix, rows: Range<usize>, cols: Range<usize>) {
+        acc.reset(rows.len(), cols.len());
+    }
+
+    /// Accumulator after the last block of the common dimension, before its readout
+    fn finish(&mut self, _acc: &mut Matrix) {}
+}
+
+/// Zero accumulator, read back as is
+impl Epilogue for () {}
+
+/// Host buffers of products: accumulator and packed operands.
+/// `_into` products take it from the caller, so the ones reusing it don't allocate
+pub struct Workspace {
+    acc: Matrix,
+    panel_a: Panel,
+    panel_b: Panel,
+}
+
+impl Workspace {
+    pub fn new() -> Self {
+        Workspace { acc: Matrix::zeros(0, 0), panel_a: Panel::new(), panel_b: Panel::new() }
+    }
+}
+
+impl Default for Workspace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Makes blocks of size no more than DIMENSION multiplication,
+/// accumulates `a * b^T` in `res_mat`
+fn block_mul<L: Lanes, R: Lanes>(res_mat: &mut Matrix, a: ArrayView2<L>, b: ArrayView2<R>, element: Element) {
     assert!(a.shape()[1] <= DIMENSION);
     assert!(b.shape()[1] <= DIMENSION);
     assert_eq!(a.shape()[1], b.shape()[1]);
-    assert_eq!(res.shape()[0], a.shape()[0]);
-    assert_eq!(res.shape()[1], b.shape()[0]);
+    assert_eq!(res_mat.rows(), a.shape()[0]);
+    assert_eq!(res_mat.cols(), b.shape()[0]);
 
-    let mut res_mat = Matrix::zeros(a.nrows(), b.nrows());
     for (r1, r2) in zip(a.columns(), b.columns()) {
-        opac(&mut res_mat, r1.try_into().unwrap(), r2.try_into().unwrap());
+        match element {
+            Element::Int8 => opac(res_mat, L::int8(r1), R::int8(r2)),
+            Element::Int4 => opac_int4(res_mat, &L::int4(r1), &R::int4(r2)),
+        }
     }
-    res_mat.convert(&mut res);
 }
 
 
-/// Makes any shape matrix multiplication
-pub fn mat_mul<'a>(a: ArrayView2<'a, f32>, b: ArrayView2<'a, f32>) -> Array2<f32> {
+/// Makes any shape matrix multiplication: `a * b^T`.
+/// Both operands are given with common dimension along columns,
+/// in any storage and memory layout
+pub fn mat_mul<A, S1, S2>(a: ArrayBase<S1, Ix2>, b: ArrayBase<S2, Ix2>) -> Array2<A>
+where
+    A: HostFloat,
+    S1: Data<Elem = A>,
+    S2: Data<Elem = A>,
+{
+    mat_mul_with(a, b, Element::Int8)
+}
+
+/// Same as `mat_mul`, writes result to `out` instead of allocating it
+pub fn mat_mul_into<A, S1, S2>(a: ArrayBase<S1, Ix2>, b: ArrayBase<S2, Ix2>, out: ArrayViewMut2<A>, ws: &mut Workspace)
+where
+    A: HostFloat,
+    S1: Data<Elem = A>,
+    S2: Data<Elem = A>,
+{
+    mat_mul_with_into(a, b, out, Element::Int8, ws)
+}
+
+/// Same as `mat_mul`, with operands quantized to `element` type
+pub fn mat_mul_with<A, S1, S2>(a: ArrayBase<S1, Ix2>, b: ArrayBase<S2, Ix2>, element: Element) -> Array2<A>
+where
+    A: HostFloat,
+    S1: Data<Elem = A>,
+    S2: Data<Elem = A>,
+{
+    let mut res = Array2::from_elem((a.nrows(), b.nrows()), A::from(0.));
+    mat_mul_with_into(a, b, res.view_mut(), element, &mut Workspace::new());
+    res
+}
+
+/// Same as `mat_mul_with`, writes result to `out`.
+/// Operand blocks used for several result blocks are packed once to contiguous `f32` columns,
+/// other `f32` blocks are read in place. Accumulator and packing buffers are kept in `ws`,
+/// so calls after the first one with the same workspace don't allocate.
+pub fn mat_mul_with_into<A, S1, S2>(
+    a: ArrayBase<S1, Ix2>,
+    b: ArrayBase<S2, Ix2>,
+    out: ArrayViewMut2<A>,
+    element: Element,
+    ws: &mut Workspace,
+) where
+    A: HostFloat,
+    S1: Data<Elem = A>,
+    S2: Data<Elem = A>,
+{
+    let mut a = Packed { x: &a, panel: &mut ws.panel_a };
+    let mut b = Packed { x: &b, panel: &mut ws.panel_b };
+    blocked_into(&mut a, &mut b, out, &mut ws.acc, element, &mut ());
+}
+
+/// `a * b^T` of operands in either host or chip format, with `Int8` elements
+pub fn mat_mul_operands(a: Operand, b: Operand) -> Array2<f32> {
+    mat_mul_operands_with(a, b, &mut ())
+}
+
+/// Same as `mat_mul_operands`, with `epilogue` applied to accumulators
+pub(crate) fn mat_mul_operands_with(a: Operand, b: Operand, epilogue: &mut impl Epilogue) -> Array2<f32> {
+    let mut res = Array2::zeros((a.dim().0, b.dim().0));
+    let out = res.view_mut();
+    let mut ws = Workspace::new();
+    let (acc, element) = (&mut ws.acc, Element::Int8);
+    match (a, b) {
+        (Operand::F32(a), Operand::F32(b)) => blocked_into(
+            &mut Packed { x: &a, panel: &mut ws.panel_a },
+            &mut Packed { x: &b, panel: &mut ws.panel_b },
+            out,
+            acc,
+            element,
+            epilogue,
+        ),
+        (Operand::F32(a), Operand::Chip(mut b)) => {
+            blocked_into(&mut Packed { x: &a, panel: &mut ws.panel_a }, &mut b, out, acc, element, epilogue)
+        }
+        (Operand::Chip(mut a), Operand::F32(b)) => {
+            blocked_into(&mut a, &mut Packed { x: &b, panel: &mut ws.panel_b }, out, acc, element, epilogue)
+        }
+        (Operand::Chip(mut a), Operand::Chip(mut b)) => blocked_into(&mut a, &mut b, out, acc, element, epilogue),
+    }
+    res
+}
+
+/// Blocked `a * b^T` into `out`, accumulator is read back per block
+fn blocked_into<A: HostFloat>(
+    a: &mut impl Blocks,
+    b: &mut impl Blocks,
+    mut out: ArrayViewMut2<A>,
+    acc: &mut Matrix,
+    element: Element,
+    epilogue: &mut impl Epilogue,
+) {
+    let ((m, common), (n, b_common)) = (a.dim(), b.dim());
+    assert_eq!(common, b_common);
+    assert_eq!(out.dim(), (m, n));
+    out.fill(A::from(0.));
+    // Common dimension is the outer loop, so each operand block is loaded once;
+    // result cells still get their blocks in order of `k`
+    for k in (0..common).step_by(DIMENSION) {
+        let next_k = min(k + DIMENSION, common);
+        let panel_b = b.block(0..n, k..next_k, m > DIMENSION);
+        for i in (0..m).step_by(DIMENSION) {
+            let next_i = min(i + DIMENSION, m);
+            let block_a = a.block(i..next_i, k..next_k, n > DIMENSION);
+            for j in (0..n).step_by(DIMENSION) {
+                let next_j = min(j + DIMENSION, n);
+                if k == 0 {
+                    epilogue.preload(acc, i..next_i, j..next_j);
+                } else {
+                    acc.reset(next_i - i, next_j - j);
+                }
+                block_mul(acc, block_a.view(), panel_b.slice(s![j..next_j, ..]), element);
+                if next_k == common {
+                    epilogue.finish(acc);
+                }
+                acc.add_to(&mut out.slice_mut(s![i..next_i, j..next_j]));
+            }
+        }
+    }
+}
+
+/// Splits `a * b^T` into blocks with at most `rows` rows of each operand
+/// and DIMENSION common columns, `f` adds product of a block to the result.
+/// Block position in the result is passed too
+pub(crate) fn tiled(
+    a: ArrayView2<f32>,
+    b: ArrayView2<f32>,
+    rows: usize,
+    mut f: impl for<'x> FnMut(ArrayViewMut2<f32>, ArrayView2<'x, f32>, ArrayView2<'x, f32>, (usize, usize)),
+) -> Array2<f32> {
     assert_eq!(a.shape()[1], b.shape()[1]);
     let common_dim = a.shape()[1];
     let mut res = Array2::default([a.shape()[0], b.shape()[0]]);
-    for i in (0..a.shape()[0]).step_by(DIMENSION) {
-        for j in (0..b.shape()[0]).step_by(DIMENSION) {
+    for i in (0..a.shape()[0]).step_by(rows) {
+        for j in (0..b.shape()[0]).step_by(rows) {
             for k in (0..common_dim).step_by(DIMENSION) {
-                let next_i = min(i + DIMENSION, a.shape()[0]);
-                let next_j = min(j + DIMENSION, b.shape()[0]);
+                let next_i = min(i + rows, a.shape()[0]);
+                let next_j = min(j + rows, b.shape()[0]);
                 let next_k = min(k + DIMENSION, a.shape()[1]);
 
                 let a_index = s![i..next_i, k..next_k];
@@ -39,24 +354,408 @@ pub fn mat_mul<'a>(a: ArrayView2<'a, f32>, b: ArrayView2<'a, f32>) -> Array2<f32
                 let block_a = a.slice(a_index);
                 let block_b = b.slice(b_index);
 
-
-
                 let res_block = res.slice_mut(res_index);
-                block_mul(res_block, block_a, block_b);
+                f(res_block, block_a, block_b, (i, j));
             }
         }
     }
     res
 }
 
+/// Reads vector register back to host
+fn to_host(x: &Array1D) -> &[ChipT] {
+    count_to_host(x.len() as u64);
+    x.as_slice()
+}
+
+/// Runs lane-wise vector instruction on vectors of any length, by chunks of DIMENSION
+fn lanewise_binary(
+    a: ArrayView1<ChipT>,
+    b: ArrayView1<ChipT>,
+    op: fn(&Array1D, &Array1D) -> Array1D,
+) -> Array1<ChipT> {
+    assert_eq!(a.len(), b.len());
+    let mut res = Vec::with_capacity(a.len());
+    for i in (0..a.len()).step_by(DIMENSION) {
+        let index = s![i..min(i + DIMENSION, a.len())];
+        let x: Array1D = a.slice(index).try_into().unwrap();
+        let y: Array1D = b.slice(index).try_into().unwrap();
+        res.extend_from_slice(to_host(&op(&x, &y)));
+    }
+    Array1::from_vec(res)
+}
+
+fn lanewise_unary(a: ArrayView1<ChipT>, op: impl Fn(&Array1D) -> Array1D) -> Array1<ChipT> {
+    let mut res = Vec::with_capacity(a.len());
+    for i in (0..a.len()).step_by(DIMENSION) {
+        let chunk: Array1D = a.slice(s![i..min(i + DIMENSION, a.len())]).try_into().unwrap();
+        res.extend_from_slice(to_host(&op(&chunk)));
+    }
+    Array1::from_vec(res)
+}
+
+/// Runs masked lane-wise instruction by chunks of DIMENSION.
+/// Lanes where `mask` is zero keep value from `dst`, or are zeroed without it.
+///
+/// Ex: `vec_binary_masked(a, b, mask, Some(dst), intrinsics::v_add_masked)`
+pub fn vec_binary_masked(
+    a: ArrayView1<ChipT>,
+    b: ArrayView1<ChipT>,
+    mask: ArrayView1<ChipT>,
+    dst: Option<ArrayView1<ChipT>>,
+    op: fn(&Array1D, &Array1D, &LaneMask) -> Array1D,
+) -> Array1<ChipT> {
+    assert_eq!(a.len(), b.len());
+    let y = chunks(b);
+    masked_chunks(a, mask, dst, |i, x, m| op(x, &y[i], m))
+}
+
+/// Same as `vec_binary_masked`, for instructions with one operand
+pub fn vec_unary_masked(
+    a: ArrayView1<ChipT>,
+    mask: ArrayView1<ChipT>,
+    dst: Option<ArrayView1<ChipT>>,
+    op: fn(&Array1D, &LaneMask) -> Array1D,
+) -> Array1<ChipT> {
+    masked_chunks(a, mask, dst, |_, x, m| op(x, m))
+}
+
+/// Vector registers with DIMENSION chunks of `a`
+fn chunks(a: ArrayView1<ChipT>) -> Vec<Array1D> {
+    (0..a.len())
+        .step_by(DIMENSION)
+        .map(|i| a.slice(s![i..min(i + DIMENSION, a.len())]).try_into().unwrap())
+        .collect()
+}
+
+fn masked_chunks(
+    a: ArrayView1<ChipT>,
+    mask: ArrayView1<ChipT>,
+    dst: Option<ArrayView1<ChipT>>,
+    op: impl Fn(usize, &Array1D, &LaneMask) -> Array1D,
+) -> Array1<ChipT> {
+    assert_eq!(mask.len(), a.len());
+    let dst = dst.map(|d| {
+        assert_eq!(d.len(), a.len());
+        chunks(d)
+    });
+    let mut res = Vec::with_capacity(a.len());
+    for (i, (x, m)) in zip(chunks(a), chunks(mask)).enumerate() {
+        let inactive = match &dst {
+            Some(d) => Inactive::Keep(&d[i]),
+            None => Inactive::Zero,
+        };
+        res.extend_from_slice(to_host(&op(i, &x, &LaneMask::new(&m, inactive))));
+    }
+    Array1::from_vec(res)
+}
+
+/// Wrapping lane-wise addition of any length vectors
+pub fn vec_add(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_add)
+}
+
+/// Wrapping lane-wise subtraction of any length vectors
+pub fn vec_sub(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_sub)
+}
+
+/// Saturating lane-wise addition of any length vectors
+pub fn vec_add_sat(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_add_sat)
+}
+
+/// Saturating lane-wise subtraction of any length vectors
+pub fn vec_sub_sat(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_sub_sat)
+}
+
+/// Lower 8 bits of lane-wise product
+pub fn vec_mul(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::sca_mul)
+}
+
+/// Upper 8 bits of lane-wise product
+pub fn vec_mulhi(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_mulhi)
+}
+
+/// Lane-wise minimum of any length vectors
+pub fn vec_min(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_min)
+}
+
+/// Lane-wise maximum of any length vectors
+pub fn vec_max(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_max)
+}
+
+/// Wrapping absolute value, so `abs(MIN) == MIN`
+pub fn vec_abs(a: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_unary(a, intrinsics::v_abs)
+}
+
+/// Absolute value clamped, so `abs(MIN) == MAX`
+pub fn vec_abs_sat(a: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_unary(a, intrinsics::v_abs_sat)
+}
+
+/// Wrapping negation, so `-MIN == MIN`
+pub fn vec_neg(a: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_unary(a, intrinsics::v_neg)
+}
+
+/// Negation clamped, so `-MIN == MAX`
+pub fn vec_neg_sat(a: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_unary(a, intrinsics::v_neg_sat)
+}
+
+/// Applies lookup table to every element
+pub fn vec_lut(lut: &Lut, a: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_unary(a, |x| v_lut(lut, x))
+}
+
+/// Left shift of `a` by lane amounts from `b`
+pub fn vec_shl(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_shl)
+}
+
+/// Arithmetic right shift of `a` by lane amounts from `b`
+pub fn vec_shr(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_shr)
+}
+
+/// Lane-wise bitwise and
+pub fn vec_and(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_and)
+}
+
+/// Lane-wise bitwise or
+pub fn vec_or(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_or)
+}
+
+/// Lane-wise bitwise xor
+pub fn vec_xor(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_xor)
+}
+
+/// Lane-wise `a == b` as mask, see `intrinsics::MASK_TRUE`
+pub fn vec_cmp_eq(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_cmp_eq)
+}
+
+/// Lane-wise `a < b` as mask, see `intrinsics::MASK_TRUE`
+pub fn vec_cmp_lt(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_cmp_lt)
+}
+
+/// Lane-wise `a <= b` as mask, see `intrinsics::MASK_TRUE`
+pub fn vec_cmp_le(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_cmp_le)
+}
+
+/// Lane-wise `a > b` as mask, see `intrinsics::MASK_TRUE`
+pub fn vec_cmp_gt(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    lanewise_binary(a, b, intrinsics::v_cmp_gt)
+}
+
+/// Takes values from `a` where `mask` is non-zero, otherwise from `b`
+pub fn vec_select(mask: ArrayView1<ChipT>, a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    assert_eq!(mask.len(), a.len());
+    assert_eq!(a.len(), b.len());
+    let mut res = Vec::with_capacity(a.len());
+    for i in (0..a.len()).step_by(DIMENSION) {
+        let index = s![i..min(i + DIMENSION, a.len())];
+        let m: Array1D = mask.slice(index).try_into().unwrap();
+        let x: Array1D = a.slice(index).try_into().unwrap();
+        let y: Array1D = b.slice(index).try_into().unwrap();
+        res.extend_from_slice(to_host(&intrinsics::v_select(&m, &x, &y)));
+    }
+    Array1::from_vec(res)
+}
+
+/// Applies reduction to every DIMENSION chunk of `a`, results are read back to host
+fn chunked<T>(a: ArrayView1<ChipT>, op: impl Fn(&Array1D) -> T) -> Vec<T> {
+    (0..a.len())
+        .step_by(DIMENSION)
+        .map(|i| {
+            count_to_host(size_of::<T>() as u64);
+            op(&a.slice(s![i..min(i + DIMENSION, a.len())]).try_into().unwrap())
+        })
+        .collect()
+}
+
+/// Exact sum of any length vector, chunk sums are accumulated on host in `i64`
+pub fn vec_sum(a: ArrayView1<ChipT>) -> i64 {
+    chunked(a, reductions::v_sum).into_iter().map(i64::from).sum()
+}
+
+/// Exact dot product, chunk results are accumulated on host in `i64`
+pub fn vec_dot(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> i64 {
+    assert_eq!(a.len(), b.len());
+    (0..a.len())
+        .step_by(DIMENSION)
+        .map(|i| {
+            let index = s![i..min(i + DIMENSION, a.len())];
+            let x: Array1D = a.slice(index).try_into().unwrap();
+            let y: Array1D = b.slice(index).try_into().unwrap();
+            count_to_host(size_of::<AccT>() as u64);
+            i64::from(reductions::v_dot(&x, &y))
+        })
+        .sum()
+}
+
+/// Maximum element. Panics on empty vector.
+pub fn vec_reduce_max(a: ArrayView1<ChipT>) -> ChipT {
+    chunked(a, reductions::v_reduce_max).into_iter().max().expect("Reduction of empty vector")
+}
+
+/// Minimum element. Panics on empty vector.
+pub fn vec_reduce_min(a: ArrayView1<ChipT>) -> ChipT {
+    chunked(a, reductions::v_reduce_min).into_iter().min().expect("Reduction of empty vector")
+}
+
+/// Index of first maximum element. Panics on empty vector.
+pub fn vec_argmax(a: ArrayView1<ChipT>) -> usize {
+    assert!(!a.is_empty(), "Reduction of empty vector");
+    let chunk_res = chunked(a, |x| {
+        let i = reductions::v_argmax(x);
+        (x[i], i)
+    });
+    let mut best = 0;
+    for (i, (value, _)) in chunk_res.iter().enumerate() {
+        if *value > chunk_res[best].0 {
+            best = i;
+        }
+    }
+    best * DIMENSION + chunk_res[best].1
+}
+
+/// Index of first minimum element. Panics on empty vector.
+pub fn vec_argmin(a: ArrayView1<ChipT>) -> usize {
+    assert!(!a.is_empty(), "Reduction of empty vector");
+    let chunk_res = chunked(a, |x| {
+        let i = reductions::v_argmin(x);
+        (x[i], i)
+    });
+    let mut best = 0;
+    for (i, (value, _)) in chunk_res.iter().enumerate() {
+        if *value < chunk_res[best].0 {
+            best = i;
+        }
+    }
+    best * DIMENSION + chunk_res[best].1
+}
+
+/// Applies masked reduction to every DIMENSION chunk of `a`, results are read back to host.
+/// `op` gets index of the chunk, its lanes and mask.
+fn chunked_masked<T>(a: ArrayView1<ChipT>, mask: ArrayView1<ChipT>, op: impl Fn(usize, &Array1D, &LaneMask) -> T) -> Vec<T> {
+    assert_eq!(mask.len(), a.len());
+    zip(chunks(a), chunks(mask))
+        .enumerate()
+        .map(|(i, (x, m))| {
+            count_to_host(size_of::<T>() as u64);
+            op(i, &x, &LaneMask::new(&m, Inactive::Zero))
+        })
+        .collect()
+}
+
+/// Exact sum of elements where `mask` is non-zero
+pub fn vec_sum_masked(a: ArrayView1<ChipT>, mask: ArrayView1<ChipT>) -> i64 {
+    chunked_masked(a, mask, |_, x, m| reductions::v_sum_masked(x, m)).into_iter().map(i64::from).sum()
+}
+
+/// Exact dot product over elements where `mask` is non-zero
+pub fn vec_dot_masked(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>, mask: ArrayView1<ChipT>) -> i64 {
+    assert_eq!(a.len(), b.len());
+    let y = chunks(b);
+    chunked_masked(a, mask, |i, x, m| reductions::v_dot_masked(x, &y[i], m))
+        .into_iter()
+        .map(i64::from)
+        .sum()
+}
+
+/// Maximum of elements where `mask` is non-zero, `ChipT::MIN` when there are none
+pub fn vec_reduce_max_masked(a: ArrayView1<ChipT>, mask: ArrayView1<ChipT>) -> ChipT {
+    let chunk_res = chunked_masked(a, mask, |_, x, m| reductions::v_reduce_max_masked(x, m));
+    chunk_res.into_iter().max().unwrap_or(ChipT::MIN)
+}
+
+/// Minimum of elements where `mask` is non-zero, `ChipT::MAX` when there are none
+pub fn vec_reduce_min_masked(a: ArrayView1<ChipT>, mask: ArrayView1<ChipT>) -> ChipT {
+    let chunk_res = chunked_masked(a, mask, |_, x, m| reductions::v_reduce_min_masked(x, m));
+    chunk_res.into_iter().min().unwrap_or(ChipT::MAX)
+}
+
+/// Index of first maximum element where `mask` is non-zero, `None` when there are none
+pub fn vec_argmax_masked(a: ArrayView1<ChipT>, mask: ArrayView1<ChipT>) -> Option<usize> {
+    let chunk_res = chunked_masked(a, mask, |i, x, m| {
+        reductions::v_argmax_masked(x, m).map(|j| (x[j], i * DIMENSION + j))
+    });
+    chunk_res.into_iter().flatten().reduce(|best, x| if x.0 > best.0 { x } else { best }).map(|x| x.1)
+}
+
+/// Index of first minimum element where `mask` is non-zero, `None` when there are none
+pub fn vec_argmin_masked(a: ArrayView1<ChipT>, mask: ArrayView1<ChipT>) -> Option<usize> {
+    let chunk_res = chunked_masked(a, mask, |i, x, m| {
+        reductions::v_argmin_masked(x, m).map(|j| (x[j], i * DIMENSION + j))
+    });
+    chunk_res.into_iter().flatten().reduce(|best, x| if x.0 < best.0 { x } else { best }).map(|x| x.1)
+}
+
+/// Runs inclusive scan on any length vector.
+/// Each chunk holds `DIMENSION - 1` new values, and its first lane is the carry from
+/// previous chunk, so result is the same as for a single long sequential scan.
+fn chunked_scan(a: ArrayView1<ChipT>, identity: ChipT, op: impl Fn(&Array1D) -> Array1D) -> Array1<ChipT> {
+    let step = DIMENSION - 1;
+    let mut res = Vec::with_capacity(a.len());
+    let mut carry = identity;
+    for i in (0..a.len()).step_by(step) {
+        let mut lanes = vec![carry];
+        lanes.extend(a.slice(s![i..min(i + step, a.len())]));
+        let scanned = op(&Array1D::from_slice(&lanes));
+        res.extend_from_slice(&to_host(&scanned)[1..]);
+        carry = *res.last().unwrap();
+    }
+    Array1::from_vec(res)
+}
+
+/// Inclusive prefix sum, see `reductions::v_prefix_sum` for overflow policy
+pub fn vec_prefix_sum(a: ArrayView1<ChipT>, overflow: Overflow) -> Array1<ChipT> {
+    chunked_scan(a, 0, |x| reductions::v_prefix_sum(x, overflow))
+}
+
+/// Exclusive prefix sum, see `reductions::v_prefix_sum` for overflow policy
+pub fn vec_prefix_sum_exclusive(a: ArrayView1<ChipT>, overflow: Overflow) -> Array1<ChipT> {
+    let inclusive = vec_prefix_sum(a, overflow);
+    let mut res = Array1::zeros(a.len());
+    if a.len() > 1 {
+        res.slice_mut(s![1..]).assign(&inclusive.slice(s![..a.len() - 1]));
+    }
+    res
+}
+
+/// Inclusive prefix maximum. Can't overflow.
+pub fn vec_prefix_max(a: ArrayView1<ChipT>) -> Array1<ChipT> {
+    chunked_scan(a, ChipT::MIN, reductions::v_prefix_max)
+}
+
+/// Inclusive prefix minimum. Can't overflow.
+pub fn vec_prefix_min(a: ArrayView1<ChipT>) -> Array1<ChipT> {
+    chunked_scan(a, ChipT::MAX, reductions::v_prefix_min)
+}
+
 #[cfg(test)]
 mod tests {
     use ndarray::array;
     use super::*;
+    use crate::intrinsics::counters::{cost_table, costed, counted};
+    use crate::testing::sample;
 
     #[test]
     fn simple_mat_mul() {
-        let denom = 64.;
+        let denom: f32 = 64.;
         let a = array![
             [1. / denom, 2. / denom],
             [3. / denom, 4. / denom]
@@ -72,4 +771,208 @@ mod tests {
         let sum = (res - c).sum().abs();
         assert!(sum < f32::EPSILON);
     }
+
+    #[test]
+    fn mat_mul_any_shape() {
+        let (m, n, k) = (3, 5, 2 * DIMENSION + 1);
+        // Exactly representable operands: multiples of 1/128
+        let a = Array2::from_shape_fn((m, k), |(i, j)| ((i * 31 + j * 7) % 17) as f32 / 128.);
+        let b = Array2::from_shape_fn((n, k), |(i, j)| ((i * 13 + j * 3) % 11) as f32 / 128. - 5. / 128.);
+        let res = mat_mul(a.view(), b.view());
+        let expected = a.dot(&b.t());
+        assert_eq!(res.shape(), &[m, n]);
+        for (x, y) in zip(res.iter(), expected.iter()) {
+            assert!((x - y).abs() < 1e-4, "{} {}", x, y);
+        }
+    }
+
+    #[test]
+    fn mat_mul_any_layout() {
+        let (m, n, k) = (5, 7, DIMENSION + 9);
+        let a = Array2::from_shape_fn((m, k), |(i, j)| ((i * 31 + j * 7) % 17) as f32 / 20. - 0.4);
+        let b = Array2::from_shape_fn((n, k), |(i, j)| ((i * 13 + j * 3) % 11) as f32 / 14. - 0.35);
+        let (expected, counters) = counted(|| mat_mul(a.view(), b.view()));
+
+        // Column-major copies, strided views and owned arrays give the same result and counts
+        let a_f = a.t().as_standard_layout().into_owned().reversed_axes();
+        assert_eq!(a_f.strides()[0], 1);
+        let wide = Array2::from_shape_fn((n, 2 * k), |(i, j)| if j % 2 == 0 { b[[i, j / 2]] } else { 9. });
+        let b_strided = wide.slice(s![.., ..;2]);
+        let (res, other) = counted(|| mat_mul(a_f.view(), b_strided));
+        assert_eq!(res, expected);
+        assert_eq!(other, counters);
+        assert_eq!(mat_mul(a.clone(), b.to_shared()), expected);
+
+        let res64 = mat_mul(a.mapv(f64::from), b.mapv(f64::from));
+        assert_eq!(res64, expected.mapv(f64::from));
+
+        let mut out = Array2::from_elem((m + 1, n), 5.);
+        mat_mul_into(a.view(), b.view(), out.slice_mut(s![1.., ..]), &mut Workspace::new());
+        assert_eq!(out.slice(s![1.., ..]), expected);
+        assert!(out.row(0).iter().all(|x| *x == 5.));
+    }
+
+    #[test]
+    fn mat_mul_into_reuses_workspace() {
+        // Both operands span two row blocks, so both are packed
+        let (a, b) = (sample(DIMENSION + 1, 5, 1, 0.5), sample(DIMENSION + 2, 5, 2, 0.5));
+        let mut out = Array2::zeros((a.nrows(), b.nrows()));
+        let mut ws = Workspace::new();
+        let (_, first) = counted(|| mat_mul_into(a.view(), b.view(), out.view_mut(), &mut ws));
+        let expected = out.clone();
+        // Stale panels of the first call don't leak into a smaller product
+        let (c, d) = (sample(3, 5, 3, 0.5), sample(DIMENSION + 2, 5, 4, 0.5));
+        let mut small = Array2::zeros((3, DIMENSION + 2));
+        mat_mul_into(c.view(), d.view(), small.view_mut(), &mut ws);
+        assert_eq!(small, mat_mul(c.view(), d.view()));
+        let (_, second) = counted(|| mat_mul_into(a.view(), b.view(), out.view_mut(), &mut ws));
+        assert_eq!(out, expected);
+        assert_eq!(out, mat_mul(a.view(), b.view()));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn mat_mul_chip_operands() {
+        // Multiples of 1/128 in chip format are the same operands as in `f32`
+        let (m, n, k) = (3, DIMENSION + 2, DIMENSION + 5);
+        let a = Array2::from_shape_fn((m, k), |(i, j)| ((i * 31 + j * 7) % 17) as ChipT - 8);
+        let b = Array2::from_shape_fn((n, k), |(i, j)| ((i * 13 + j * 3) % 11) as ChipT - 5);
+        let (a_f, b_f) = (a.mapv(|x| x as f32 / 128.), b.mapv(|x| x as f32 / 128.));
+        let (expected, counters) = counted(|| mat_mul(a_f.view(), b_f.view()));
+        let (res, chip) = counted(|| mat_mul_operands(Operand::Chip(a.view()), Operand::Chip(b.view())));
+        assert_eq!(res, expected);
+        assert_eq!(chip.opac, counters.opac);
+        // Only the accumulator is read back with conversion
+        assert_eq!(chip.conversions, (m * n * 2) as u64);
+        let (res, mixed) = counted(|| mat_mul_operands(Operand::F32(a_f.view()), Operand::Chip(b.view())));
+        assert_eq!(res, expected);
+        assert!(chip.conversions < mixed.conversions && mixed.conversions < counters.conversions);
+    }
+
+    #[test]
+    fn mat_mul_int4_vs_int8() {
+        let (m, n, k) = (4, 6, DIMENSION + 3);
+        let a = Array2::from_shape_fn((m, k), |(i, j)| ((i * 31 + j * 7) % 17) as f32 / 20. - 0.4);
+        let b = Array2::from_shape_fn((n, k), |(i, j)| ((i * 13 + j * 3) % 11) as f32 / 14. - 0.35);
+        let expected = a.dot(&b.t());
+        let error = |res: Array2<f32>| zip(res.iter(), expected.iter()).map(|(x, y)| (x - y).abs()).fold(0., f32::max);
+        let error8 = error(mat_mul(a.view(), b.view()));
+        let error4 = error(mat_mul_with(a.view(), b.view(), Element::Int4));
+        // Rounding error of each operand is 1/256 and 1/16, over `k` products
+        assert!(error8 < 1. / 256. * k as f32 * 0.1, "{}", error8);
+        assert!(error4 < 1. / 16. * k as f32 * 0.1, "{}", error4);
+        assert!(error8 < error4);
+
+        // Values on int4 grid are exact in both modes
+        let a = a.mapv(|x| (x * 8.).round() / 8.);
+        let b = b.mapv(|x| (x * 8.).round() / 8.);
+        assert_eq!(mat_mul_with(a.view(), b.view(), Element::Int4), mat_mul(a.view(), b.view()));
+    }
+
+    #[test]
+    fn cost_summary() {
+        let (m, n, k) = (3, 4, 5);
+        let a = Array2::from_elem((m, k), 0.25);
+        let b = Array2::from_elem((n, k), 0.5);
+        let (res, summary) = costed(|| mat_mul(a.view(), b.view()));
+        assert_eq!(res, mat_mul(a.view(), b.view()));
+        let counters = summary.counters;
+        assert_eq!(counters.opac, k as u64);
+        assert_eq!(counters.conversions, (k * (m + n) + m * n) as u64);
+        assert_eq!(counters.bytes_to_device, (k * (m + n)) as u64);
+        assert_eq!(counters.bytes_to_host, (m * n * size_of::<AccT>()) as u64);
+        assert_eq!(summary.cost, counters.cost(&cost_table()));
+        assert!(summary.cost.cycles > 0. && summary.cost.energy > 0.);
+
+        let x = Array1::from_elem(DIMENSION + 1, 3);
+        let (_, summary) = costed(|| vec_cmp_lt(x.view(), vec_add(x.view(), x.view()).view()));
+        assert_eq!(summary.counters.vectors.arithmetic, 2);
+        assert_eq!(summary.counters.vectors.compare, 2);
+        assert_eq!(summary.counters.bytes_to_host, 2 * (DIMENSION as u64 + 1));
+        assert_eq!(summary.counters.conversions, 0);
+    }
+
+    #[test]
+    fn vector_ops_any_length() {
+        let len = 2 * DIMENSION + 7;
+        let a = Array1::from_iter((0..len).map(|i| (i % 256) as u8 as ChipT));
+        let b = Array1::from_iter((0..len).map(|i| (i * 7 % 256) as u8 as ChipT));
+
+        let sum = vec_add_sat(a.view(), b.view());
+        let low = vec_min(a.view(), b.view());
+        let mask = vec_cmp_lt(a.view(), b.view());
+        let selected = vec_select(mask.view(), a.view(), b.view());
+        assert_eq!(sum.len(), len);
+        for i in 0..len {
+            assert_eq!(sum[i], a[i].saturating_add(b[i]));
+            assert_eq!(low[i], a[i].min(b[i]));
+            assert_eq!(selected[i], low[i]);
+        }
+        assert_eq!(vec_neg(a.view()).len(), len);
+    }
+
+    #[test]
+    #[should_panic(expected = "Reduction of empty vector")]
+    fn argmax_of_empty() {
+        vec_argmax(Array1::<ChipT>::zeros(0).view());
+    }
+
+    #[test]
+    fn reductions_any_length() {
+        let len = 3 * DIMENSION + 5;
+        let a = Array1::from_iter((0..len).map(|i| ((i * 13 + 5) % 251) as u8 as ChipT));
+        let b = Array1::from_iter((0..len).map(|i| ((i * 7) % 256) as u8 as ChipT));
+
+        assert_eq!(vec_sum(a.view()), a.iter().map(|x| *x as i64).sum::<i64>());
+        assert_eq!(vec_dot(a.view(), b.view()), a.iter().zip(&b).map(|(x, y)| *x as i64 * *y as i64).sum::<i64>());
+        assert_eq!(vec_reduce_max(a.view()), *a.iter().max().unwrap());
+        assert_eq!(vec_reduce_min(a.view()), *a.iter().min().unwrap());
+        assert_eq!(vec_argmax(a.view()), a.iter().position(|x| *x == *a.iter().max().unwrap()).unwrap());
+        assert_eq!(vec_argmin(a.view()), a.iter().position(|x| *x == *a.iter().min().unwrap()).unwrap());
+
+        let mut acc: ChipT = 0;
+        let mut acc_max = ChipT::MIN;
+        let inclusive = vec_prefix_sum(a.view(), Overflow::Saturate);
+        let exclusive = vec_prefix_sum_exclusive(a.view(), Overflow::Saturate);
+        let running_max = vec_prefix_max(a.view());
+        for i in 0..len {
+            assert_eq!(exclusive[i], acc);
+            acc = acc.saturating_add(a[i]);
+            acc_max = acc_max.max(a[i]);
+            assert_eq!(inclusive[i], acc);
+            assert_eq!(running_max[i], acc_max);
+        }
+    }
+
+    #[test]
+    fn masked_any_length() {
+        let len = 2 * DIMENSION + 7;
+        let a = Array1::from_iter((0..len).map(|i| ((i * 13 + 5) % 251) as u8 as ChipT));
+        let b = Array1::from_iter((0..len).map(|i| ((i * 7) % 256) as u8 as ChipT));
+        let dst = Array1::from_elem(len, 3 as ChipT);
+        // Even elements are active, odd ones are excluded
+        let mask = Array1::from_iter((0..len).map(|i| if i % 2 == 0 { -1 } else { 0 }));
+        let even = |i: &usize| i.is_multiple_of(2);
+
+        let sum = vec_binary_masked(a.view(), b.view(), mask.view(), Some(dst.view()), intrinsics::v_add_masked);
+        let neg = vec_unary_masked(a.view(), mask.view(), None, intrinsics::v_neg_masked);
+        for i in 0..len {
+            assert_eq!(sum[i], if even(&i) { a[i].wrapping_add(b[i]) } else { 3 });
+            assert_eq!(neg[i], if even(&i) { a[i].wrapping_neg() } else { 0 });
+        }
+
+        let active = || (0..len).filter(even);
+        assert_eq!(vec_sum_masked(a.view(), mask.view()), active().map(|i| a[i] as i64).sum::<i64>());
+        assert_eq!(
+            vec_dot_masked(a.view(), b.view(), mask.view()),
+            active().map(|i| a[i] as i64 * b[i] as i64).sum::<i64>()
+        );
+        let max = active().map(|i| a[i]).max().unwrap();
+        let min = active().map(|i| a[i]).min().unwrap();
+        assert_eq!(vec_reduce_max_masked(a.view(), mask.view()), max);
+        assert_eq!(vec_reduce_min_masked(a.view(), mask.view()), min);
+        assert_eq!(vec_argmax_masked(a.view(), mask.view()), active().find(|i| a[*i] == max));
+        assert_eq!(vec_argmin_masked(a.view(), mask.view()), active().find(|i| a[*i] == min));
+        assert_eq!(vec_argmax_masked(a.view(), Array1::zeros(len).view()), None);
+    }
 }
@@ -0,0 +1,158 @@
+//! Scaled dot-product attention: `softmax(Q * K^T / sqrt(d) + mask) * V`.
+//!
+//! Both products run on `OPAC` through `mat_mul`, softmax runs on vector units.
+//! Heads (and batches) are given along the first axis and processed one by one.
+//!
+//! Precision is limited by probabilities, which have 7 fractional bits:
+//! for a dozen of keys and `|V| <= 1` results are within `0.08` of `f32` attention.
+
+use ndarray::{s, Array2, Array3, ArrayView1, ArrayView2, ArrayView3, Axis};
+use crate::intrinsics::counters::{counted, Counters};
+use crate::intrinsics::intrinsics::{ChipT, MASK_FALSE, MASK_TRUE};
+use crate::intrinsics::lut::Lut;
+use crate::intrinsics::quant::{range_factor, Quantizer};
+use crate::intrinsics::wrappers::mat_mul;
+use super::norm::{quantize, softmax_recip, softmax_row, EXP_SCALE, SOFTMAX_OUTPUT_SCALE};
+
+pub enum AttentionMask<'a> {
+    None,
+    /// Query `i` sees keys up to `i`, queries are aligned with the last keys
+    Causal,
+    /// `[heads, keys]`, key is visible when `true`
+    Padding(ArrayView2<'a, bool>),
+}
+
+impl AttentionMask<'_> {
+    /// Mask lanes of scores row of query `i` of `head`
+    fn row(&self, head: usize, i: usize, queries: usize, keys: usize) -> Option<Vec<ChipT>> {
+        let lane = |x: bool| if x { MASK_TRUE } else { MASK_FALSE };
+        match self {
+            AttentionMask::None => None,
+            AttentionMask::Causal => {
+                let last = i + keys - queries;
+                Some((0..keys).map(|j| lane(j <= last)).collect())
+            }
+            AttentionMask::Padding(valid) => Some(valid.row(head).iter().map(|x| lane(*x)).collect()),
+        }
+    }
+}
+
+/// Attention of a single head
+fn attention_head(q: ArrayView2<f32>, k: ArrayView2<f32>, v: ArrayView2<f32>, mask: &AttentionMask, head: usize) -> Array2<f32> {
+    let (queries, keys) = (q.nrows(), k.nrows());
+    let (fq, fk, fv) = (range_factor(q), range_factor(k), range_factor(v));
+
+    let scale = fq * fk / (q.ncols() as f32).sqrt();
+    let scores = mat_mul((&q / fq).view(), (&k / fk).view()) * scale;
+
+    // Softmax input step is chosen from actual range of scores
+    let input = Quantizer::for_values(&scores);
+    let exp = Lut::exp(input, Quantizer::new(EXP_SCALE));
+    let recip = softmax_recip();
+
+    // Probabilities are already in `OPAC` operand format, so it's lossless to pass them as f32
+    let mut probs = Array2::zeros((queries, keys));
+    for (i, row) in scores.axis_iter(Axis(0)).enumerate() {
+        let mask_row = mask.row(head, i, queries, keys);
+        let p = softmax_row(
+            quantize(row, input).view(),
+            mask_row.as_ref().map(|m| ArrayView1::from(m.as_slice())),
+            &exp,
+            &recip,
+        );
+        probs.row_mut(i).assign(&p.map(|x| *x as f32 * SOFTMAX_OUTPUT_SCALE));
+    }
+    mat_mul(probs.view(), (&v.t() / fv).view()) * fv
+}
+
+/// Multi-head attention. `q` is `[heads, queries, d]`, `k` is `[heads, keys, d]`,
+/// `v` is `[heads, keys, d_v]`, result is `[heads, queries, d_v]`.
+/// Returns device instructions used, to estimate cost of transformer layer.
+pub fn attention(q: ArrayView3<f32>, k: ArrayView3<f32>, v: ArrayView3<f32>, mask: AttentionMask) -> (Array3<f32>, Counters) {
+    let (heads, queries, d) = q.dim();
+    assert_eq!(k.dim().0, heads);
+    assert_eq!(v.dim().0, heads);
+    assert_eq!(k.dim().2, d);
+    assert_eq!(k.dim().1, v.dim().1);
+    assert!(queries <= k.dim().1 || !matches!(mask, AttentionMask::Causal));
+    if let AttentionMask::Padding(valid) = &mask {
+        assert_eq!(valid.dim(), (heads, k.dim().1));
+    }
+
+    counted(|| {
+        let mut res = Array3::zeros((heads, queries, v.dim().2));
+        for h in 0..heads {
+            let out = attention_head(q.slice(s![h, .., ..]), k.slice(s![h, .., ..]), v.slice(s![h, .., ..]), &mask, h);
+            res.slice_mut(s![h, .., ..]).assign(&out);
+        }
+        res
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(shape: (usize, usize, usize), seed: usize) -> Array3<f32> {
+        Array3::from_shape_fn(shape, |(h, i, j)| {
+            let x = ((h * 7 + i * 7919 + j * 104729 + seed * 31) % 1000) as f32 / 1000.;
+            2. * x - 1.
+        })
+    }
+
+    fn reference(q: &Array3<f32>, k: &Array3<f32>, v: &Array3<f32>, visible: impl Fn(usize, usize, usize) -> bool) -> Array3<f32> {
+        let (heads, queries, d) = q.dim();
+        let mut res = Array3::zeros((heads, queries, v.dim().2));
+        for h in 0..heads {
+            let qh = q.slice(s![h, .., ..]);
+            let kh = k.slice(s![h, .., ..]);
+            let mut scores = qh.dot(&kh.t()) / (d as f32).sqrt();
+            for ((i, j), x) in scores.indexed_iter_mut() {
+                *x = if visible(h, i, j) { x.exp() } else { 0. };
+            }
+            for mut row in scores.axis_iter_mut(Axis(0)) {
+                let sum = row.sum();
+                row /= sum;
+            }
+            res.slice_mut(s![h, .., ..]).assign(&scores.dot(&v.slice(s![h, .., ..])));
+        }
+        res
+    }
+
+    fn max_diff(a: &Array3<f32>, b: &Array3<f32>) -> f32 {
+        (a - b).iter().fold(0., |acc, x| acc.max(x.abs()))
+    }
+
+    /// Dominated by 7-bit probabilities, each of them is off by up to half of a step
+    const TOLERANCE: f32 = 0.08;
+
+    #[test]
+    fn matches_f32() {
+        let (heads, seq, d) = (2, 12, 8);
+        let q = sample((heads, seq, d), 1) * 2.;
+        let k = sample((heads, seq, d), 2) * 2.;
+        let v = sample((heads, seq, d), 3);
+
+        let (res, _) = attention(q.view(), k.view(), v.view(), AttentionMask::None);
+        assert!(max_diff(&res, &reference(&q, &k, &v, |_, _, _| true)) < TOLERANCE);
+
+        let (res, _) = attention(q.view(), k.view(), v.view(), AttentionMask::Causal);
+        assert!(max_diff(&res, &reference(&q, &k, &v, |_, i, j| j <= i)) < TOLERANCE);
+
+        let valid = Array2::from_shape_fn((heads, seq), |(h, j)| j < seq - 3 * h - 1);
+        let (res, _) = attention(q.view(), k.view(), v.view(), AttentionMask::Padding(valid.view()));
+        assert!(max_diff(&res, &reference(&q, &k, &v, |h, _, j| valid[[h, j]])) < TOLERANCE);
+    }
+
+    #[test]
+    fn reports_cost() {
+        let (heads, queries, keys, d) = (3, 4, 6, 5);
+        let q = sample((heads, queries, d), 1);
+        let k = sample((heads, keys, d), 2);
+        let v = sample((heads, keys, d), 3);
+        let (_, counters) = attention(q.view(), k.view(), v.view(), AttentionMask::Causal);
+        // One pass per common dimension of each product
+        assert_eq!(counters.opac, (heads * (d + keys)) as u64);
+        assert!(counters.vector > 0);
+    }
+}
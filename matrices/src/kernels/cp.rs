@@ -0,0 +1,161 @@
+//! CP decomposition of 3-way tensors by alternating least squares.
+//!
+//! The MTTKRP step, unfolded tensor times Khatri-Rao product of the other two factors,
+//! runs on `OPAC`: Khatri-Rao products with `kron::khatri_rao` and the product with `mat_mul`.
+//! Normal equations are only `rank` by `rank`, so they are solved on host.
+
+use ndarray::{Array1, Array2, Array3, ArrayView2, ArrayView3, Axis};
+use crate::intrinsics::kron::khatri_rao;
+use crate::intrinsics::quant::range_factor;
+use crate::intrinsics::wrappers::mat_mul;
+use super::eigen::symmetric_eigen;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpConfig {
+    pub rank: usize,
+    pub max_iter: usize,
+    /// Stops when fit changes less than this between iterations
+    pub tol: f32,
+}
+
+impl CpConfig {
+    pub fn new(rank: usize) -> Self {
+        CpConfig { rank, max_iter: 100, tol: 1e-4 }
+    }
+}
+
+/// `x[i, j, k] = sum_r weights[r] * a[i, r] * b[j, r] * c[k, r]`, factors have unit columns
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpModel {
+    pub weights: Array1<f32>,
+    pub factors: [Array2<f32>; 3],
+    /// `1 - |x - reconstruction| / |x|` by iteration
+    pub fits: Vec<f32>,
+}
+
+impl CpModel {
+    pub fn reconstruct(&self) -> Array3<f32> {
+        let [a, b, c] = &self.factors;
+        Array3::from_shape_fn((a.nrows(), b.nrows(), c.nrows()), |(i, j, k)| {
+            (0..self.weights.len()).map(|r| self.weights[r] * a[[i, r]] * b[[j, r]] * c[[k, r]]).sum()
+        })
+    }
+
+    pub fn fit(&self) -> f32 {
+        self.fits.last().copied().unwrap_or(0.)
+    }
+}
+
+/// Mode-`mode` unfolding, columns run over the other modes with the lower one fastest,
+/// so they match rows of `khatri_rao(later, earlier)`
+fn unfold(x: ArrayView3<f32>, mode: usize) -> Array2<f32> {
+    let (d0, d1, d2) = x.dim();
+    match mode {
+        0 => Array2::from_shape_fn((d0, d1 * d2), |(i, col)| x[[i, col % d1, col / d1]]),
+        1 => Array2::from_shape_fn((d1, d0 * d2), |(j, col)| x[[col % d0, j, col / d0]]),
+        2 => Array2::from_shape_fn((d2, d0 * d1), |(k, col)| x[[col % d0, col / d0, k]]),
+        _ => panic!("Tensor has 3 modes"),
+    }
+}
+
+/// Unfolded tensor times Khatri-Rao product of the other factors, on `OPAC`
+fn mttkrp(unfolded: ArrayView2<f32>, factors: &[Array2<f32>; 3], mode: usize) -> Array2<f32> {
+    let (earlier, later) = match mode {
+        0 => (&factors[1], &factors[2]),
+        1 => (&factors[0], &factors[2]),
+        _ => (&factors[0], &factors[1]),
+    };
+    let kr = khatri_rao(later.view(), earlier.view());
+    let (fx, fk) = (range_factor(unfolded), range_factor(kr.view()));
+    mat_mul(&unfolded / fx, (&kr / fk).t()) * (fx * fk)
+}
+
+/// Pseudo-inverse of symmetric positive semi-definite `v`
+fn pinv(v: ArrayView2<f32>) -> Array2<f32> {
+    let (values, vectors) = symmetric_eigen(v);
+    let cutoff = values.iter().fold(0f32, |acc, x| acc.max(x.abs())) * 1e-6;
+    let inverse = values.mapv(|x| if x.abs() > cutoff { 1. / x } else { 0. });
+    (&vectors * &inverse).dot(&vectors.t())
+}
+
+/// Rank `config.rank` CP decomposition of `x`
+pub fn cp_als(x: ArrayView3<f32>, config: &CpConfig) -> CpModel {
+    assert!(config.rank > 0, "Rank must be positive");
+    let (d0, d1, d2) = x.dim();
+    let unfolded = [unfold(x, 0), unfold(x, 1), unfold(x, 2)];
+    // Deterministic, well spread initial factors
+    let init = |rows: usize, mode: usize| {
+        Array2::from_shape_fn((rows, config.rank), |(i, r)| ((i * 7 + r * 13 + mode * 5 + 1) as f32 * 0.618).sin())
+    };
+    let mut model = CpModel {
+        weights: Array1::ones(config.rank),
+        factors: [init(d0, 0), init(d1, 1), init(d2, 2)],
+        fits: Vec::new(),
+    };
+    let norm_x = x.iter().map(|v| v * v).sum::<f32>().sqrt();
+    for _ in 0..config.max_iter {
+        for (mode, unfolded) in unfolded.iter().enumerate() {
+            let mut v = Array2::<f32>::ones((config.rank, config.rank));
+            for (other, factor) in model.factors.iter().enumerate() {
+                if other != mode {
+                    v *= &factor.t().dot(factor);
+                }
+            }
+            let mut factor = mttkrp(unfolded.view(), &model.factors, mode).dot(&pinv(v.view()));
+            let norms = factor.map_axis(Axis(0), |col| col.dot(&col).sqrt());
+            factor /= &norms.mapv(|n| if n > 0. { n } else { 1. });
+            model.weights = norms;
+            model.factors[mode] = factor;
+        }
+        let error = (&x - &model.reconstruct()).iter().map(|v| v * v).sum::<f32>().sqrt();
+        let fit = if norm_x > 0. { 1. - error / norm_x } else { 1. };
+        let done = model.fits.last().is_some_and(|prev| (fit - prev).abs() < config.tol);
+        model.fits.push(fit);
+        if done {
+            break;
+        }
+    }
+    model
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn low_rank() -> (Array3<f32>, [Array2<f32>; 3]) {
+        let factor = |rows: usize, seed: usize| {
+            Array2::from_shape_fn((rows, 2), |(i, r)| ((i * 5 + r * 11 + seed) % 7) as f32 / 3. - 1.)
+        };
+        let factors = [factor(6, 1), factor(5, 2), factor(4, 3)];
+        let model = CpModel { weights: Array1::ones(2), factors: factors.clone(), fits: vec![] };
+        (model.reconstruct(), factors)
+    }
+
+    #[test]
+    fn unfolding_matches_khatri_rao() {
+        let (x, [a, b, c]) = low_rank();
+        // Exact rank 2 tensor: X_(n) = F_n * (KR of the others)^T
+        let expected = [
+            a.dot(&khatri_rao(c.view(), b.view()).t()),
+            b.dot(&khatri_rao(c.view(), a.view()).t()),
+            c.dot(&khatri_rao(b.view(), a.view()).t()),
+        ];
+        for (mode, expected) in expected.iter().enumerate() {
+            let res = unfold(x.view(), mode);
+            assert!(res.iter().zip(expected).all(|(x, y)| (x - y).abs() < 0.05), "mode {}", mode);
+        }
+    }
+
+    #[test]
+    fn recovers_low_rank() {
+        let (x, _) = low_rank();
+        let model = cp_als(x.view(), &CpConfig::new(2));
+        assert!(model.fit() > 0.97, "{:?}", model.fits);
+        assert!(model.fits.len() < CpConfig::new(2).max_iter);
+        for factor in &model.factors {
+            for col in factor.columns() {
+                assert!((col.dot(&col) - 1.).abs() < 1e-4);
+            }
+        }
+    }
+}
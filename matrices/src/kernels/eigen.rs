@@ -0,0 +1,299 @@
+//! Eigen solvers for symmetric matrices: power iteration, top-k by deflation, and Lanczos.
+//!
+//! Matrix-vector products run on `OPAC` through `mat_mul`, orthogonalization
+//! and small dense problems run on host. Every matvec is quantized to 8 bits,
+//! so residuals stall at a noise floor of a few percent of `|A|`;
+//! `Matvec::Host` runs the same algorithms with exact products for comparison.
+
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, Axis};
+use crate::intrinsics::quant::range_factor;
+use crate::intrinsics::wrappers::mat_mul;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Matvec {
+    /// Emulated `OPAC` path, with 8-bit operands
+    Opac,
+    /// Exact `f32` product on host
+    Host,
+}
+
+/// `A` brought into operand range once, with its scale
+struct Operator {
+    a: Array2<f32>,
+    scale: f32,
+    matvec: Matvec,
+}
+
+impl Operator {
+    fn new(a: ArrayView2<f32>, matvec: Matvec) -> Self {
+        assert_eq!(a.nrows(), a.ncols(), "Matrix must be square");
+        let scale = range_factor(a.iter());
+        Operator { a: a.to_owned() / scale, scale, matvec }
+    }
+
+    fn apply(&self, x: ArrayView1<f32>) -> Array1<f32> {
+        match self.matvec {
+            Matvec::Opac => {
+                let factor = range_factor(x.iter());
+                let x = (&x / factor).insert_axis(Axis(0));
+                mat_mul(self.a.view(), x.view()).remove_axis(Axis(1)) * (self.scale * factor)
+            }
+            Matvec::Host => self.a.dot(&x) * self.scale,
+        }
+    }
+}
+
+fn norm(x: ArrayView1<f32>) -> f32 {
+    x.dot(&x).sqrt()
+}
+
+/// Removes components along orthonormal `basis`
+fn orthogonalize(x: &mut Array1<f32>, basis: &[Array1<f32>]) {
+    for v in basis {
+        let projection = v.dot(x);
+        x.scaled_add(-projection, v);
+    }
+}
+
+/// Eigenvalue estimates and relative residuals `|A x - l x| / |l|` by iteration
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Convergence {
+    pub eigenvalues: Vec<f32>,
+    pub residuals: Vec<f32>,
+    pub converged: bool,
+}
+
+impl Convergence {
+    pub fn iterations(&self) -> usize {
+        self.eigenvalues.len()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EigenPair {
+    pub value: f32,
+    /// Unit vector
+    pub vector: Array1<f32>,
+    pub convergence: Convergence,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PowerConfig {
+    pub max_iter: usize,
+    /// Relative residual to stop at
+    pub tol: f32,
+    pub matvec: Matvec,
+}
+
+impl Default for PowerConfig {
+    /// Tolerance is above the noise floor of 8-bit matvecs
+    fn default() -> Self {
+        PowerConfig { max_iter: 300, tol: 0.05, matvec: Matvec::Opac }
+    }
+}
+
+/// Deterministic start vector, not orthogonal to typical eigenvectors
+fn start_vector(n: usize) -> Array1<f32> {
+    let x = Array1::from_shape_fn(n, |i| 1. + (i as f32 * 0.7).sin() * 0.5);
+    let len = norm(x.view());
+    x / len
+}
+
+fn power(op: &Operator, found: &[Array1<f32>], config: &PowerConfig) -> EigenPair {
+    let n = op.a.nrows();
+    let mut x = start_vector(n);
+    orthogonalize(&mut x, found);
+    let mut convergence = Convergence::default();
+    let mut value = 0.;
+    for _ in 0..config.max_iter {
+        let mut y = op.apply(x.view());
+        orthogonalize(&mut y, found);
+        value = x.dot(&y);
+        let residual = norm((&y - &(&x * value)).view()) / value.abs().max(f32::MIN_POSITIVE);
+        convergence.eigenvalues.push(value);
+        convergence.residuals.push(residual);
+        let len = norm(y.view());
+        if len == 0. {
+            break;
+        }
+        x = y / len;
+        if residual < config.tol {
+            convergence.converged = true;
+            break;
+        }
+    }
+    EigenPair { value, vector: x, convergence }
+}
+
+/// Eigenpair of the largest by magnitude eigenvalue of symmetric `a`
+pub fn power_iteration(a: ArrayView2<f32>, config: &PowerConfig) -> EigenPair {
+    power(&Operator::new(a, config.matvec), &[], config)
+}
+
+/// `k` largest by magnitude eigenpairs of symmetric `a`.
+/// Deflation projects found eigenvectors out of every iterate on host,
+/// so matvecs keep using the original matrix
+pub fn top_k(a: ArrayView2<f32>, k: usize, config: &PowerConfig) -> Vec<EigenPair> {
+    assert!(k <= a.nrows());
+    let op = Operator::new(a, config.matvec);
+    let mut res: Vec<EigenPair> = Vec::with_capacity(k);
+    for _ in 0..k {
+        let found: Vec<Array1<f32>> = res.iter().map(|p| p.vector.clone()).collect();
+        res.push(power(&op, &found, config));
+    }
+    res
+}
+
+/// Eigenvalues in descending order and eigenvectors in columns,
+/// by cyclic Jacobi rotations on host. For small and reference problems
+pub fn symmetric_eigen(a: ArrayView2<f32>) -> (Array1<f32>, Array2<f32>) {
+    let n = a.nrows();
+    assert_eq!(n, a.ncols(), "Matrix must be square");
+    let mut m = a.mapv(f64::from);
+    let mut v = Array2::<f64>::eye(n);
+    for _ in 0..100 {
+        let off: f64 = (0..n).flat_map(|i| (0..n).filter(move |j| *j != i).map(move |j| (i, j))).map(|(i, j)| m[[i, j]].powi(2)).sum();
+        if off < 1e-20 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                if m[[p, q]].abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (m[[q, q]] - m[[p, p]]) / (2. * m[[p, q]]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.).sqrt());
+                let (c, s) = (1. / (t * t + 1.).sqrt(), t / (t * t + 1.).sqrt());
+                for k in 0..n {
+                    let (mkp, mkq) = (m[[k, p]], m[[k, q]]);
+                    m[[k, p]] = c * mkp - s * mkq;
+                    m[[k, q]] = s * mkp + c * mkq;
+                }
+                for k in 0..n {
+                    let (mpk, mqk) = (m[[p, k]], m[[q, k]]);
+                    m[[p, k]] = c * mpk - s * mqk;
+                    m[[q, k]] = s * mpk + c * mqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[[k, p]], v[[k, q]]);
+                    v[[k, p]] = c * vkp - s * vkq;
+                    v[[k, q]] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|i, j| m[[*j, *j]].total_cmp(&m[[*i, *i]]));
+    let values = Array1::from_iter(order.iter().map(|i| m[[*i, *i]] as f32));
+    let vectors = Array2::from_shape_fn((n, n), |(r, c)| v[[r, order[c]]] as f32);
+    (values, vectors)
+}
+
+/// Ritz pairs of symmetric `a` after `steps` Lanczos iterations, in descending order.
+/// Lanczos vectors are fully reorthogonalized on host, because quantized matvecs
+/// lose orthogonality fast. Convergence of each pair holds its residual estimate
+/// `|beta * s_last|`, relative to the Ritz value, and is converged with default `PowerConfig` tolerance
+pub fn lanczos(a: ArrayView2<f32>, steps: usize, matvec: Matvec) -> Vec<EigenPair> {
+    let n = a.nrows();
+    let steps = steps.min(n);
+    assert!(steps > 0);
+    let op = Operator::new(a, matvec);
+    let mut basis = vec![start_vector(n)];
+    let (mut alpha, mut beta) = (Vec::new(), Vec::new());
+    for j in 0..steps {
+        let mut w = op.apply(basis[j].view());
+        alpha.push(basis[j].dot(&w));
+        // Twice is enough, to keep the basis orthogonal to working precision
+        orthogonalize(&mut w, &basis);
+        orthogonalize(&mut w, &basis);
+        let b = norm(w.view());
+        beta.push(b);
+        if j + 1 == steps || b < 1e-6 {
+            break;
+        }
+        basis.push(w / b);
+    }
+
+    let m = alpha.len();
+    let mut t = Array2::zeros((m, m));
+    for i in 0..m {
+        t[[i, i]] = alpha[i];
+        if i + 1 < m {
+            t[[i, i + 1]] = beta[i];
+            t[[i + 1, i]] = beta[i];
+        }
+    }
+    let (values, s) = symmetric_eigen(t.view());
+    let last_beta = beta[m - 1];
+    (0..m)
+        .map(|i| {
+            let vector = basis.iter().enumerate().fold(Array1::zeros(n), |acc, (j, v)| acc + v * s[[j, i]]);
+            let residual = (last_beta * s[[m - 1, i]]).abs() / values[i].abs().max(f32::MIN_POSITIVE);
+            let converged = residual < PowerConfig::default().tol;
+            let convergence = Convergence { eigenvalues: vec![values[i]], residuals: vec![residual], converged };
+            EigenPair { value: values[i], vector, convergence }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Symmetric matrix with eigenvalues `values` and a fixed rotation of eigenvectors
+    fn with_spectrum(values: &[f32]) -> Array2<f32> {
+        let n = values.len();
+        let h = Array2::from_shape_fn((n, n), |(i, j)| ((i * 7 + j * 13) % 11) as f32 / 11. - 0.5);
+        let (_, q) = symmetric_eigen((&h + &h.t()).view());
+        let d = Array2::from_diag(&Array1::from_vec(values.to_vec()));
+        q.dot(&d).dot(&q.t())
+    }
+
+    #[test]
+    fn jacobi_reference() {
+        let a = with_spectrum(&[3., -2., 1., 0.5]);
+        let (values, vectors) = symmetric_eigen(a.view());
+        for (x, y) in values.iter().zip([3., 1., 0.5, -2.]) {
+            assert!((x - y).abs() < 1e-4, "{} {}", x, y);
+        }
+        let reconstructed = vectors.dot(&Array2::from_diag(&values)).dot(&vectors.t());
+        assert!(reconstructed.iter().zip(&a).all(|(x, y)| (x - y).abs() < 1e-4));
+    }
+
+    #[test]
+    fn power_and_deflation() {
+        let a = with_spectrum(&[4., -2.5, 1.2, 0.6, 0.3, 0.1, 0.05, 0.02]);
+        let top = power_iteration(a.view(), &PowerConfig::default());
+        assert!(top.convergence.converged);
+        assert!((top.value - 4.).abs() < 0.1, "{}", top.value);
+
+        let pairs = top_k(a.view(), 3, &PowerConfig::default());
+        for (pair, expected) in pairs.iter().zip([4., -2.5, 1.2]) {
+            assert!((pair.value - expected).abs() < 0.1 * expected.abs(), "{} {}", pair.value, expected);
+        }
+        // Eigenvectors from deflation are orthogonal
+        assert!(pairs[0].vector.dot(&pairs[1].vector).abs() < 1e-3);
+
+        // Exact matvecs converge further than 8-bit ones
+        let config = PowerConfig { tol: 1e-4, max_iter: 500, ..Default::default() };
+        let opac = power_iteration(a.view(), &config);
+        let host = power_iteration(a.view(), &PowerConfig { matvec: Matvec::Host, ..config });
+        assert!(host.convergence.converged);
+        let floor = |c: &Convergence| c.residuals.iter().fold(f32::MAX, |acc, r| acc.min(*r));
+        assert!(floor(&host.convergence) < floor(&opac.convergence));
+    }
+
+    #[test]
+    fn lanczos_extremes() {
+        let values = [5., 3., -4., 1., 0.5, 0.25, -0.2, 0.1, 0.05, 0.02];
+        let a = with_spectrum(&values);
+        let host = lanczos(a.view(), 10, Matvec::Host);
+        assert!((host[0].value - 5.).abs() < 1e-3);
+        assert!((host.last().unwrap().value + 4.).abs() < 1e-3);
+
+        let opac = lanczos(a.view(), 10, Matvec::Opac);
+        assert!((opac[0].value - 5.).abs() < 0.15, "{}", opac[0].value);
+        assert!((opac.last().unwrap().value + 4.).abs() < 0.15, "{}", opac.last().unwrap().value);
+        assert!((norm(opac[0].vector.view()) - 1.).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,506 @@
+//! Lazy matrix expressions with fused evaluation.
+//!
+//! Expression like `relu(A * B^T + bias) * C` is planned before it runs: every product
+//! becomes a `Gemm` stage, run by the `mat_mul` blocked loop. A following bias addition is fused
+//! into the stage by preloading the accumulator, `relu` clamps accumulator cells at readout when
+//! the common dimension fits one block, and results consumed by another product are quantized once
+//! and stay in chip format. The rest of elementwise work runs on host.
+//! Input operands of products must be in `OPAC` operand range, as for `mat_mul`.
+
+use std::fmt;
+use std::ops::{Add, Range};
+use ndarray::{s, Array2, ArrayView2};
+use crate::intrinsics::config::DIMENSION;
+use crate::intrinsics::counters::count_conversions;
+use crate::intrinsics::intrinsics::{AccT, ChipT, Matrix};
+use crate::intrinsics::quant::{max_abs, Quantizer};
+use crate::intrinsics::wrappers::{self, mat_mul_operands_with, Epilogue};
+
+/// Value of `f32` operand, which `Array1D` conversion uses
+const INPUT_SCALE: f32 = 1. / 128.;
+
+enum Node<'a> {
+    Input(ArrayView2<'a, f32>),
+    /// `a * b^T`, as `mat_mul`
+    MatMulT(Expr<'a>, Expr<'a>),
+    Add(Expr<'a>, Expr<'a>),
+    Relu(Expr<'a>),
+    Transpose(Expr<'a>),
+}
+
+/// Lazy expression over ndarray views
+pub struct Expr<'a>(Box<Node<'a>>);
+
+impl<'a> Expr<'a> {
+    pub fn input(x: ArrayView2<'a, f32>) -> Self {
+        Expr(Box::new(Node::Input(x)))
+    }
+
+    /// `self * other^T`
+    pub fn mat_mul_t(self, other: Expr<'a>) -> Self {
+        Expr(Box::new(Node::MatMulT(self, other)))
+    }
+
+    /// `self * other`
+    pub fn dot(self, other: Expr<'a>) -> Self {
+        self.mat_mul_t(other.t())
+    }
+
+    pub fn relu(self) -> Self {
+        Expr(Box::new(Node::Relu(self)))
+    }
+
+    pub fn t(self) -> Self {
+        Expr(Box::new(Node::Transpose(self)))
+    }
+
+    pub fn plan(&self) -> Plan<'a> {
+        let mut plan = Plan { inputs: Vec::new(), stages: Vec::new(), output: Value::Input(0) };
+        plan.output = plan.lower(self);
+        plan.mark_quantized();
+        plan
+    }
+
+    pub fn eval(&self) -> Array2<f32> {
+        self.plan().run()
+    }
+
+    /// Chosen plan with its `OPAC` and conversion counts
+    pub fn explain(&self) -> String {
+        self.plan().to_string()
+    }
+}
+
+impl<'a> Add for Expr<'a> {
+    type Output = Self;
+
+    /// Elementwise sum, operand with a single row is broadcast over rows
+    fn add(self, other: Expr<'a>) -> Self {
+        Expr(Box::new(Node::Add(self, other)))
+    }
+}
+
+/// Value of plan: input view or result of a stage
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Value {
+    Input(usize),
+    Stage(usize),
+}
+
+/// Possibly transposed value
+#[derive(Clone, Copy, Debug)]
+struct Operand {
+    value: Value,
+    transposed: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum HostOp {
+    Add,
+    Relu,
+    /// Transposed view of a value, free on host
+    View,
+}
+
+enum Stage {
+    Gemm {
+        a: Operand,
+        b: Operand,
+        /// Preloaded into accumulator
+        bias: Option<Operand>,
+        relu: bool,
+        /// Result is consumed by another `Gemm`, so it's kept in chip format
+        quantized: bool,
+    },
+    Host { op: HostOp, args: Vec<Operand> },
+}
+
+/// Evaluation plan of `Expr`
+pub struct Plan<'a> {
+    inputs: Vec<ArrayView2<'a, f32>>,
+    stages: Vec<Stage>,
+    output: Value,
+}
+
+impl Operand {
+    fn of(value: Value) -> Self {
+        Operand { value, transposed: false }
+    }
+}
+
+impl<'a> Plan<'a> {
+    /// Lowers `expr`, with its transposes collapsed into the operand
+    fn operand(&mut self, expr: &Expr<'a>) -> Operand {
+        match expr.0.as_ref() {
+            Node::Transpose(inner) => {
+                let x = self.operand(inner);
+                Operand { transposed: !x.transposed, ..x }
+            }
+            _ => Operand::of(self.lower(expr)),
+        }
+    }
+
+    fn lower(&mut self, expr: &Expr<'a>) -> Value {
+        match expr.0.as_ref() {
+            Node::Input(x) => {
+                self.inputs.push(*x);
+                Value::Input(self.inputs.len() - 1)
+            }
+            Node::MatMulT(a, b) => {
+                let (a, b) = (self.operand(a), self.operand(b));
+                self.push(Stage::Gemm { a, b, bias: None, relu: false, quantized: false })
+            }
+            Node::Add(x, y) => {
+                let (x, y) = (self.operand(x), self.operand(y));
+                for (gemm, other) in [(x, y), (y, x)] {
+                    if let (false, Value::Stage(id)) = (gemm.transposed, gemm.value) {
+                        if let Stage::Gemm { bias: bias @ None, relu: false, .. } = &mut self.stages[id] {
+                            *bias = Some(other);
+                            return gemm.value;
+                        }
+                    }
+                }
+                self.push(Stage::Host { op: HostOp::Add, args: vec![x, y] })
+            }
+            Node::Relu(x) => {
+                let x = self.operand(x);
+                if let (false, Value::Stage(id)) = (x.transposed, x.value) {
+                    // Readout of earlier blocks of the common dimension is summed on host
+                    let one_block = matches!(&self.stages[id], Stage::Gemm { a, .. } if self.shape(*a).1 <= DIMENSION);
+                    if let (true, Stage::Gemm { relu: relu @ false, .. }) = (one_block, &mut self.stages[id]) {
+                        *relu = true;
+                        return x.value;
+                    }
+                }
+                self.push(Stage::Host { op: HostOp::Relu, args: vec![x] })
+            }
+            Node::Transpose(_) => {
+                let x = self.operand(expr);
+                if !x.transposed {
+                    return x.value;
+                }
+                self.push(Stage::Host { op: HostOp::View, args: vec![x] })
+            }
+        }
+    }
+
+    fn push(&mut self, stage: Stage) -> Value {
+        self.stages.push(stage);
+        Value::Stage(self.stages.len() - 1)
+    }
+
+    /// Results of `Gemm` stages, which are only used as operands of other `Gemm` stages
+    fn mark_quantized(&mut self) {
+        let n = self.stages.len();
+        let mut as_operand = vec![false; n];
+        let mut elsewhere = vec![false; n];
+        if let Value::Stage(id) = self.output {
+            elsewhere[id] = true;
+        }
+        for stage in &self.stages {
+            match stage {
+                Stage::Gemm { a, b, bias, .. } => {
+                    for x in [a, b] {
+                        if let Value::Stage(id) = x.value {
+                            as_operand[id] = true;
+                        }
+                    }
+                    if let Some(Operand { value: Value::Stage(id), .. }) = bias {
+                        elsewhere[*id] = true;
+                    }
+                }
+                Stage::Host { args, .. } => {
+                    for x in args {
+                        if let Value::Stage(id) = x.value {
+                            elsewhere[id] = true;
+                        }
+                    }
+                }
+            }
+        }
+        for (id, stage) in self.stages.iter_mut().enumerate() {
+            if let Stage::Gemm { quantized, .. } = stage {
+                *quantized = as_operand[id] && !elsewhere[id];
+            }
+        }
+    }
+
+    fn shape(&self, x: Operand) -> (usize, usize) {
+        let (r, c) = match x.value {
+            Value::Input(i) => self.inputs[i].dim(),
+            Value::Stage(id) => match &self.stages[id] {
+                Stage::Gemm { a, b, .. } => (self.shape(*a).0, self.shape(*b).0),
+                Stage::Host { args, .. } => self.shape(args[0]),
+            },
+        };
+        if x.transposed { (c, r) } else { (r, c) }
+    }
+
+    fn is_quantized(&self, value: Value) -> bool {
+        matches!(value, Value::Stage(id) if matches!(self.stages[id], Stage::Gemm { quantized: true, .. }))
+    }
+
+    /// `OPAC` passes and conversions of a stage, with bias preloaded
+    fn stage_counts(&self, stage: &Stage) -> (u64, u64) {
+        let Stage::Gemm { a, b, bias, quantized, .. } = stage else {
+            return (0, 0);
+        };
+        let ((m, k), (n, _)) = (self.shape(*a), self.shape(*b));
+        let tiles = |x: usize| x.div_ceil(DIMENSION) as u64;
+        let (m, n, k) = (m as u64, n as u64, k as u64);
+        let mut conversions = m * n * tiles(k as usize);
+        if !self.is_quantized(a.value) {
+            conversions += m * k * tiles(n as usize);
+        }
+        if !self.is_quantized(b.value) {
+            conversions += n * k * tiles(m as usize);
+        }
+        if bias.is_some() {
+            conversions += m * n;
+        }
+        if *quantized {
+            conversions += m * n;
+        }
+        (tiles(m as usize) * tiles(n as usize) * k, conversions)
+    }
+
+    /// Total `OPAC` passes and conversions
+    pub fn counts(&self) -> (u64, u64) {
+        self.stages.iter().map(|s| self.stage_counts(s)).fold((0, 0), |acc, x| (acc.0 + x.0, acc.1 + x.1))
+    }
+
+    pub fn run(&self) -> Array2<f32> {
+        let mut results: Vec<Computed> = Vec::with_capacity(self.stages.len());
+        for stage in &self.stages {
+            let res = match stage {
+                Stage::Gemm { a, b, bias, relu, quantized } => {
+                    self.gemm((*a, *b, *bias), *relu, *quantized, &results)
+                }
+                Stage::Host { op, args } => {
+                    let x = self.f32_value(args[0], &results);
+                    Computed::F32(match op {
+                        HostOp::Add => &x + &self.f32_value(args[1], &results),
+                        HostOp::Relu => x.mapv(|v| v.max(0.)),
+                        HostOp::View => x,
+                    })
+                }
+            };
+            results.push(res);
+        }
+        self.f32_value(Operand::of(self.output), &results)
+    }
+
+    fn f32_value(&self, x: Operand, results: &[Computed]) -> Array2<f32> {
+        let res = match x.value {
+            Value::Input(i) => self.inputs[i].to_owned(),
+            Value::Stage(id) => match &results[id] {
+                Computed::F32(v) => v.clone(),
+                Computed::Quantized(q, scale) => q.mapv(|v| v as f32 * scale),
+            },
+        };
+        if x.transposed { res.reversed_axes() } else { res }
+    }
+
+    fn gemm(&self, (a, b, bias): (Operand, Operand, Option<Operand>), relu: bool, quantized: bool, results: &[Computed]) -> Computed {
+        let (a, b) = (OperandData::new(self, a, results), OperandData::new(self, b, results));
+        let ((m, k), (n, _)) = (a.lanes.dim(), b.lanes.dim());
+        assert_eq!(a.lanes.dim().1, b.lanes.dim().1, "Product shapes don't match");
+        // Real value of accumulator unit is `scale_a * scale_b`, readout assumes `INPUT_SCALE^2`
+        let unit = a.scale * b.scale / (INPUT_SCALE * INPUT_SCALE);
+        let bias = bias.map(|x| {
+            let x = self.f32_value(x, results);
+            x.broadcast((m, n)).expect("Bias shape doesn't match").to_owned() / unit
+        });
+        // Preloaded cell and `k` products of at most one must fit into accumulator,
+        // otherwise bias is added on host
+        let headroom = AccT::MAX as f32 * INPUT_SCALE * INPUT_SCALE - k as f32;
+        let (preload, host_bias) = match bias {
+            Some(bias) if k > 0 && max_abs(&bias) <= headroom => (Some(bias), None),
+            bias => (None, bias),
+        };
+
+        let mut epilogue = StageEpilogue { bias: preload.as_ref(), relu };
+        let mut res = mat_mul_operands_with(a.lanes, b.lanes, &mut epilogue);
+        if let Some(bias) = host_bias {
+            res += &bias;
+        }
+        res *= unit;
+        if quantized {
+            count_conversions(res.len() as u64);
+            let q = Quantizer::for_values(&res);
+            Computed::Quantized(res.mapv(|v| q.quantize(v)), q.scale())
+        } else {
+            Computed::F32(res)
+        }
+    }
+}
+
+enum Computed {
+    F32(Array2<f32>),
+    /// Values and quantization scale
+    Quantized(Array2<ChipT>, f32),
+}
+
+/// Operand of `Gemm`, in layout `[rows, common]`, with real value of its unit
+struct OperandData<'v> {
+    lanes: wrappers::Operand<'v>,
+    scale: f32,
+}
+
+impl<'v> OperandData<'v> {
+    fn new<'a: 'v>(plan: &'v Plan<'a>, x: Operand, results: &'v [Computed]) -> Self {
+        let orient = |v: ArrayView2<'v, f32>| if x.transposed { v.reversed_axes() } else { v };
+        let f32_lanes = |v| OperandData { lanes: wrappers::Operand::F32(orient(v)), scale: INPUT_SCALE };
+        match x.value {
+            Value::Input(i) => f32_lanes(plan.inputs[i].view()),
+            Value::Stage(id) => match &results[id] {
+                Computed::F32(v) => f32_lanes(v.view()),
+                Computed::Quantized(q, scale) => {
+                    let q = q.view();
+                    let q = if x.transposed { q.reversed_axes() } else { q };
+                    OperandData { lanes: wrappers::Operand::Chip(q), scale: *scale }
+                }
+            },
+        }
+    }
+}
+
+/// Bias preload and `relu` at readout of a `Gemm` stage
+struct StageEpilogue<'b> {
+    /// In accumulator units
+    bias: Option<&'b Array2<f32>>,
+    relu: bool,
+}
+
+impl Epilogue for StageEpilogue<'_> {
+    fn preload(&mut self, acc: &mut Matrix, rows: Range<usize>, cols: Range<usize>) {
+        match self.bias {
+            Some(bias) => *acc = Matrix::try_from(bias.slice(s![rows, cols])).unwrap(),
+            None => acc.reset(rows.len(), cols.len()),
+        }
+    }
+
+    fn finish(&mut self, acc: &mut Matrix) {
+        if self.relu {
+            // `unit` is positive, so clamping cells commutes with scaling
+            for r in 0..acc.rows() {
+                for c in 0..acc.cols() {
+                    acc[(r, c)] = acc[(r, c)].max(0);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Plan<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |x: &Operand| {
+            let base = match x.value {
+                Value::Input(i) => format!("in{}", i),
+                Value::Stage(id) => format!("s{}", id),
+            };
+            if x.transposed { format!("{}^T", base) } else { base }
+        };
+        for (id, stage) in self.stages.iter().enumerate() {
+            let (m, n) = self.shape(Operand::of(Value::Stage(id)));
+            match stage {
+                Stage::Gemm { a, b, bias, relu, quantized } => {
+                    let (opac, conversions) = self.stage_counts(stage);
+                    let b_t = Operand { transposed: !b.transposed, ..*b };
+                    write!(f, "s{} = gemm {} * {}", id, name(a), name(&b_t))?;
+                    if let Some(bias) = bias {
+                        write!(f, " + {} (preload)", name(bias))?;
+                    }
+                    if *relu {
+                        write!(f, ", relu at readout")?;
+                    }
+                    if *quantized {
+                        write!(f, ", kept quantized")?;
+                    }
+                    let tiles = m.div_ceil(DIMENSION) * n.div_ceil(DIMENSION);
+                    writeln!(f, " [{}x{}], tiles {}, opac {}, conversions {}", m, n, tiles, opac, conversions)?;
+                }
+                Stage::Host { op, args } => {
+                    let args: Vec<_> = args.iter().map(name).collect();
+                    writeln!(f, "s{} = host {:?}({}) [{}x{}]", id, op, args.join(", "), m, n)?;
+                }
+            }
+        }
+        let (opac, conversions) = self.counts();
+        write!(f, "total: opac {}, conversions {}", opac, conversions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sample;
+    use crate::intrinsics::counters::counted;
+    use crate::intrinsics::quant::max_abs;
+
+    #[test]
+    fn fused_mlp() {
+        let (a, b, c) = (sample(6, 20, 1, 0.4), sample(8, 20, 2, 0.4), sample(8, 5, 3, 0.4));
+        let bias = sample(1, 8, 4, 0.4);
+        let product = Expr::input(a.view()).mat_mul_t(Expr::input(b.view()));
+        let expr = (product + Expr::input(bias.view())).relu().dot(Expr::input(c.view()));
+        let plan = expr.plan();
+        assert_eq!(plan.stages.len(), 2);
+        let explain = expr.explain();
+        assert!(explain.contains("s0 = gemm in0 * in1^T + in2 (preload), relu at readout, kept quantized"), "{}", explain);
+        assert!(explain.contains("s1 = gemm s0 * in3 [6x5]"), "{}", explain);
+
+        let (res, counters) = counted(|| expr.eval());
+        assert_eq!((counters.opac, counters.conversions), plan.counts());
+
+        let expected = (a.dot(&b.t()) + &bias).mapv(|v| v.max(0.)).dot(&c);
+        let max_abs = max_abs(&expected);
+        for (x, y) in res.iter().zip(&expected) {
+            assert!((x - y).abs() < 0.03 * max_abs, "{} {}", x, y);
+        }
+    }
+
+    #[test]
+    fn host_fallback() {
+        let (a, b) = (sample(3, 4, 1, 0.4), sample(3, 4, 2, 0.4));
+        // Neither operand of the sum is a product
+        let expr = (Expr::input(a.view()) + Expr::input(b.view())).relu();
+        assert!(expr.explain().starts_with("s0 = host Add(in0, in1) [3x4]\ns1 = host Relu(s0)"));
+        assert_eq!(expr.eval(), (&a + &b).mapv(|v| v.max(0.)));
+
+        // Product used outside of products is read back in f32
+        let expr = Expr::input(a.view()).mat_mul_t(Expr::input(b.view())).t();
+        assert!(!expr.explain().contains("quantized"));
+        let res = expr.eval();
+        let expected = b.dot(&a.t());
+        assert!(res.iter().zip(&expected).all(|(x, y)| (x - y).abs() < 0.02));
+
+        // Double transposes cancel without host views
+        let expr = Expr::input(a.view()).t().t().mat_mul_t(Expr::input(b.view()).t().t()).relu();
+        assert_eq!(expr.plan().stages.len(), 1);
+        assert!(expr.explain().starts_with("s0 = gemm in0 * in1^T, relu at readout [3x3]"), "{}", expr.explain());
+        let expected = a.dot(&b.t()).mapv(|v| v.max(0.));
+        assert!(expr.eval().iter().zip(&expected).all(|(x, y)| (x - y).abs() < 0.02));
+    }
+
+    #[test]
+    fn bias_and_relu_limits() {
+        let (a, b) = (sample(3, 4, 1, 0.4), sample(2, 4, 2, 0.4));
+        let product = a.dot(&b.t());
+        // Bias past accumulator range is added on host instead of wrapping in the preload
+        let bias = Array2::from_elem((1, 2), 200000.);
+        let expr = Expr::input(a.view()).mat_mul_t(Expr::input(b.view())) + Expr::input(bias.view());
+        assert!(expr.explain().contains("(preload)"));
+        let expected = &product + &bias;
+        assert!(expr.eval().iter().zip(&expected).all(|(x, y)| (x - y).abs() < 0.05), "{}", expr.eval());
+
+        // Blocks of long common dimension are summed on host, so `relu` can't be applied to them
+        let (a, b) = (sample(3, DIMENSION + 1, 1, 0.4), sample(2, DIMENSION + 1, 2, 0.4));
+        let expr = Expr::input(a.view()).mat_mul_t(Expr::input(b.view())).relu();
+        assert!(expr.explain().contains("s1 = host Relu(s0)"), "{}", expr.explain());
+        let (res, counters) = counted(|| expr.eval());
+        assert_eq!((counters.opac, counters.conversions), expr.plan().counts());
+        let expected = a.dot(&b.t()).mapv(|v| v.max(0.));
+        assert!(res.iter().zip(&expected).all(|(x, y)| (x - y).abs() < 0.3), "{} {}", res, expected);
+    }
+}
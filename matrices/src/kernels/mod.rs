@@ -0,0 +1,8 @@
+//! Higher level kernels, built only from emulated device instructions
+pub mod norm;
+pub mod attention;
+pub mod sort;
+pub mod pool;
+pub mod eigen;
+pub mod expr;
+pub mod cp;
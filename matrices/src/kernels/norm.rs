@@ -0,0 +1,356 @@
+//! Row-wise normalizations for transformer layers, in integer arithmetic.
+//!
+//! Host quantizes inputs, precomputes per-lane constants from quantization
+//! scales, and dequantizes results. Max, exponentials, sums and every per-lane
+//! rescaling run on vector instructions: products are formed with `sca_mul` and
+//! `v_mulhi` and shifted in lanes. Per-row scalars are normalized by the scalar core
+//! to 8 significant bits, their reciprocal (softmax) and reciprocal square root (layer norm)
+//! come from lookup tables.
+//!
+//! Tolerances against `f32` reference on the same quantized input:
+//! - `softmax`: `2 / 128` per element, it comes from rounding of exp table, reciprocal and output
+//! - `layer_norm`: `1.5` output quantization steps plus `2 / 128 * |gamma|` from
+//!   rounding of the per-lane multiplier
+
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, Axis};
+use crate::intrinsics::intrinsics::ChipT;
+use crate::intrinsics::lut::Lut;
+use crate::intrinsics::quant::Quantizer;
+use crate::intrinsics::wrappers::{
+    vec_add_sat, vec_and, vec_cmp_gt, vec_cmp_lt, vec_dot, vec_lut, vec_mul, vec_mulhi, vec_or, vec_reduce_max,
+    vec_reduce_min, vec_select, vec_shl, vec_shr, vec_sub, vec_sub_sat, vec_sum,
+};
+
+/// Fractional bits of `softmax` output probabilities
+const SOFTMAX_OUTPUT_BITS: u32 = 7;
+
+pub const SOFTMAX_OUTPUT_SCALE: f32 = 1. / (1 << SOFTMAX_OUTPUT_BITS) as f32;
+
+/// Scale of exp table output, so that `exp(0)` is exactly `ChipT::MAX`
+pub const EXP_SCALE: f32 = 1. / ChipT::MAX as f32;
+
+/// Fractional bits of reciprocal table output, for inputs in `[64, 128)`
+const RECIP_BITS: u32 = 13;
+
+/// Fractional bits of fixed point variance
+const VAR_BITS: u32 = 16;
+
+/// Fractional bits of reciprocal square root table output, for inputs in `[64, 256)`
+const RSQRT_BITS: u32 = 10;
+
+/// Fractional bits of fixed point mean and reciprocal of row length
+const MEAN_BITS: u32 = 8;
+const INV_N_BITS: u32 = 32;
+
+pub(crate) fn quantize(x: ArrayView1<f32>, q: Quantizer) -> Array1<ChipT> {
+    x.map(|v| q.quantize(*v))
+}
+
+fn splat(x: ChipT, len: usize) -> Array1<ChipT> {
+    Array1::from_elem(len, x)
+}
+
+/// `round(a * b / 2^k)` in lanes, saturated to `ChipT`.
+/// 16-bit products are assembled from `v_mulhi` and `sca_mul` halves.
+fn mul_shift(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>, k: i32) -> Array1<ChipT> {
+    let len = a.len();
+    if k >= 16 {
+        // |a * b| <= 2^14
+        return Array1::zeros(len);
+    }
+    if k < 1 {
+        // Shifted left, lowest bits don't matter next to saturation
+        let mut res = mul_shift(a, b, 1);
+        for _ in 0..(1 - k).min(ChipT::BITS as i32) {
+            res = vec_add_sat(res.view(), res.view());
+        }
+        return res;
+    }
+    let hi = vec_mulhi(a, b);
+    let lo = vec_mul(a, b);
+    if k >= 8 {
+        // Rounded upper half, sign of lower half is its top bit
+        let hi = vec_sub(hi.view(), vec_shr(lo.view(), splat(7, len).view()).view());
+        if k == 8 {
+            return hi;
+        }
+        let half = vec_add_sat(hi.view(), splat(1 << (k - 9), len).view());
+        return vec_shr(half.view(), splat((k - 8) as ChipT, len).view());
+    }
+    // Upper half moves up by `8 - k`, lower half down by `k` without sign fill
+    let upper = vec_shl(hi.view(), splat((8 - k) as ChipT, len).view());
+    let low_bits = ((1 << (8 - k)) - 1) as ChipT;
+    let lower = vec_and(vec_shr(lo.view(), splat(k as ChipT, len).view()).view(), splat(low_bits, len).view());
+    let round = vec_and(vec_shr(lo.view(), splat((k - 1) as ChipT, len).view()).view(), splat(1, len).view());
+    let res = vec_add_sat(vec_or(upper.view(), lower.view()).view(), round.view());
+    // Result fits iff upper half is in `[-2^(k-1), 2^(k-1))`
+    let limit = (1 << (k - 1)) as ChipT;
+    let res = vec_select(vec_cmp_gt(hi.view(), splat(limit - 1, len).view()).view(), splat(ChipT::MAX, len).view(), res.view());
+    vec_select(vec_cmp_lt(hi.view(), splat(-limit, len).view()).view(), splat(ChipT::MIN, len).view(), res.view())
+}
+
+/// Splits positive `x` into `m * 2^(step * e)`, with `m` rounded to nearest
+/// and in `[2^(bits - step), 2^bits)`
+fn normalize(x: i64, bits: u32, step: u32) -> (i64, i32) {
+    let (bits, step) = (bits as i32, step as i32);
+    let mut e = ((i64::BITS - x.leading_zeros()) as i32 - bits).div_euclid(step);
+    loop {
+        let shift = step * e;
+        let m = if shift > 0 { (x + (1 << (shift - 1))) >> shift } else { x << -shift };
+        if m < 1 << bits {
+            return (m, e);
+        }
+        e += 1;
+    }
+}
+
+/// Reciprocal table for `softmax_row`, with `2^RECIP_BITS / x` for `x` in `[64, 128)`
+pub fn softmax_recip() -> Lut {
+    Lut::reciprocal(Quantizer::new(1.), Quantizer::new(1. / (1 << RECIP_BITS) as f32))
+}
+
+/// Integer softmax of quantized row, result has `SOFTMAX_OUTPUT_SCALE`.
+/// Lanes disabled by `mask` get zero probability, if all of them are disabled result is zero.
+/// `recip` is made by `softmax_recip`.
+pub fn softmax_row(q: ArrayView1<ChipT>, mask: Option<ArrayView1<ChipT>>, exp: &Lut, recip: &Lut) -> Array1<ChipT> {
+    let len = q.len();
+    let max = match mask {
+        Some(mask) => vec_reduce_max(vec_select(mask, q, splat(ChipT::MIN, len).view()).view()),
+        None => vec_reduce_max(q),
+    };
+    // All differences are non positive, saturation only hits values with negligible exp
+    let shifted = vec_sub_sat(q, splat(max, len).view());
+    let mut e = vec_lut(exp, shifted.view());
+    if let Some(mask) = mask {
+        e = vec_select(mask, e.view(), Array1::zeros(len).view());
+    }
+    let sum = vec_sum(e.view());
+    if sum == 0 {
+        return Array1::zeros(len);
+    }
+    // Maximum lane is `exp(0)`, so `sum >= 127` and `s >= 0`
+    let (m, s) = normalize(sum, 7, 1);
+    let r = vec_lut(recip, splat(m as ChipT, len).view());
+    // p = e / (m * 2^s) = e * r / 2^(RECIP_BITS + s), output keeps SOFTMAX_OUTPUT_BITS
+    mul_shift(e.view(), r.view(), (RECIP_BITS - SOFTMAX_OUTPUT_BITS) as i32 + s)
+}
+
+/// Row-wise softmax, inputs are quantized with `input`
+pub fn softmax(x: ArrayView2<f32>, input: Quantizer) -> Array2<f32> {
+    let exp = Lut::exp(input, Quantizer::new(EXP_SCALE));
+    let recip = softmax_recip();
+    let out = Quantizer::new(SOFTMAX_OUTPUT_SCALE);
+    let mut res = Array2::zeros(x.raw_dim());
+    for (src, mut dst) in x.axis_iter(Axis(0)).zip(res.axis_iter_mut(Axis(0))) {
+        let row = softmax_row(quantize(src, input).view(), None, &exp, &recip);
+        dst.assign(&row.map(|v| out.dequantize(*v)));
+    }
+    res
+}
+
+/// Per-call constants of `layer_norm_row`, prepared on host
+struct LayerNorm {
+    /// `gamma / output scale`, as `gamma * 2^-gamma_bits`
+    gamma: Array1<ChipT>,
+    gamma_bits: i32,
+    beta: Array1<ChipT>,
+    /// `2^INV_N_BITS / n`
+    inv_n: i64,
+    /// In `2^VAR_BITS` of squared input steps
+    eps: i64,
+    /// `2^RSQRT_BITS / sqrt(x)` for `x` in `[64, 256)`, lanes are unsigned
+    rsqrt: Lut,
+}
+
+/// Layer norm of quantized row.
+///
+/// Deviations from mean need 9 bits when the row is skewed, then they are formed
+/// at half of input step. Mean and variance come from exact sums, normalized variance
+/// indexes `rsqrt` table, and its result scales `gamma` to the per-lane multiplier.
+fn layer_norm_row(q: ArrayView1<ChipT>, ln: &LayerNorm) -> Array1<ChipT> {
+    let len = q.len();
+    let sum = vec_sum(q);
+    let sum_sq = vec_dot(q, q);
+    // sum <= 2^7 n, sum_sq <= 2^14 n, so products with 2^32 / n fit
+    let mean = (sum * ln.inv_n) >> (INV_N_BITS - MEAN_BITS);
+    let mean_sq = (sum_sq * ln.inv_n) >> (INV_N_BITS - VAR_BITS);
+    let var = (mean_sq - mean * mean).max(0) + ln.eps;
+
+    let mean_q = ((mean + (1 << (MEAN_BITS - 1))) >> MEAN_BITS) as ChipT;
+    let (high, low) = (vec_reduce_max(q) as i32 - mean_q as i32, vec_reduce_min(q) as i32 - mean_q as i32);
+    let (d, half) = if high > ChipT::MAX as i32 || low < ChipT::MIN as i32 {
+        let one = splat(1, len);
+        let x = vec_shr(q, one.view());
+        (vec_sub(x.view(), splat(mean_q >> 1, len).view()), 1)
+    } else {
+        (vec_sub(q, splat(mean_q, len).view()), 0)
+    };
+
+    // var = m * 4^e / 2^VAR_BITS, so 1 / sqrt(var) = rsqrt(m) * 2^(VAR_BITS / 2 - RSQRT_BITS - e)
+    let (m, e) = normalize(var.max(1), 8, 2);
+    let rs = vec_lut(&ln.rsqrt, splat(m as u8 as ChipT, len).view());
+    let mult = mul_shift(ln.gamma.view(), rs.view(), 7);
+    // y = d * 2^half * mult * 2^(7 - gamma_bits) * 2^(VAR_BITS / 2 - RSQRT_BITS - e)
+    let k = ln.gamma_bits + e - 7 - (VAR_BITS / 2) as i32 + RSQRT_BITS as i32 - half;
+    vec_add_sat(mul_shift(d.view(), mult.view(), k).view(), ln.beta.view())
+}
+
+/// Row-wise layer norm: `(x - mean) / sqrt(var + eps) * gamma + beta`.
+/// Inputs are quantized with `input`, outputs with `output`.
+pub fn layer_norm(
+    x: ArrayView2<f32>,
+    gamma: ArrayView1<f32>,
+    beta: ArrayView1<f32>,
+    eps: f32,
+    input: Quantizer,
+    output: Quantizer,
+) -> Array2<f32> {
+    assert_eq!(x.ncols(), gamma.len());
+    assert_eq!(x.ncols(), beta.len());
+    // Constants are computed once, on host
+    let max_gamma = gamma.iter().fold(0f32, |acc, g| acc.max(g.abs())) / output.scale();
+    let gamma_bits = if max_gamma > 0. { (ChipT::MAX as f32 / max_gamma).log2().floor() as i32 } else { 0 };
+    let gamma_q = gamma.map(|g| (g / output.scale() * (gamma_bits as f32).exp2()).round() as ChipT);
+    let rsqrt = Lut::from_fn(
+        |x| {
+            let m = if x < 0. { x + 256. } else { x };
+            (1 << RSQRT_BITS) as f32 / m.sqrt()
+        },
+        Quantizer::new(1.),
+        Quantizer::new(1.),
+    );
+    let ln = LayerNorm {
+        gamma: gamma_q,
+        gamma_bits,
+        beta: beta.map(|b| output.quantize(*b)),
+        inv_n: ((1i64 << INV_N_BITS) + x.ncols() as i64 / 2) / x.ncols() as i64,
+        eps: (eps / (input.scale() * input.scale()) * (1u64 << VAR_BITS) as f32).round() as i64,
+        rsqrt,
+    };
+
+    let mut res = Array2::zeros(x.raw_dim());
+    for (src, mut dst) in x.axis_iter(Axis(0)).zip(res.axis_iter_mut(Axis(0))) {
+        let row = layer_norm_row(quantize(src, input).view(), &ln);
+        dst.assign(&row.map(|v| output.dequantize(*v)));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intrinsics::config::DIMENSION;
+    use crate::intrinsics::counters::counted;
+
+    fn sample(rows: usize, cols: usize, range: f32) -> Array2<f32> {
+        Array2::from_shape_fn((rows, cols), |(i, j)| {
+            let x = ((i * 7919 + j * 104729) % 1000) as f32 / 1000.;
+            (2. * x - 1.) * range
+        })
+    }
+
+    fn dequantized(x: &Array2<f32>, q: Quantizer) -> Array2<f32> {
+        x.map(|v| q.dequantize(q.quantize(*v)))
+    }
+
+    fn max_diff(a: &Array2<f32>, b: &Array2<f32>) -> f32 {
+        (a - b).iter().fold(0., |acc, x| acc.max(x.abs()))
+    }
+
+    #[test]
+    fn softmax_matches_f32() {
+        let input = Quantizer::new(1. / 16.);
+        for cols in [1, 10, DIMENSION + 3] {
+            let x = dequantized(&sample(4, cols, 6.), input);
+            let mut expected = x.map(|v| v.exp());
+            for mut row in expected.axis_iter_mut(Axis(0)) {
+                let sum = row.sum();
+                row /= sum;
+            }
+            let res = softmax(x.view(), input);
+            assert!(max_diff(&res, &expected) <= 2. / 128., "cols={}", cols);
+        }
+    }
+
+    #[test]
+    fn layer_norm_matches_f32() {
+        let input = Quantizer::new(1. / 32.);
+        let output = Quantizer::new(1. / 32.);
+        let eps = 1e-5;
+        for cols in [8, 64, DIMENSION + 3] {
+            let x = dequantized(&sample(3, cols, 3.), input);
+            let gamma = Array1::from_shape_fn(cols, |j| 0.5 + (j % 3) as f32 * 0.25);
+            let beta = Array1::from_shape_fn(cols, |j| (j % 5) as f32 * 0.1 - 0.2);
+            let mut expected = x.clone();
+            for mut row in expected.axis_iter_mut(Axis(0)) {
+                let mean = row.mean().unwrap();
+                let var = row.map(|v| (v - mean) * (v - mean)).mean().unwrap();
+                row.map_inplace(|v| *v = (*v - mean) / (var + eps).sqrt());
+                row *= &gamma;
+                row += &beta;
+            }
+            let res = layer_norm(x.view(), gamma.view(), beta.view(), eps, input, output);
+            let max_gamma = gamma.iter().fold(0f32, |acc, g| acc.max(g.abs()));
+            let tolerance = 1.5 * output.scale() + 2. / 128. * max_gamma;
+            assert!(max_diff(&res, &expected) <= tolerance, "cols={} diff={}", cols, max_diff(&res, &expected));
+        }
+    }
+
+    #[test]
+    fn layer_norm_skewed() {
+        // Deviation of the outlier from mean is out of `ChipT` range
+        let input = Quantizer::new(1. / 128.);
+        let output = Quantizer::new(1. / 32.);
+        let mut x = Array2::from_elem((1, 16), -0.9);
+        x[(0, 15)] = 0.99;
+        let x = dequantized(&x, input);
+        let ones = Array1::ones(16);
+        let res = layer_norm(x.view(), ones.view(), Array1::zeros(16).view(), 1e-5, input, output);
+
+        let mean = x.mean().unwrap();
+        let std = (x.map(|v| (v - mean) * (v - mean)).mean().unwrap() + 1e-5).sqrt();
+        let expected = x.map(|v| (v - mean) / std);
+        assert!((expected[(0, 0)] + 0.26).abs() < 0.01 && (expected[(0, 15)] - 3.87).abs() < 0.01);
+        assert!(max_diff(&res, &expected) <= 1.5 * output.scale() + 2. / 128., "{}", res);
+    }
+
+    #[test]
+    fn lane_products() {
+        let values: Vec<ChipT> = (ChipT::MIN..=ChipT::MAX).step_by(3).collect();
+        for k in -2..18 {
+            for b in [ChipT::MIN, -77, -1, 0, 1, 45, ChipT::MAX] {
+                let res = mul_shift(ArrayView1::from(&values), Array1::from_elem(values.len(), b).view(), k);
+                for (x, y) in values.iter().zip(&res) {
+                    let expected = (*x as f64 * b as f64 / (k as f64).exp2() + 0.5).floor();
+                    let expected = expected.clamp(ChipT::MIN as f64, ChipT::MAX as f64);
+                    // Only shifts left and the double rounding for `k > 8` may lose the lowest bit
+                    let slack = if k < 1 { ((1 - k) as f64).exp2() } else if k > 8 { 1. } else { 0. };
+                    assert!((*y as f64 - expected).abs() <= slack, "{} * {} >> {} = {}", x, b, k, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn normalized_scalars() {
+        for x in [1, 63, 64, 127, 128, 255, 256, 1000, 65535, 1 << 40] {
+            let (m, e) = normalize(x, 7, 1);
+            assert!((64..128).contains(&m) && (m as f64 * (e as f64).exp2() / x as f64 - 1.).abs() <= 1. / 128.);
+            let (m, e) = normalize(x, 8, 2);
+            assert!((64..256).contains(&m) && (m as f64 * (2. * e as f64).exp2() / x as f64 - 1.).abs() <= 1. / 128.);
+        }
+    }
+
+    #[test]
+    fn rescaling_on_vector_units() {
+        let input = Quantizer::new(1. / 32.);
+        let x = dequantized(&sample(2, 16, 3.), input);
+        let ones = Array1::ones(16);
+        let (_, counters) = counted(|| layer_norm(x.view(), ones.view(), Array1::zeros(16).view(), 1e-5, input, input));
+        assert_eq!(counters.vectors.lookup, 2);
+        assert!(counters.vectors.arithmetic >= 2 * 4);
+        let (_, counters) = counted(|| softmax(x.view(), input));
+        assert_eq!(counters.vectors.lookup, 2 * 2);
+        assert!(counters.vectors.arithmetic >= 2 * 2);
+    }
+}
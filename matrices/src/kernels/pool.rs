@@ -0,0 +1,376 @@
+//! Pooling and depthwise convolution of NCHW tensors on vector units.
+//!
+//! Output rows are vectorized: each kernel tap loads strided input row
+//! (padding lanes are filled on load) and combines it with accumulator row.
+//!
+//! Products use fixed point format with 7 fractional bits: they are assembled
+//! from low (`sca_mul`) and high (`v_mulhi`) bytes of exact 16-bit product and rounded,
+//! so each tap adds at most half of output step of error.
+//! Average pooling keeps exact 16-bit sums of taps in pairs of registers instead,
+//! taps are permuted on device from input rows loaded once, and sums are scaled
+//! on readout, so it rounds only once.
+
+use std::cmp::min;
+use ndarray::{s, Array1, Array4, ArrayView1, ArrayView2, ArrayView3, ArrayView4};
+use crate::intrinsics::config::DIMENSION;
+use crate::intrinsics::counters::count_to_host;
+use crate::intrinsics::intrinsics::{v_add, v_cmp_lt, v_shr, v_sub, Array1D, ChipT};
+use crate::intrinsics::quant::Quantizer;
+use crate::intrinsics::shuffle::v_permute;
+use crate::intrinsics::wrappers::{vec_add_sat, vec_and, vec_max, vec_mul, vec_mulhi, vec_shr};
+
+/// Sliding window of pooling or convolution, `(height, width)` pairs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Window2d {
+    pub kernel: (usize, usize),
+    pub stride: (usize, usize),
+    pub padding: (usize, usize),
+}
+
+impl Window2d {
+    pub fn new(kernel: (usize, usize), stride: (usize, usize), padding: (usize, usize)) -> Self {
+        assert!(kernel.0 > 0 && kernel.1 > 0);
+        assert!(stride.0 > 0 && stride.1 > 0);
+        Window2d { kernel, stride, padding }
+    }
+
+    fn output_dim(&self, h: usize, w: usize) -> (usize, usize) {
+        let out = |size: usize, k: usize, s: usize, p: usize| {
+            assert!(size + 2 * p >= k, "Window is larger than padded input");
+            (size + 2 * p - k) / s + 1
+        };
+        (
+            out(h, self.kernel.0, self.stride.0, self.padding.0),
+            out(w, self.kernel.1, self.stride.1, self.padding.1),
+        )
+    }
+
+    /// Loads input lanes of tap `(kh, kw)` for output row `oh`
+    fn tap_row(&self, plane: ArrayView2<ChipT>, oh: usize, kh: usize, kw: usize, w_out: usize, pad: ChipT) -> Array1<ChipT> {
+        let ih = (oh * self.stride.0 + kh) as isize - self.padding.0 as isize;
+        Array1::from_shape_fn(w_out, |ow| {
+            let iw = (ow * self.stride.1 + kw) as isize - self.padding.1 as isize;
+            if ih < 0 || iw < 0 || ih as usize >= plane.nrows() || iw as usize >= plane.ncols() {
+                pad
+            } else {
+                plane[[ih as usize, iw as usize]]
+            }
+        })
+    }
+}
+
+/// Rounded fixed point product: `a * b / 128`, ties are rounded up, result saturates
+fn q7_mul(a: ArrayView1<ChipT>, b: ArrayView1<ChipT>) -> Array1<ChipT> {
+    let n = a.len();
+    let low = vec_mul(a, b);
+    let high = vec_mulhi(a, b);
+    let bit = |x: &Array1<ChipT>, pos: ChipT| {
+        let shifted = vec_shr(x.view(), Array1::from_elem(n, pos).view());
+        vec_and(shifted.view(), Array1::from_elem(n, 1).view())
+    };
+    // `(high << 1) | bit 7 of low` is product truncated to 7 fractional bits, bit 6 rounds it
+    let truncated = vec_add_sat(vec_add_sat(high.view(), high.view()).view(), bit(&low, 7).view());
+    vec_add_sat(truncated.view(), bit(&low, 6).view())
+}
+
+/// Runs `combine(accumulator, tap row, channel)` for every kernel tap of every output row
+fn sliding(
+    x: ArrayView4<ChipT>,
+    window: Window2d,
+    pad: ChipT,
+    init: ChipT,
+    combine: impl Fn(Array1<ChipT>, Array1<ChipT>, usize, usize, usize) -> Array1<ChipT>,
+) -> Array4<ChipT> {
+    let (n, c, h, w) = x.dim();
+    let (h_out, w_out) = window.output_dim(h, w);
+    let mut res = Array4::zeros((n, c, h_out, w_out));
+    for b in 0..n {
+        for ch in 0..c {
+            let plane = x.slice(s![b, ch, .., ..]);
+            for oh in 0..h_out {
+                let mut acc = Array1::from_elem(w_out, init);
+                for kh in 0..window.kernel.0 {
+                    for kw in 0..window.kernel.1 {
+                        let row = window.tap_row(plane, oh, kh, kw, w_out, pad);
+                        acc = combine(acc, row, ch, kh, kw);
+                    }
+                }
+                res.slice_mut(s![b, ch, oh, ..]).assign(&acc);
+            }
+        }
+    }
+    res
+}
+
+fn quantize4(x: ArrayView4<f32>, q: Quantizer) -> Array4<ChipT> {
+    x.map(|v| q.quantize(*v))
+}
+
+/// Max pooling, padding never wins. Exact on quantized input.
+pub fn max_pool2d(x: ArrayView4<f32>, window: Window2d, q: Quantizer) -> Array4<f32> {
+    let xq = quantize4(x, q);
+    let res = sliding(xq.view(), window, ChipT::MIN, ChipT::MIN, |acc, row, _, _, _| {
+        vec_max(acc.view(), row.view())
+    });
+    res.map(|v| q.dequantize(*v))
+}
+
+/// Taps, which are summed in `WideSum` without overflow
+const WIDE_TAPS: usize = 1 << (16 - ChipT::BITS);
+
+/// Exact 16-bit sum in two registers: `high * 256 + low`, with `low` unsigned.
+/// `low` is kept with its top bit flipped, so unsigned carry is a signed compare.
+/// Every `WIDE_TAPS` additions registers are read back and cleared.
+struct WideSum {
+    high: Array1D,
+    low: Array1D,
+    count: usize,
+    flushed: Vec<i32>,
+    /// Constant registers: flipped zero and shift amount of the sign
+    low_zero: Array1D,
+    sign_shift: Array1D,
+}
+
+impl WideSum {
+    fn new(sz: usize) -> Self {
+        let low_zero = Array1D::from_slice(&vec![ChipT::MIN; sz]);
+        let sign_shift = Array1D::from_slice(&vec![ChipT::BITS as ChipT - 1; sz]);
+        WideSum { high: Array1D::zeros(sz), low: low_zero.clone(), count: 0, flushed: vec![0; sz], low_zero, sign_shift }
+    }
+
+    fn clear_registers(&mut self) {
+        self.high = Array1D::zeros(self.high.len());
+        self.low = self.low_zero.clone();
+        self.count = 0;
+    }
+
+    fn add(&mut self, x: &Array1D) {
+        if self.count == WIDE_TAPS {
+            self.flushed = self.read();
+            self.clear_registers();
+        }
+        self.count += 1;
+        let low = v_add(&self.low, x);
+        // Carry is `-1` in compare mask, sign of `x` is `-1` after shift
+        let carry = v_cmp_lt(&low, &self.low);
+        let sign = v_shr(x, &self.sign_shift);
+        self.high = v_add(&v_sub(&self.high, &carry), &sign);
+        self.low = low;
+    }
+
+    fn read(&self) -> Vec<i32> {
+        let (high, low) = (to_host(&self.high), to_host(&self.low));
+        let lanes = high.iter().zip(low).map(|(h, l)| *h as i32 * 256 + (*l as i32 - ChipT::MIN as i32));
+        lanes.zip(&self.flushed).map(|(x, f)| x + f).collect()
+    }
+
+    /// Reads the sum back and starts a new one
+    fn take(&mut self) -> Vec<i32> {
+        let res = self.read();
+        self.clear_registers();
+        self.flushed.fill(0);
+        res
+    }
+}
+
+fn to_host(x: &Array1D) -> &[ChipT] {
+    count_to_host(x.len() as u64);
+    x.as_slice()
+}
+
+/// Average pooling, padding is counted as zeros (as `count_include_pad` of PyTorch).
+/// Taps are summed exactly on vector units, in `WideSum` of every output row,
+/// and the sum is scaled by `1 / taps` once on readout, so error is within half of step of `q`.
+pub fn avg_pool2d(x: ArrayView4<f32>, window: Window2d, q: Quantizer) -> Array4<f32> {
+    let xq = quantize4(x, q);
+    let (n, c, h, w) = xq.dim();
+    let (h_out, w_out) = window.output_dim(h, w);
+    let (kh, kw) = window.kernel;
+    let taps = kh * kw;
+    assert!(kw <= DIMENSION, "Kernel row must fit in one vector");
+    // Output lanes of a chunk read `(cols - 1) * stride + kw` input lanes
+    let cols = (DIMENSION - kw) / window.stride.1 + 1;
+    let mut res = Array4::zeros((n, c, h_out, w_out));
+    for b in 0..n {
+        for ch in 0..c {
+            let plane = xq.slice(s![b, ch, .., ..]);
+            for start in (0..w_out).step_by(cols) {
+                let end = min(start + cols, w_out);
+                let first = (start * window.stride.1) as isize - window.padding.1 as isize;
+                let width = (end - start - 1) * window.stride.1 + kw;
+                // Input rows are loaded once per chunk, padding lanes are filled on load
+                let mut rows: Vec<Option<Array1D>> = vec![None; h];
+                let taps_index: Vec<Vec<usize>> =
+                    (0..kw).map(|j| (0..end - start).map(|o| o * window.stride.1 + j).collect()).collect();
+                let mut sum = WideSum::new(end - start);
+                for oh in 0..h_out {
+                    for i in 0..kh {
+                        let ih = (oh * window.stride.0 + i) as isize - window.padding.0 as isize;
+                        if ih < 0 || ih as usize >= h {
+                            continue;
+                        }
+                        let row = rows[ih as usize].get_or_insert_with(|| {
+                            let values: Vec<ChipT> = (0..width as isize)
+                                .map(|j| {
+                                    let iw = first + j;
+                                    if iw < 0 || iw as usize >= w { 0 } else { plane[[ih as usize, iw as usize]] }
+                                })
+                                .collect();
+                            Array1D::from_slice(&values)
+                        });
+                        for index in &taps_index {
+                            sum.add(&v_permute(row, index).unwrap());
+                        }
+                    }
+                    let sums = sum.take();
+                    let mut dst = res.slice_mut(s![b, ch, oh, start..end]);
+                    dst.assign(&Array1::from_iter(sums.iter().map(|v| q.dequantize(q.quantize(q.scale() * *v as f32 / taps as f32)))));
+                }
+            }
+        }
+    }
+    res
+}
+
+/// Output quantization of `depthwise_conv2d`
+pub fn depthwise_output(input: Quantizer, weights: Quantizer) -> Quantizer {
+    Quantizer::new(input.scale() * weights.scale() * 128.)
+}
+
+/// Depthwise convolution: channel `c` of output is correlation of input channel `c`
+/// with `w[c]`, `w` is `[channels, kernel height, kernel width]`.
+/// Output is saturated to `depthwise_output` range, error is within `taps / 2` of its steps.
+pub fn depthwise_conv2d(
+    x: ArrayView4<f32>,
+    w: ArrayView3<f32>,
+    stride: (usize, usize),
+    padding: (usize, usize),
+    input: Quantizer,
+    weights: Quantizer,
+) -> Array4<f32> {
+    let (channels, kh, kw) = w.dim();
+    assert_eq!(x.dim().1, channels);
+    let window = Window2d::new((kh, kw), stride, padding);
+    let xq = quantize4(x, input);
+    let wq = w.map(|v| weights.quantize(*v));
+    let res = sliding(xq.view(), window, 0, 0, |acc, row, ch, i, j| {
+        let prod = q7_mul(row.view(), Array1::from_elem(row.len(), wq[[ch, i, j]]).view());
+        vec_add_sat(acc.view(), prod.view())
+    });
+    let output = depthwise_output(input, weights);
+    res.map(|v| output.dequantize(*v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array3;
+    use crate::intrinsics::counters::counted;
+
+    fn sample(shape: (usize, usize, usize, usize)) -> Array4<f32> {
+        Array4::from_shape_fn(shape, |(b, c, i, j)| {
+            let x = ((b * 31 + c * 7 + i * 7919 + j * 104729) % 1000) as f32 / 1000.;
+            2. * x - 1.
+        })
+    }
+
+    /// Reference on dequantized input
+    fn reference(x: &Array4<f32>, window: Window2d, pad: f32, op: impl Fn(&[f32], usize) -> f32) -> Array4<f32> {
+        let (n, c, h, w) = x.dim();
+        let (h_out, w_out) = window.output_dim(h, w);
+        Array4::from_shape_fn((n, c, h_out, w_out), |(b, ch, oh, ow)| {
+            let mut values = Vec::new();
+            for kh in 0..window.kernel.0 {
+                for kw in 0..window.kernel.1 {
+                    let ih = (oh * window.stride.0 + kh) as isize - window.padding.0 as isize;
+                    let iw = (ow * window.stride.1 + kw) as isize - window.padding.1 as isize;
+                    if ih < 0 || iw < 0 || ih as usize >= h || iw as usize >= w {
+                        values.push(pad);
+                    } else {
+                        values.push(x[[b, ch, ih as usize, iw as usize]]);
+                    }
+                }
+            }
+            op(&values, ch)
+        })
+    }
+
+    fn max_diff(a: &Array4<f32>, b: &Array4<f32>) -> f32 {
+        assert_eq!(a.dim(), b.dim());
+        (a - b).iter().fold(0., |acc, x| acc.max(x.abs()))
+    }
+
+    #[test]
+    fn q7_product() {
+        let values: Vec<ChipT> = (ChipT::MIN..=ChipT::MAX).collect();
+        for b in [-128, -77, -1, 0, 1, 64, 127] {
+            let a = Array1::from_vec(values.clone());
+            let res = q7_mul(a.view(), Array1::from_elem(a.len(), b).view());
+            for (x, y) in a.iter().zip(&res) {
+                // Ties are rounded up
+                let expected = ((*x as f32 * b as f32) / 128. + 0.5).floor().clamp(-128., 127.);
+                assert_eq!(*y as f32, expected, "{} * {}", x, b);
+            }
+        }
+    }
+
+    #[test]
+    fn pooling() {
+        let q = Quantizer::default();
+        let x = sample((2, 3, 9, 11)).map(|v| q.dequantize(q.quantize(*v)));
+        let window = Window2d::new((3, 3), (2, 2), (1, 1));
+
+        let res = max_pool2d(x.view(), window, q);
+        let expected = reference(&x, window, f32::MIN, |v, _| v.iter().cloned().fold(f32::MIN, f32::max));
+        assert_eq!(max_diff(&res, &expected), 0.);
+
+        let res = avg_pool2d(x.view(), window, q);
+        let expected = reference(&x, window, 0., |v, _| v.iter().sum::<f32>() / v.len() as f32);
+        assert!(max_diff(&res, &expected) <= 0.5 * q.scale());
+    }
+
+    #[test]
+    fn avg_pool_large_windows() {
+        let q = Quantizer::default();
+        for kernel in [(1, 1), (2, 5), (7, 7), (17, 17)] {
+            let window = Window2d::new(kernel, (1, 1), (0, 0));
+            let x = Array4::from_elem((1, 1, kernel.0 + 2, kernel.1 + 1), 0.5);
+            let res = avg_pool2d(x.view(), window, q);
+            assert!(res.iter().all(|v| *v == 0.5), "{:?} {}", kernel, res);
+
+            let x = sample((1, 2, kernel.0 + 3, kernel.1 + 4)).map(|v| q.dequantize(q.quantize(*v)));
+            let res = avg_pool2d(x.view(), window, q);
+            let expected = reference(&x, window, 0., |v, _| v.iter().sum::<f32>() / v.len() as f32);
+            assert!(max_diff(&res, &expected) <= 0.5 * q.scale(), "{:?}", kernel);
+        }
+    }
+
+    #[test]
+    fn avg_pool_on_vector_units() {
+        let q = Quantizer::default();
+        let x = sample((1, 1, 6, 9));
+        let window = Window2d::new((3, 3), (1, 2), (1, 1));
+        let (_, counters) = counted(|| avg_pool2d(x.view(), window, q));
+        assert_eq!(counters.opac, 0);
+        // 6 rows of 11 padded lanes and 2 constant registers of 5 lanes
+        assert_eq!(counters.bytes_to_device, 6 * 11 + 2 * 5);
+        assert_eq!(counters.bytes_to_host, 6 * 2 * 5);
+        assert_eq!(counters.shuffle, 6 * 3 * 3 - 2 * 3);
+    }
+
+    #[test]
+    fn depthwise() {
+        let input = Quantizer::default();
+        let weights = Quantizer::new(1. / 512.);
+        let x = sample((1, 4, 8, 7)).map(|v| input.dequantize(input.quantize(*v)));
+        let w = Array3::from_shape_fn((4, 3, 2), |(c, i, j)| ((c + 2 * i + 3 * j) % 7) as f32 / 64. - 3. / 64.);
+        let w = w.map(|v| weights.dequantize(weights.quantize(*v)));
+        let window = Window2d::new((3, 2), (1, 2), (1, 0));
+
+        let res = depthwise_conv2d(x.view(), w.view(), window.stride, window.padding, input, weights);
+        let expected = reference(&x, window, 0., |v, ch| {
+            v.iter().enumerate().map(|(t, x)| x * w[[ch, t / 2, t % 2]]).sum()
+        });
+        assert!(max_diff(&res, &expected) <= 6. / 2. * depthwise_output(input, weights).scale());
+    }
+}
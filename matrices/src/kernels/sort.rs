@@ -0,0 +1,272 @@
+//! Sorting networks on vector units.
+//!
+//! Every comparator puts minimum to the lower lane, so networks for any length
+//! are obtained from power of two networks by dropping comparators with lanes
+//! beyond the length (as if the tail was padded with `+inf`).
+//! Stage of disjoint comparators is executed as permutation to partner lanes,
+//! `v_min` of all lanes and `v_max` masked to the upper lanes, which keeps minimums elsewhere.
+//! Permutations are reported as shuffles in `Counters`.
+//!
+//! Every stage costs the same, so `top_k` runs a network with fewer stages than sorting:
+//! blocks of `k` lanes are sorted, then pairs of blocks are merged keeping the larger half.
+
+use ndarray::{Array1, ArrayView1};
+use crate::intrinsics::config::DIMENSION;
+use crate::intrinsics::counters::{counted, Counters};
+use crate::intrinsics::intrinsics::{v_max_masked, v_min, Array1D, ChipT, Inactive, LaneMask, MASK_TRUE};
+use crate::intrinsics::shuffle::{v_permute, v_reverse};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Network {
+    Bitonic,
+    OddEvenMerge,
+}
+
+/// Comparators `(low, high)` grouped by stages, comparators of a stage are disjoint
+type Stages = Vec<Vec<(usize, usize)>>;
+
+fn bitonic(n: usize) -> Stages {
+    let size = n.next_power_of_two();
+    let mut res = Vec::new();
+    let mut k = 2;
+    while k <= size {
+        // First merge step compares mirrored lanes, so all comparators are ascending
+        res.push((0..size).filter(|i| i % k < k / 2).map(|i| (i, i - i % k + k - 1 - i % k)).collect());
+        let mut j = k / 4;
+        while j >= 1 {
+            res.push((0..size).filter(|i| i & j == 0).map(|i| (i, i | j)).collect());
+            j /= 2;
+        }
+        k *= 2;
+    }
+    res
+}
+
+fn odd_even_merge(n: usize) -> Stages {
+    let mut res = Vec::new();
+    let mut p = 1;
+    while p < n {
+        let mut k = p;
+        while k >= 1 {
+            let mut stage = Vec::new();
+            let mut j = k % p;
+            while j + k < n {
+                for i in 0..k.min(n - j - k) {
+                    if (i + j) / (2 * p) == (i + j + k) / (2 * p) {
+                        stage.push((i + j, i + j + k));
+                    }
+                }
+                j += 2 * k;
+            }
+            res.push(stage);
+            k /= 2;
+        }
+        p *= 2;
+    }
+    res
+}
+
+/// Comparators of `network` for `n` lanes
+fn stages(network: Network, n: usize) -> Stages {
+    let full = match network {
+        Network::Bitonic => bitonic(n),
+        Network::OddEvenMerge => odd_even_merge(n),
+    };
+    full.into_iter()
+        .map(|stage| stage.into_iter().filter(|(_, high)| *high < n).collect::<Vec<_>>())
+        .filter(|stage| !stage.is_empty())
+        .collect()
+}
+
+/// Network, which puts `k` largest of `n` lanes in ascending order to the last lanes.
+/// Lanes are aligned to the end of power of two network, missing lanes in front act as `-inf`,
+/// so comparators starting there don't change anything.
+fn top_stages(network: Network, n: usize, k: usize) -> Stages {
+    let block = k.max(1).next_power_of_two();
+    let size = n.next_power_of_two().max(block);
+    let blocks = size / block;
+    let within = |b: usize, stage: &[(usize, usize)]| stage.iter().map(|(l, h)| (b * block + l, b * block + h)).collect::<Vec<_>>();
+    let mut res: Stages = stages(network, block)
+        .iter()
+        .map(|stage| (0..blocks).flat_map(|b| within(b, stage)).collect())
+        .collect();
+    let mut stride = 1;
+    while stride < blocks {
+        // Upper block gets maximums against reversed lower block, they form bitonic sequence
+        let uppers: Vec<usize> = (2 * stride - 1..blocks).step_by(2 * stride).collect();
+        res.push(
+            uppers
+                .iter()
+                .flat_map(|u| (0..block).map(move |i| ((u - stride) * block + i, u * block + block - 1 - i)))
+                .collect(),
+        );
+        let mut j = block / 2;
+        while j >= 1 {
+            let stage: Vec<_> = (0..block).filter(|i| i & j == 0).map(|i| (i, i | j)).collect();
+            res.push(uppers.iter().flat_map(|u| within(*u, &stage)).collect());
+            j /= 2;
+        }
+        stride *= 2;
+    }
+    let pad = size - n;
+    res.into_iter()
+        .map(|stage| stage.into_iter().filter(|(low, _)| *low >= pad).map(|(l, h)| (l - pad, h - pad)).collect::<Vec<_>>())
+        .filter(|stage| !stage.is_empty())
+        .collect()
+}
+
+/// Drops comparators, which don't affect lanes `needed_from..`
+fn prune(stages: Stages, n: usize, needed_from: usize) -> Stages {
+    let mut needed: Vec<bool> = (0..n).map(|i| i >= needed_from).collect();
+    let mut res: Stages = Vec::new();
+    for stage in stages.into_iter().rev() {
+        let kept: Vec<_> = stage.into_iter().filter(|(l, h)| needed[*l] || needed[*h]).collect();
+        for (l, h) in &kept {
+            needed[*l] = true;
+            needed[*h] = true;
+        }
+        if !kept.is_empty() {
+            res.push(kept);
+        }
+    }
+    res.reverse();
+    res
+}
+
+/// Number of comparators, to compare with host sorting
+pub fn comparators(network: Network, n: usize) -> usize {
+    stages(network, n).iter().map(|s| s.len()).sum()
+}
+
+fn run(a: &Array1D, stages: &Stages) -> Array1D {
+    let n = a.len();
+    let mut res = a.clone();
+    for stage in stages {
+        let mut partner: Vec<usize> = (0..n).collect();
+        let mut high = Array1D::zeros(n);
+        for (l, h) in stage {
+            partner[*l] = *h;
+            partner[*h] = *l;
+            high[*h] = MASK_TRUE;
+        }
+        let other = v_permute(&res, &partner).unwrap();
+        let min = v_min(&res, &other);
+        res = v_max_masked(&res, &other, &LaneMask::new(&high, Inactive::Keep(&min)));
+    }
+    res
+}
+
+/// Sorts lanes in ascending order, returns instructions used
+pub fn sort(a: &Array1D, network: Network) -> (Array1D, Counters) {
+    counted(|| run(a, &stages(network, a.len())))
+}
+
+/// `k` largest lanes in descending order, returns instructions used.
+/// Runs `top_stages` network with blocks sorted by `network`, and only comparators,
+/// which affect the largest lanes.
+pub fn top_k(a: &Array1D, k: usize, network: Network) -> (Array1D, Counters) {
+    let n = a.len();
+    assert!(k <= n);
+    counted(|| {
+        let sorted = run(a, &prune(top_stages(network, n, k), n, n - k));
+        let index: Vec<usize> = (n - k..n).collect();
+        v_reverse(&v_permute(&sorted, &index).unwrap())
+    })
+}
+
+/// Sorts vector of up to `DIMENSION` values in ascending order
+pub fn sort_vec(a: ArrayView1<ChipT>, network: Network) -> (Array1<ChipT>, Counters) {
+    let (res, counters) = sort(&a.try_into().unwrap(), network);
+    (Array1::from_vec(res.as_slice().to_vec()), counters)
+}
+
+/// `k` largest values of any length vector in descending order.
+/// Candidates are reduced by `top_k` of `DIMENSION` chunks until they fit in one vector.
+pub fn top_k_vec(a: ArrayView1<ChipT>, k: usize, network: Network) -> (Array1<ChipT>, Counters) {
+    assert!(k <= a.len());
+    assert!(2 * k <= DIMENSION, "Candidates of two chunks must fit in one vector");
+    counted(|| {
+        let mut candidates = a.to_vec();
+        while candidates.len() > DIMENSION {
+            candidates = candidates
+                .chunks(DIMENSION)
+                .flat_map(|chunk| {
+                    let chunk = Array1D::from_slice(chunk);
+                    top_k(&chunk, k.min(chunk.len()), network).0.as_slice().to_vec()
+                })
+                .collect();
+        }
+        let res = top_k(&Array1D::from_slice(&candidates), k, network).0;
+        Array1::from_vec(res.as_slice().to_vec())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(n: usize, seed: usize) -> Vec<ChipT> {
+        (0..n).map(|i| ((i * 7919 + seed * 104729) % 256) as u8 as ChipT).collect()
+    }
+
+    #[test]
+    fn sorts_any_length() {
+        for network in [Network::Bitonic, Network::OddEvenMerge] {
+            for n in [0, 1, 2, 3, 5, 8, 17, 64, 100, DIMENSION] {
+                let values = sample(n, n);
+                let (res, counters) = sort(&Array1D::from_slice(&values), network);
+                let mut expected = values.clone();
+                expected.sort();
+                assert_eq!(res.as_slice(), expected.as_slice(), "{:?} n={}", network, n);
+                assert_eq!(counters.opac, 0);
+                assert_eq!(counters.vector, 2 * stages(network, n).len() as u64);
+                assert_eq!(counters.shuffle, stages(network, n).len() as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn top_k_prunes_comparators() {
+        let n = 256;
+        let values = sample(n, 3);
+        let mut expected = values.clone();
+        expected.sort_by(|a, b| b.cmp(a));
+        for network in [Network::Bitonic, Network::OddEvenMerge] {
+            let (res, top_counters) = top_k(&Array1D::from_slice(&values), 4, network);
+            assert_eq!(res.as_slice(), &expected[..4]);
+            let (_, sort_counters) = sort(&Array1D::from_slice(&values), network);
+            let executed = prune(top_stages(network, n, 4), n, n - 4);
+            assert_eq!(top_counters.vector, 2 * executed.len() as u64);
+            assert!(top_counters.vector < sort_counters.vector);
+            assert!(top_counters.shuffle < sort_counters.shuffle);
+            let count = |s: &Stages| s.iter().map(|x| x.len()).sum::<usize>();
+            assert!(count(&executed) < count(&stages(network, n)));
+        }
+        assert!(comparators(Network::OddEvenMerge, n) < comparators(Network::Bitonic, n));
+    }
+
+    #[test]
+    fn top_k_small_and_odd() {
+        for network in [Network::Bitonic, Network::OddEvenMerge] {
+            for n in [1, 2, 3, 7, 13, 100] {
+                let values = sample(n, n + 1);
+                let mut expected = values.clone();
+                expected.sort_by(|a, b| b.cmp(a));
+                for k in [0, 1, 3, n / 2, n].into_iter().filter(|k| *k <= n) {
+                    let (res, _) = top_k(&Array1D::from_slice(&values), k, network);
+                    assert_eq!(res.as_slice(), &expected[..k], "{:?} n={} k={}", network, n, k);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn top_k_any_length() {
+        let values = sample(5 * DIMENSION + 3, 1);
+        let mut expected = values.clone();
+        expected.sort_by(|a, b| b.cmp(a));
+        let (res, counters) = top_k_vec(ArrayView1::from(&values), 10, Network::OddEvenMerge);
+        assert_eq!(res.as_slice().unwrap(), &expected[..10]);
+        assert!(counters.vector > 0);
+    }
+}
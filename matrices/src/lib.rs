@@ -0,0 +1,6 @@
+pub mod intrinsics;
+pub mod kernels;
+pub mod nn;
+
+#[cfg(test)]
+mod testing;
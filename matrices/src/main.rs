@@ -1,8 +1,9 @@
 use ndarray::array;
-use crate::intrinsics::wrappers::mat_mul;
-
-mod intrinsics;
+use assignment::intrinsics::trace::traced;
+use assignment::intrinsics::wrappers::mat_mul;
 
+/// Prints product, and writes its instruction trace for the optimizer,
+/// when output path is given
 fn main() {
     let denom = 64.;
     let a = array![
@@ -14,6 +15,9 @@ fn main() {
             [3. / denom, 4. / denom]
         ];
 
-    let res = mat_mul(a.t().view(), b.view());
+    let (res, trace) = traced(|| mat_mul(a.t().view(), b.view()));
     println!("{:?}", res);
+    if let Some(path) = std::env::args().nth(1) {
+        std::fs::write(path, trace.to_optimizer_input()).expect("Can't write trace");
+    }
 }
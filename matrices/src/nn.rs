@@ -0,0 +1,348 @@
+//! Small quantized MLP inference on the emulator.
+//!
+//! `Dense` layers run through `mat_mul` with a scale per layer for weights and one for inputs,
+//! `ReLU` runs on vector units, `Softmax` is `kernels::norm::softmax`.
+//! Outputs of `Dense` are `f32` on host, so `ReLU` quantizes them again with one scale per batch.
+//! That adds rounding error up to half of the scale, `max_abs / 254`, to positive values too.
+//! Input scale is calibrated from an `f32` pass with `Sequential::calibrate`,
+//! otherwise it's taken from each batch.
+//!
+//! Weight file is little-endian: magic `MLP1`, `u32` number of layers, then for every layer
+//! a `u8` tag (0 dense, 1 relu, 2 softmax). Dense layer continues with `u32` outputs,
+//! `u32` inputs, `outputs * inputs` row-major `f32` weights and `outputs` `f32` biases.
+//! Inputs of a dense layer match outputs of the previous one, and a layer has at most
+//! `MAX_LAYER_WEIGHTS` weights, so that a corrupted header can't request a huge buffer.
+
+use std::io::{self, Read, Write};
+use ndarray::{Array1, Array2, ArrayView2, Axis};
+use crate::intrinsics::counters::{costed, CostSummary};
+use crate::intrinsics::quant::{max_abs, range_factor, Quantizer};
+use crate::intrinsics::wrappers::{mat_mul, vec_max};
+use crate::kernels::norm::softmax;
+
+const MAGIC: &[u8; 4] = b"MLP1";
+const MAX_LAYER_WEIGHTS: usize = 1 << 26;
+
+/// Fully connected layer: `x * weights^T + bias`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dense {
+    /// `[outputs, inputs]`
+    weights: Array2<f32>,
+    /// `weights`, brought into operand range
+    operands: Array2<f32>,
+    bias: Array1<f32>,
+    weight_scale: f32,
+    /// Calibrated factor of inputs, per batch when not set
+    input_scale: Option<f32>,
+}
+
+impl Dense {
+    pub fn new(weights: Array2<f32>, bias: Array1<f32>) -> Self {
+        assert_eq!(weights.nrows(), bias.len(), "Bias doesn't match outputs");
+        let weight_scale = range_factor(weights.view());
+        let operands = &weights / weight_scale;
+        Dense { weights, operands, bias, weight_scale, input_scale: None }
+    }
+
+    pub fn weights(&self) -> &Array2<f32> {
+        &self.weights
+    }
+
+    pub fn bias(&self) -> &Array1<f32> {
+        &self.bias
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.weights.ncols()
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.weights.nrows()
+    }
+
+    fn forward(&self, x: ArrayView2<f32>) -> Array2<f32> {
+        let input_scale = self.input_scale.unwrap_or_else(|| range_factor(x));
+        mat_mul(&x / input_scale, self.operands.view()) * (input_scale * self.weight_scale) + &self.bias
+    }
+
+    fn forward_f32(&self, x: ArrayView2<f32>) -> Array2<f32> {
+        x.dot(&self.weights.t()) + &self.bias
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Layer {
+    Dense(Dense),
+    Relu,
+    /// Row-wise
+    Softmax,
+}
+
+impl Layer {
+    fn tag(&self) -> u8 {
+        match self {
+            Layer::Dense(_) => 0,
+            Layer::Relu => 1,
+            Layer::Softmax => 2,
+        }
+    }
+
+    fn forward(&self, x: ArrayView2<f32>) -> Array2<f32> {
+        match self {
+            Layer::Dense(dense) => dense.forward(x),
+            Layer::Relu => {
+                // Requantized with the scale of the whole batch, see module docs
+                let q = Quantizer::for_values(x);
+                let values = Array1::from_iter(x.iter().map(|v| q.quantize(*v)));
+                let res = vec_max(values.view(), Array1::zeros(values.len()).view());
+                res.mapv(|v| q.dequantize(v)).into_shape_with_order(x.raw_dim()).unwrap()
+            }
+            Layer::Softmax => {
+                // Differences to row maximum are up to twice the largest value, they must not saturate
+                let max_abs = max_abs(x);
+                softmax(x, Quantizer::for_range(if max_abs > 0. { 2. * max_abs } else { 1. }))
+            }
+        }
+    }
+
+    fn forward_f32(&self, x: ArrayView2<f32>) -> Array2<f32> {
+        match self {
+            Layer::Dense(dense) => dense.forward_f32(x),
+            Layer::Relu => x.mapv(|v| v.max(0.)),
+            Layer::Softmax => {
+                let mut res = x.to_owned();
+                for mut row in res.axis_iter_mut(Axis(0)) {
+                    let max = row.fold(f32::NEG_INFINITY, |acc, v| acc.max(*v));
+                    row.mapv_inplace(|v| (v - max).exp());
+                    let sum = row.sum();
+                    row /= sum;
+                }
+                res
+            }
+        }
+    }
+}
+
+/// Comparison of emulated forward pass with `f32` one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Accuracy {
+    pub max_error: f32,
+    pub mean_error: f32,
+    /// Share of rows, which have the same argmax in both passes
+    pub agreement: f32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Evaluation {
+    pub output: Array2<f32>,
+    pub accuracy: Accuracy,
+    pub cost: CostSummary,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sequential {
+    pub layers: Vec<Layer>,
+}
+
+fn argmax(row: ndarray::ArrayView1<f32>) -> usize {
+    let mut res = 0;
+    for (i, v) in row.iter().enumerate() {
+        if *v > row[res] {
+            res = i;
+        }
+    }
+    res
+}
+
+impl Sequential {
+    pub fn new(layers: Vec<Layer>) -> Self {
+        Sequential { layers }
+    }
+
+    /// Runs a batch, rows of `x` are samples
+    pub fn forward(&self, x: ArrayView2<f32>) -> Array2<f32> {
+        self.layers.iter().fold(x.to_owned(), |x, layer| layer.forward(x.view()))
+    }
+
+    /// Reference pass on host
+    pub fn forward_f32(&self, x: ArrayView2<f32>) -> Array2<f32> {
+        self.layers.iter().fold(x.to_owned(), |x, layer| layer.forward_f32(x.view()))
+    }
+
+    /// Fixes input scale of every dense layer to the range it gets in `f32` pass over `x`
+    pub fn calibrate(&mut self, x: ArrayView2<f32>) {
+        let mut x = x.to_owned();
+        for layer in &mut self.layers {
+            if let Layer::Dense(dense) = layer {
+                dense.input_scale = Some(range_factor(x.view()));
+            }
+            x = layer.forward_f32(x.view());
+        }
+    }
+
+    /// Emulated forward pass with its cost and accuracy against `f32` pass
+    pub fn evaluate(&self, x: ArrayView2<f32>) -> Evaluation {
+        let (output, cost) = costed(|| self.forward(x));
+        let expected = self.forward_f32(x);
+        let errors: Vec<f32> = output.iter().zip(&expected).map(|(a, b)| (a - b).abs()).collect();
+        let same = output.rows().into_iter().zip(expected.rows()).filter(|(a, b)| argmax(*a) == argmax(*b)).count();
+        let accuracy = Accuracy {
+            max_error: errors.iter().copied().fold(0., f32::max),
+            mean_error: errors.iter().sum::<f32>() / errors.len().max(1) as f32,
+            agreement: same as f32 / output.nrows().max(1) as f32,
+        };
+        Evaluation { output, accuracy, cost }
+    }
+
+    pub fn save(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&(self.layers.len() as u32).to_le_bytes())?;
+        for layer in &self.layers {
+            w.write_all(&[layer.tag()])?;
+            if let Layer::Dense(dense) = layer {
+                w.write_all(&(dense.outputs() as u32).to_le_bytes())?;
+                w.write_all(&(dense.inputs() as u32).to_le_bytes())?;
+                for v in dense.weights().iter().chain(dense.bias()) {
+                    w.write_all(&v.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(r: &mut impl Read) -> io::Result<Self> {
+        let invalid = |msg: &'static str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("Not a model file"));
+        }
+        let mut layers = Vec::new();
+        let mut width = None;
+        for _ in 0..read_u32(r)? {
+            let mut tag = [0];
+            r.read_exact(&mut tag)?;
+            layers.push(match tag[0] {
+                0 => {
+                    let (outputs, inputs) = (read_u32(r)? as usize, read_u32(r)? as usize);
+                    let len = outputs.checked_mul(inputs).filter(|n| *n <= MAX_LAYER_WEIGHTS);
+                    let len = len.ok_or_else(|| invalid("Dense layer is too large"))?;
+                    if width.is_some_and(|w| w != inputs) {
+                        return Err(invalid("Dense layer inputs don't match previous outputs"));
+                    }
+                    width = Some(outputs);
+                    let weights = read_f32s(r, len)?;
+                    let bias = read_f32s(r, outputs)?;
+                    let weights = Array2::from_shape_vec((outputs, inputs), weights).unwrap();
+                    Layer::Dense(Dense::new(weights, Array1::from_vec(bias)))
+                }
+                1 => Layer::Relu,
+                2 => Layer::Softmax,
+                _ => return Err(invalid("Unknown layer tag")),
+            });
+        }
+        Ok(Sequential { layers })
+    }
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Buffer grows with bytes actually read, so a short file can't request `len` upfront
+fn read_f32s(r: &mut impl Read, len: usize) -> io::Result<Vec<f32>> {
+    let mut bytes = Vec::new();
+    r.by_ref().take(len as u64 * 4).read_to_end(&mut bytes)?;
+    if bytes.len() != len * 4 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sample;
+
+    fn model() -> Sequential {
+        Sequential::new(vec![
+            Layer::Dense(Dense::new(sample(16, 8, 1, 1.), Array1::from_elem(16, 0.1))),
+            Layer::Relu,
+            Layer::Dense(Dense::new(sample(4, 16, 2, 1.) * 0.3, Array1::zeros(4))),
+            Layer::Softmax,
+        ])
+    }
+
+    #[test]
+    fn save_load() {
+        let model = model();
+        let mut bytes = Vec::new();
+        model.save(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 4 + 4 + 4 + 2 * 8 + (16 * 8 + 16 + 4 * 16 + 4) * 4);
+        // Weights are stored as given, not as scaled operands
+        assert_eq!(Sequential::load(&mut bytes.as_slice()).unwrap(), model);
+
+        bytes[0] = b'X';
+        assert_eq!(Sequential::load(&mut bytes.as_slice()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(Sequential::load(&mut &bytes[..10]).is_err());
+    }
+
+    #[test]
+    fn load_rejects_bad_shapes() {
+        let load = |bytes: &[u8]| Sequential::load(&mut &bytes[..]).unwrap_err().kind();
+        let mut huge = MAGIC.to_vec();
+        for x in [1, u32::MAX, u32::MAX] {
+            huge.extend_from_slice(&x.to_le_bytes());
+        }
+        huge.insert(8, 0);
+        assert_eq!(load(&huge), io::ErrorKind::InvalidData);
+
+        // Header of the largest allowed layer in a short file
+        let mut short = MAGIC.to_vec();
+        for x in [1u32, 1 << 13, 1 << 13] {
+            short.extend_from_slice(&x.to_le_bytes());
+        }
+        short.insert(8, 0);
+        short.extend_from_slice(&[0; 8]);
+        assert_eq!(load(&short), io::ErrorKind::UnexpectedEof);
+
+        let broken = Sequential::new(vec![
+            Layer::Dense(Dense::new(sample(16, 8, 1, 1.), Array1::zeros(16))),
+            Layer::Relu,
+            Layer::Dense(Dense::new(sample(4, 15, 2, 1.), Array1::zeros(4))),
+        ]);
+        let mut bytes = Vec::new();
+        broken.save(&mut bytes).unwrap();
+        assert_eq!(load(&bytes), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn relu_rounding() {
+        let x = sample(3, 8, 5, 1.) * 0.7;
+        let res = Layer::Relu.forward(x.view());
+        let bound = Quantizer::for_values(&x).scale() / 2.;
+        for (y, v) in res.iter().zip(&x) {
+            assert!((y - v.max(0.)).abs() <= bound + 1e-6, "{} {}", y, v);
+        }
+    }
+
+    #[test]
+    fn forward_matches_f32() {
+        let mut model = model();
+        let x = sample(10, 8, 3, 1.) * 0.5;
+        let eval = model.evaluate(x.view());
+        assert_eq!(eval.output.dim(), (10, 4));
+        assert!(eval.accuracy.max_error < 0.02, "{:?}", eval.accuracy);
+        assert!(eval.accuracy.agreement >= 0.9, "{:?}", eval.accuracy);
+        // One `OPAC` pass per input feature of each dense layer
+        assert_eq!(eval.cost.counters.opac, 8 + 16);
+        assert!(eval.cost.cost.cycles > 0.);
+
+        model.calibrate(x.view());
+        let calibrated = model.evaluate(x.view());
+        assert!(calibrated.accuracy.max_error < 0.02, "{:?}", calibrated.accuracy);
+        assert_eq!(calibrated.cost.counters.opac, eval.cost.counters.opac);
+    }
+}
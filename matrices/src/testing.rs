@@ -0,0 +1,10 @@
+//! Fixtures shared by unit tests
+
+use ndarray::Array2;
+
+/// Deterministic matrix with values `(k / 8 - 1) * range`, `k` in `0..=16`,
+/// so for power of two `range` they are exact in `f32` and on the chip grid.
+/// Different `seed` gives different matrix of the same shape.
+pub(crate) fn sample(rows: usize, cols: usize, seed: usize, range: f32) -> Array2<f32> {
+    Array2::from_shape_fn((rows, cols), |(i, j)| (((i * 31 + j * 7 + seed) % 17) as f32 / 8. - 1.) * range)
+}
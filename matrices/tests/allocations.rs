@@ -0,0 +1,55 @@
+//! Heap allocations of `_into` products. Own test binary, so that the counting
+//! allocator doesn't replace the allocator of the library and its unit tests.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use assignment::intrinsics::config::DIMENSION;
+use assignment::intrinsics::wrappers::{mat_mul, mat_mul_into, Workspace};
+use ndarray::Array2;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// System allocator, which counts allocations of each thread
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Runs `f` and returns number of heap allocations it made
+fn allocations<R>(f: impl FnOnce() -> R) -> (R, u64) {
+    let before = ALLOCATIONS.with(|n| n.get());
+    let res = f();
+    (res, ALLOCATIONS.with(|n| n.get()) - before)
+}
+
+#[test]
+fn mat_mul_into_reuses_workspace() {
+    // Both operands span two row blocks, so both are packed
+    let sample = |rows, seed| Array2::from_shape_fn((rows, 5), |(i, j)| ((i * 31 + j * 7 + seed) % 17) as f32 / 16. - 0.5);
+    let (a, b) = (sample(DIMENSION + 1, 1), sample(DIMENSION + 2, 2));
+    let mut out = Array2::zeros((a.nrows(), b.nrows()));
+    let mut ws = Workspace::new();
+    let (_, first) = allocations(|| mat_mul_into(a.view(), b.view(), out.view_mut(), &mut ws));
+    let (_, second) = allocations(|| mat_mul_into(a.view(), b.view(), out.view_mut(), &mut ws));
+    assert!(first > 0);
+    assert_eq!(second, 0);
+    assert_eq!(out, mat_mul(a.view(), b.view()));
+}
@@ -214,7 +214,7 @@ impl AragoSpec {
     }
 
     /// Move input to device. Before execution all inputs must be presented in device memory
-    pub fn to_device(&mut self, id: &Id, reg: &Register) {
+    pub fn move_to_device(&mut self, id: &Id, reg: &Register) {
         self.time += TRANSFER_TIME;
         debug!("{:?} -> {:?} | {:?}", id, reg, self.time);
         self.done_tasks.insert(*id);
@@ -238,8 +238,8 @@ impl AragoSpec {
 
     pub fn get_cost(&self, op: &Operation) -> u32 {
         match op {
-            Operation::VAdd | Operation::VMin | Operation::VMax => POINTWISE_COST,
-            Operation::VScaMul => OPAC_COST,
+            Operation::VAdd | Operation::VMin | Operation::VMax | Operation::VOther => POINTWISE_COST,
+            Operation::VScaMul | Operation::Opac => OPAC_COST,
         }
     }
 
@@ -256,8 +256,8 @@ impl AragoSpec {
 
     pub fn get_core(&mut self, op: &Operation) -> &mut Core {
         match op {
-            Operation::VAdd | Operation::VMin | Operation::VMax => &mut self.active_pointwise_cores,
-            Operation::VScaMul => &mut self.active_mult_cores,
+            Operation::VAdd | Operation::VMin | Operation::VMax | Operation::VOther => &mut self.active_pointwise_cores,
+            Operation::VScaMul | Operation::Opac => &mut self.active_mult_cores,
         }
     }
 
@@ -8,8 +8,24 @@ mod scheduler;
 mod devices;
 mod regalloc;
 mod instruction;
+mod trace;
 
+/// Schedules DAG from a `matrices` trace file, when its path is given,
+/// otherwise the built-in example
 fn main() {
+    let dag = match std::env::args().nth(1) {
+        Some(path) => {
+            let text = std::fs::read_to_string(&path).expect("Can't read trace");
+            trace::parse_trace(&text).expect("Invalid trace")
+        }
+        None => example_dag(),
+    };
+    let scheduler = Scheduler::new(dag);
+    println!("Baseline execution: {:?}", scheduler.baseline_execute());
+    println!("Optimal execution: {:?}", scheduler.optimal_execute());
+}
+
+fn example_dag() -> Dag {
     let nodes = vec![
         Some(Operation::VScaMul),
         Some(Operation::VScaMul),
@@ -53,8 +69,5 @@ fn main() {
         (12, 13),
     ].iter().map(|(a, b)| (*a as Id, *b as Id)).collect();
 
-    let dag = Dag::new(nodes, edges);
-    let scheduler = Scheduler::new(dag);
-    println!("Baseline execution: {:?}", scheduler.baseline_execute());
-    println!("Optimal execution: {:?}", scheduler.optimal_execute());
+    Dag::new(nodes, edges)
 }
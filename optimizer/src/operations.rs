@@ -1,10 +1,31 @@
 
-#[derive(Clone, Eq, PartialEq, Hash)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Operation {
     VAdd,
     VMin,
     VMax,
     VScaMul,
+    /// Outer product pass of matrix core
+    Opac,
+    /// Any other pointwise instruction of scalar core
+    VOther,
+}
+
+impl Operation {
+    /// Operation by intrinsic name, as in `matrices` traces
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "v_add" => Some(Operation::VAdd),
+            "v_min" => Some(Operation::VMin),
+            "v_max" => Some(Operation::VMax),
+            "sca_mul" => Some(Operation::VScaMul),
+            "opac" => Some(Operation::Opac),
+            "v_sub" | "v_add_sat" | "v_sub_sat" | "v_mulhi" | "v_abs" | "v_abs_sat" | "v_neg" | "v_neg_sat" | "vector" => {
+                Some(Operation::VOther)
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Eq, PartialEq, Hash)]
@@ -64,7 +64,7 @@ impl Scheduler {
             let srcs = self.dag.all_nodes[*id].borrow().sources.clone();
             let regs = (0..srcs.len()).map(|x| Register::new(x as u8));
             moves.push(Instruction::new(
-                *id, Register::new(srcs.len() as u8), zip(srcs.into_iter(), regs).collect()
+                *id, Register::new(srcs.len() as u8), zip(srcs, regs).collect()
             ));
         }
         (self.exec_time(moves, device), order)
@@ -79,12 +79,12 @@ impl Scheduler {
         for inst in &moves {
             let required = HashSet::<Id>::from_iter(self.dag.all_nodes[inst.id].borrow().sources.iter().cloned());
             for (id, op) in &inst.pre_move {
-                device.to_device(id, op);
+                device.move_to_device(id, op);
             }
             let op = &self.dag.all_nodes[inst.id].borrow().op;
             match op {
                 Some(x) => device.schedule(x, &inst.id, &inst.res_reg, &required),
-                None => device.to_device(&inst.id, &inst.res_reg),
+                None => device.move_to_device(&inst.id, &inst.res_reg),
             }
             queue.push_back(inst.id);
         }
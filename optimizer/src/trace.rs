@@ -0,0 +1,57 @@
+use crate::dag::{Dag, Id};
+use crate::operations::Operation;
+
+/// Reads DAG from instruction trace of `matrices` crate:
+/// `node <id> <op>` lines, where `input` marks loaded values, then `edge <source> <user>` lines
+pub fn parse_trace(text: &str) -> Result<Dag, String> {
+    let mut nodes = Vec::new();
+    let mut edges: Vec<(Id, Id)> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let words: Vec<_> = line.split_whitespace().collect();
+        let parse_id = |x: &str| x.parse::<Id>().map_err(|e| format!("line {}: {}", n + 1, e));
+        match words.as_slice() {
+            [] => {}
+            ["node", id, op] => {
+                if parse_id(id)? != nodes.len() {
+                    return Err(format!("line {}: nodes must be numbered in order", n + 1));
+                }
+                let op = match *op {
+                    "input" => None,
+                    name => Some(Operation::from_name(name).ok_or(format!("line {}: unknown operation {}", n + 1, name))?),
+                };
+                nodes.push(op);
+            }
+            ["edge", from, to] => edges.push((parse_id(from)?, parse_id(to)?)),
+            _ => return Err(format!("line {}: can't parse {:?}", n + 1, line)),
+        }
+    }
+    if let Some((from, to)) = edges.iter().find(|(from, to)| *from >= nodes.len() || *to >= nodes.len()) {
+        return Err(format!("edge {} {} refers to unknown node", from, to));
+    }
+    Ok(Dag::new(nodes, edges))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Scheduler;
+
+    #[test]
+    fn opac_chain() {
+        let text = "node 0 input\nnode 1 input\nnode 2 opac\nnode 3 input\nnode 4 input\nnode 5 opac\n\
+                    edge 0 2\nedge 1 2\nedge 3 5\nedge 4 5\nedge 2 5\n";
+        let dag = parse_trace(text).unwrap();
+        assert_eq!(dag.all_nodes.len(), 6);
+        assert_eq!(dag.all_nodes[5].borrow().sources, vec![3, 4, 2]);
+        let (_, order) = Scheduler::new(dag).optimal_execute();
+        assert_eq!(order.len(), 6);
+        assert!(order.iter().position(|x| *x == 2) < order.iter().position(|x| *x == 5));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse_trace("node 0 v_div\n").is_err());
+        assert!(parse_trace("node 1 input\n").is_err());
+        assert!(parse_trace("node 0 input\nedge 0 1\n").is_err());
+    }
+}